@@ -0,0 +1,151 @@
+//! Measures the first `heavy()` of a process apart from the later ones, to show what `init()`
+//! saves it: selecting the strategy, which registers the process for `sys_membarrier()` or
+//! benchmarks the `mprotect()`-based barriers on Linux, the first fault of their page, the binding
+//! of the Mach calls by dyld on macOS, or the first `FlushProcessWriteBuffers()` on Windows.
+//!
+//! Those costs are paid once per process, so every sample is taken in a fresh process, which this
+//! benchmark spawns by running itself again. It needs no harness, and so not the `nightly`
+//! feature either:
+//!
+//! ```text
+//! cargo bench --bench cold
+//! ```
+
+extern crate membarrier;
+
+use membarrier::{Backend, Config};
+use std::env;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// The environment variable that tells a child process what to measure.
+const CHILD: &str = "MEMBARRIER_BENCH_COLD";
+
+/// The number of processes to take the first barrier of, per backend and way to start.
+const SAMPLES: usize = 15;
+
+/// The number of barriers each process issues after the first one.
+const WARM: usize = 1_000;
+
+/// The mechanisms to prefer, `None` letting the crate select the fastest one.
+fn preferences() -> Vec<Option<Backend>> {
+    let mut preferences = vec![None];
+    if cfg!(target_os = "linux") {
+        preferences.extend_from_slice(&[
+            Some(Backend::Membarrier),
+            Some(Backend::Mprotect),
+            Some(Backend::Madvise),
+            Some(Backend::Signal),
+        ]);
+    }
+    preferences
+}
+
+/// A sample taken by a child process.
+struct Sample {
+    /// The name of the mechanism that was used.
+    backend: String,
+    first: Duration,
+    warm: Duration,
+}
+
+/// Issues the barriers of a child process, and prints how long the first one and the median of
+/// the later ones took.
+fn child(spec: &str) {
+    let mut parts = spec.split(':');
+    let preference = preferences()[parts.next().unwrap().parse::<usize>().unwrap()];
+    let init = parts.next() == Some("init");
+
+    let config = Config {
+        prefer: preference,
+        allow_signals: preference == Some(Backend::Signal),
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+    if init {
+        membarrier::init();
+    }
+
+    let started = Instant::now();
+    membarrier::heavy();
+    let first = started.elapsed();
+
+    let mut warm = (0..WARM)
+        .map(|_| {
+            let started = Instant::now();
+            membarrier::heavy();
+            started.elapsed()
+        })
+        .collect::<Vec<_>>();
+    warm.sort();
+    println!(
+        "{:?} {} {}",
+        membarrier::backend(),
+        first.as_nanos(),
+        warm[WARM / 2].as_nanos()
+    );
+}
+
+/// Runs a child process that prefers the mechanism at `index`, calling `init()` first if `init`.
+fn sample(index: usize, init: bool) -> Sample {
+    let spec = format!("{}:{}", index, if init { "init" } else { "cold" });
+    let output = Command::new(env::current_exe().unwrap())
+        .env(CHILD, spec)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "the child process failed");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut fields = stdout.split_whitespace();
+    let backend = fields.next().unwrap().to_string();
+    let mut nanos = || Duration::from_nanos(fields.next().unwrap().parse().unwrap());
+    Sample {
+        backend,
+        first: nanos(),
+        warm: nanos(),
+    }
+}
+
+/// Returns the median of `durations`.
+fn median(mut durations: Vec<Duration>) -> Duration {
+    durations.sort();
+    durations[durations.len() / 2]
+}
+
+fn main() {
+    if let Ok(spec) = env::var(CHILD) {
+        child(&spec);
+        return;
+    }
+
+    println!(
+        "{:<28} {:>14} {:>14} {:>14}",
+        "backend", "cold first", "after init()", "steady state"
+    );
+    for (index, preference) in preferences().into_iter().enumerate() {
+        let cold = (0..SAMPLES)
+            .map(|_| sample(index, false))
+            .collect::<Vec<_>>();
+        let init = (0..SAMPLES)
+            .map(|_| sample(index, true))
+            .collect::<Vec<_>>();
+
+        // The preferred mechanism may be unavailable, so name the one that was used.
+        let backend = &cold[0].backend;
+        let label = match preference {
+            Some(preference) if format!("{:?}", preference) != *backend => {
+                format!("{} (for {:?})", backend, preference)
+            }
+            Some(_) => backend.clone(),
+            None => format!("{} (default)", backend),
+        };
+        let warm = median(cold.iter().map(|sample| sample.warm).collect());
+        println!(
+            "{:<28} {:>14?} {:>14?} {:>14?}",
+            label,
+            median(cold.iter().map(|sample| sample.first).collect()),
+            median(init.iter().map(|sample| sample.first).collect()),
+            warm
+        );
+    }
+}
@@ -0,0 +1,40 @@
+#![cfg(all(feature = "nightly", target_os = "linux"))]
+#![feature(test)]
+
+extern crate membarrier;
+extern crate test;
+
+use membarrier::{Backend, Config};
+use std::sync::Once;
+use std::thread;
+use test::Bencher;
+
+static SETUP: Once = Once::new();
+
+/// Selects the signal-based barrier, and starts a few threads issuing `light()`.
+fn setup() {
+    SETUP.call_once(|| {
+        let config = Config {
+            eager_init: true,
+            prefer: Some(Backend::Signal),
+            allow_signals: true,
+            ..Config::default()
+        };
+        membarrier::configure(config).unwrap();
+
+        for _ in 0..4 {
+            thread::spawn(|| loop {
+                membarrier::light();
+                thread::yield_now();
+            });
+        }
+    });
+}
+
+#[bench]
+fn heavy(b: &mut Bencher) {
+    setup();
+    b.iter(|| {
+        membarrier::heavy();
+    });
+}
@@ -0,0 +1,187 @@
+//! A minimal hazard-pointer scheme in the style of production memory reclamation libraries, with
+//! `membarrier::light()` on the read side and `membarrier::heavy()` before reclamation.
+//!
+//! A reader announces the node it is about to dereference in its hazard slot, issues `light()`,
+//! and checks that the node is still current. The writer unlinks a node, and before freeing it,
+//! issues `heavy()` and checks that no hazard slot holds it. The two barriers pair up like `SeqCst`
+//! fences: either the reader sees the node unlinked and retries, or the writer sees the hazard and
+//! keeps the node for later.
+//!
+//! The stress test runs with `cargo test`. Run it under AddressSanitizer to check that no node is
+//! read after it is freed:
+//!
+//! ```text
+//! RUSTFLAGS=-Zsanitizer=address cargo +nightly test --example crossbeam_style \
+//!     --target x86_64-unknown-linux-gnu
+//! ```
+
+extern crate membarrier;
+
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// The value a node holds once it is dropped, so that a reader of a freed node is likely to notice
+/// even without AddressSanitizer.
+const POISON: usize = usize::MAX;
+
+/// The number of nodes that are allocated and not freed yet.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+struct Node {
+    value: usize,
+}
+
+impl Node {
+    fn alloc(value: usize) -> *mut Node {
+        LIVE.fetch_add(1, Ordering::Relaxed);
+        Box::into_raw(Box::new(Node { value }))
+    }
+
+    /// Frees `node`, which must have been allocated by `alloc()` and be unreachable.
+    unsafe fn free(node: *mut Node) {
+        (*node).value = POISON;
+        drop(Box::from_raw(node));
+        LIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A pointer that readers protect with their hazard slots, and that a single writer replaces.
+struct Shared {
+    current: AtomicPtr<Node>,
+    hazards: Vec<AtomicPtr<Node>>,
+}
+
+impl Shared {
+    fn new(readers: usize) -> Shared {
+        Shared {
+            current: AtomicPtr::new(Node::alloc(0)),
+            hazards: (0..readers)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+        }
+    }
+
+    /// Reads the value of the current node, protecting it with the hazard slot of `reader`.
+    fn read(&self, reader: usize) -> usize {
+        let hazard = &self.hazards[reader];
+        let mut node = self.current.load(Ordering::Acquire);
+        loop {
+            hazard.store(node, Ordering::Relaxed);
+            // Orders the announcement before the validation, as seen by `heavy()` in `reclaim()`.
+            membarrier::light();
+            let current = self.current.load(Ordering::Acquire);
+            if current == node {
+                break;
+            }
+            node = current;
+        }
+
+        let value = unsafe { (*node).value };
+        // Orders the read before the node is freed, once the writer sees the slot cleared.
+        hazard.store(ptr::null_mut(), Ordering::Release);
+        value
+    }
+
+    /// Replaces the current node with one holding `value`, and retires the old one.
+    fn replace(&self, value: usize, retired: &mut Vec<*mut Node>) {
+        let old = self.current.swap(Node::alloc(value), Ordering::AcqRel);
+        retired.push(old);
+    }
+
+    /// Frees the retired nodes that no reader protects, and keeps the others for later.
+    fn reclaim(&self, retired: &mut Vec<*mut Node>) {
+        // Orders the unlinking before the scan, and makes every announcement of a reader that may
+        // still see a retired node visible to it.
+        membarrier::heavy();
+        let protected = self
+            .hazards
+            .iter()
+            .map(|hazard| hazard.load(Ordering::Acquire))
+            .collect::<Vec<_>>();
+        retired.retain(|&node| {
+            if protected.contains(&node) {
+                return true;
+            }
+            unsafe { Node::free(node) };
+            false
+        });
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { Node::free(*self.current.get_mut()) };
+    }
+}
+
+/// Lets `readers` threads read while the current thread replaces the node `replacements` times,
+/// reclaiming every `batch` replacements. Returns the number of reads.
+fn stress(readers: usize, replacements: usize, batch: usize) -> usize {
+    let shared = Arc::new(Shared::new(readers));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let handles = (0..readers)
+        .map(|reader| {
+            let shared = shared.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut reads = 0;
+                let mut last = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let value = shared.read(reader);
+                    assert_ne!(value, POISON, "read a freed node");
+                    // The writer only ever increases the value.
+                    assert!(value >= last);
+                    last = value;
+                    reads += 1;
+                    thread::yield_now();
+                }
+                reads
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut retired = Vec::new();
+    for value in 1..=replacements {
+        shared.replace(value, &mut retired);
+        if retired.len() >= batch {
+            shared.reclaim(&mut retired);
+        }
+        // Lets the readers catch up even on a single CPU.
+        thread::yield_now();
+    }
+
+    done.store(true, Ordering::Relaxed);
+    let reads = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .sum();
+
+    // No reader is left, so every retired node can be freed.
+    shared.reclaim(&mut retired);
+    assert!(retired.is_empty());
+    reads
+}
+
+fn main() {
+    let reads = stress(4, 100_000, 64);
+    println!(
+        "{} reads with {:?} barriers, {} nodes leaked",
+        reads,
+        membarrier::backend(),
+        LIVE.load(Ordering::Relaxed)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_use_after_free() {
+        stress(4, 10_000, 16);
+        assert_eq!(LIVE.load(Ordering::Relaxed), 0, "leaked nodes");
+    }
+}
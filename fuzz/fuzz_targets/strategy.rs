@@ -0,0 +1,238 @@
+//! Drives the Linux strategy selection through random configurations, system capabilities, and
+//! sequences of operations, checking that:
+//!
+//! - the strategy is selected at most once, and each probe runs at most once per selection, so that
+//!   the process registers for membarrier at most once;
+//! - a strategy is only selected if the system offers it and the configuration allows it;
+//! - the preferred mechanism is selected whenever it is available;
+//! - the legacy shared `sys_membarrier()` is only selected over fences;
+//! - a failing `sys_membarrier()`, expedited or shared, is only ever replaced by the `mprotect`-based trick, the
+//!   signal-based barrier, or the perf-event-based barrier, never by fences, which couldn't cover
+//!   the `light()` calls that relied on it.
+//!
+//! Run it with `cargo +nightly fuzz run strategy`.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate membarrier;
+
+use membarrier::selection::{self, Probe, Strategy};
+use membarrier::{Backend, Config};
+
+struct FuzzProbe {
+    membarrier_usable: bool,
+    shared_membarrier_usable: bool,
+    mprotect_usable: bool,
+    mprotect_fastest: Strategy,
+    signal_usable: bool,
+    signal_faster: bool,
+    perf_usable: bool,
+    /// Whether `Config::benchmark` is set, so that the selection may measure.
+    benchmark: bool,
+    membarrier_probes: usize,
+    shared_membarrier_probes: usize,
+    mprotect_probes: usize,
+    signal_probes: usize,
+    perf_probes: usize,
+}
+
+impl Probe for FuzzProbe {
+    fn membarrier_usable(&mut self) -> bool {
+        self.membarrier_probes += 1;
+        self.membarrier_usable
+    }
+
+    fn shared_membarrier_usable(&mut self) -> bool {
+        self.shared_membarrier_probes += 1;
+        self.shared_membarrier_usable
+    }
+
+    fn mprotect_usable(&mut self) -> bool {
+        self.mprotect_probes += 1;
+        self.mprotect_usable
+    }
+
+    fn mprotect_fastest(&mut self) -> Strategy {
+        assert!(self.benchmark && self.mprotect_usable);
+        self.mprotect_fastest
+    }
+
+    fn signal_usable(&mut self) -> bool {
+        self.signal_probes += 1;
+        self.signal_usable
+    }
+
+    fn signal_faster(&mut self, than: Strategy) -> bool {
+        assert!(self.benchmark && self.signal_usable && self.mprotect_usable);
+        assert_eq!(than, self.mprotect_fastest);
+        self.signal_faster
+    }
+
+    fn perf_usable(&mut self) -> bool {
+        self.perf_probes += 1;
+        self.perf_usable
+    }
+}
+
+/// Checks that `strategy` is offered by the system and allowed by the configuration.
+fn check_available(config: &Config, probe: &FuzzProbe, strategy: Strategy) {
+    match strategy {
+        Strategy::Membarrier => assert!(probe.membarrier_usable),
+        Strategy::SharedMembarrier => assert!(probe.shared_membarrier_usable),
+        Strategy::Mprotect | Strategy::Madvise => {
+            assert!(config.allow_mprotect && probe.mprotect_usable)
+        }
+        Strategy::Signal => assert!(config.allow_signals && probe.signal_usable),
+        Strategy::Perf => assert!(probe.perf_usable),
+        Strategy::Fallback => {}
+    }
+}
+
+/// Returns the strategy that should replace both `sys_membarrier()` and fences if available: the
+/// faster of the `mprotect`-based trick and the signal-based barrier, or the trick without
+/// `Config::benchmark`, or else the perf-event-based barrier.
+fn interrupting(config: &Config, probe: &FuzzProbe) -> Option<Strategy> {
+    let mprotect = config.allow_mprotect && probe.mprotect_usable;
+    let signal = config.allow_signals && probe.signal_usable;
+    match (mprotect, signal) {
+        (true, true) if config.benchmark && probe.signal_faster => Some(Strategy::Signal),
+        (true, _) if config.benchmark => Some(probe.mprotect_fastest),
+        (true, _) => Some(Strategy::Mprotect),
+        (false, true) => Some(Strategy::Signal),
+        (false, false) if probe.perf_usable => Some(Strategy::Perf),
+        (false, false) => None,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let (setup, more_setup, ops) = (data[0], data[1], &data[2..]);
+
+    let config = Config {
+        eager_init: false,
+        auto_register: true,
+        prefer: match (setup >> 1) & 0b111 {
+            1 => Some(Backend::Membarrier),
+            2 => Some(Backend::Mprotect),
+            3 => Some(Backend::Madvise),
+            4 => Some(Backend::Fence),
+            5 => Some(Backend::Signal),
+            6 => Some(Backend::PerfEvent),
+            7 => Some(Backend::SharedMembarrier),
+            _ => None,
+        },
+        allow_mprotect: setup & 1 != 0,
+        allow_signals: more_setup & 1 != 0,
+        benchmark: more_setup & (1 << 5) != 0,
+        mprotect_numa_node: None,
+    };
+    let mut probe = FuzzProbe {
+        membarrier_usable: setup & (1 << 4) != 0,
+        shared_membarrier_usable: more_setup & (1 << 4) != 0,
+        mprotect_usable: setup & (1 << 5) != 0,
+        mprotect_fastest: if setup & (1 << 6) != 0 {
+            Strategy::Madvise
+        } else {
+            Strategy::Mprotect
+        },
+        signal_usable: more_setup & (1 << 1) != 0,
+        signal_faster: more_setup & (1 << 2) != 0,
+        perf_usable: more_setup & (1 << 3) != 0,
+        benchmark: config.benchmark,
+        membarrier_probes: 0,
+        shared_membarrier_probes: 0,
+        mprotect_probes: 0,
+        signal_probes: 0,
+        perf_probes: 0,
+    };
+
+    let mut strategy = None;
+    let mut selections = 0;
+    let mut membarrier_blocked = false;
+    let mut aborted = false;
+
+    for op in ops {
+        if aborted {
+            break;
+        }
+        match op % 3 {
+            // `init()`, or the first barrier.
+            0 => {
+                if strategy.is_none() {
+                    let selected = selection::select(&config, &mut probe);
+                    selections += 1;
+                    assert!(
+                        probe.membarrier_probes <= 1
+                            && probe.shared_membarrier_probes <= 1
+                            && probe.mprotect_probes <= 1
+                            && probe.signal_probes <= 1
+                            && probe.perf_probes <= 1
+                    );
+                    check_available(&config, &probe, selected);
+
+                    let preferred = match config.prefer {
+                        Some(Backend::Membarrier) if probe.membarrier_usable => {
+                            Some(Strategy::Membarrier)
+                        }
+                        Some(Backend::Mprotect)
+                            if config.allow_mprotect && probe.mprotect_usable =>
+                        {
+                            Some(Strategy::Mprotect)
+                        }
+                        Some(Backend::Madvise)
+                            if config.allow_mprotect && probe.mprotect_usable =>
+                        {
+                            Some(Strategy::Madvise)
+                        }
+                        Some(Backend::Signal) if config.allow_signals && probe.signal_usable => {
+                            Some(Strategy::Signal)
+                        }
+                        Some(Backend::PerfEvent) if probe.perf_usable => Some(Strategy::Perf),
+                        Some(Backend::SharedMembarrier) if probe.shared_membarrier_usable => {
+                            Some(Strategy::SharedMembarrier)
+                        }
+                        Some(Backend::Fence) => Some(Strategy::Fallback),
+                        _ => None,
+                    };
+                    if let Some(preferred) = preferred {
+                        assert_eq!(selected, preferred);
+                    } else if probe.membarrier_usable {
+                        assert_eq!(selected, Strategy::Membarrier);
+                    } else if let Some(expected) = interrupting(&config, &probe) {
+                        assert_eq!(selected, expected);
+                    } else if probe.shared_membarrier_usable {
+                        assert_eq!(selected, Strategy::SharedMembarrier);
+                    } else {
+                        assert_eq!(selected, Strategy::Fallback);
+                    }
+                    strategy = Some(selected);
+                }
+            }
+            // A sandbox starts rejecting `sys_membarrier()`.
+            1 => membarrier_blocked = true,
+            // `heavy()`.
+            _ => {
+                let membarrier = strategy == Some(Strategy::Membarrier)
+                    || strategy == Some(Strategy::SharedMembarrier);
+                if membarrier && membarrier_blocked {
+                    match selection::downgrade(&config, &mut probe) {
+                        Some(to) => {
+                            assert_eq!(Some(to), interrupting(&config, &probe));
+                            check_available(&config, &probe, to);
+                            strategy = Some(to);
+                        }
+                        None => {
+                            assert_eq!(interrupting(&config, &probe), None);
+                            aborted = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    assert!(selections <= 1);
+});
@@ -0,0 +1,24 @@
+//! Checks at link time that `light()` can never panic, with the `no-panic` crate: the build fails
+//! if the compiler can't prove that a wrapper around it never unwinds.
+//!
+//! ```text
+//! cd no-panic && cargo test
+//! ```
+
+extern crate membarrier;
+extern crate no_panic;
+
+use no_panic::no_panic;
+
+#[no_panic]
+fn light() {
+    let _guard = membarrier::light();
+}
+
+#[test]
+fn light_never_panics() {
+    // Before and after the strategy is selected.
+    light();
+    membarrier::init();
+    light();
+}
@@ -0,0 +1,564 @@
+//! The Mach thread-state barrier is implementable for only x64 and ARM64 on Apple environments.
+//! The other architectures use the `default` module instead.
+
+use core::sync::atomic;
+use core::time::Duration;
+
+#[cfg(feature = "paranoid")]
+use super::spin_once::SpinOnce;
+use super::{Backend, BarrierError, Capabilities, HeavyCost, RegisterError, Syscall, Timeout};
+
+#[cfg(feature = "diagnostics")]
+use super::BarrierReport;
+
+/// Whether the Mach thread-state barrier passed its checks.
+#[cfg(feature = "paranoid")]
+static TRUSTED: SpinOnce<bool> = SpinOnce::new();
+
+/// Returns whether the Mach thread-state barrier can be trusted, checking it on first use.
+///
+/// If it can't, both barriers fall back to `SeqCst` fences.
+#[cfg(feature = "paranoid")]
+#[inline]
+fn trusted() -> bool {
+    *TRUSTED.get_or_init(|| {
+        unsafe { barrier::thread_list_sane() }
+        &&super::paranoid::litmus(&|| unsafe { barrier::flush_process_write_buffers() }.is_ok())
+    })
+}
+
+#[cfg(not(feature = "paranoid"))]
+#[inline]
+fn trusted() -> bool {
+    true
+}
+
+mod barrier {
+    #![allow(non_camel_case_types)]
+    #![allow(non_upper_case_globals)]
+    #![allow(dead_code)]
+
+    use core::mem;
+    use core::ptr;
+    use core::slice;
+
+    use libc::{c_int, c_uint, size_t, uintptr_t};
+
+    // The Mach types and calls are declared by hand rather than taken from `libc` or
+    // generated from the SDK headers, so that the backend builds on every Apple target,
+    // including tier-3 ones built with `-Zbuild-std`. They all live in `libSystem`.
+    type natural_t = c_uint;
+    type kern_return_t = c_int;
+    type mach_port_t = natural_t;
+    type thread_act_t = mach_port_t;
+    type mach_msg_type_number_t = natural_t;
+    type thread_state_flavor_t = c_int;
+    type vm_address_t = usize;
+    type vm_size_t = usize;
+
+    const KERN_SUCCESS: kern_return_t = 0;
+    /// `KERN_INSUFFICIENT_BUFFER_SIZE` in `<mach/kern_return.h>`.
+    const KERN_INSUFFICIENT_BUFFER_SIZE: kern_return_t = 52;
+
+    // The thread-state structs and flavors are ABI-stable, so they are written out by hand as
+    // well, and the build never needs libclang. To check them against a new SDK, regenerate
+    // them from `apple/mach.h` with
+    // `bindgen apple/mach.h --use-core --allowlist-type '(x86|arm)_thread_state64_t'
+    // --allowlist-var '(x86|ARM)_THREAD_STATE64'`.
+
+    /// `x86_THREAD_STATE64` in `<mach/i386/thread_status.h>`.
+    const x86_THREAD_STATE64: thread_state_flavor_t = 4;
+    /// `ARM_THREAD_STATE64` in `<mach/arm/thread_status.h>`.
+    const ARM_THREAD_STATE64: thread_state_flavor_t = 6;
+
+    /// `x86_thread_state64_t` in `<mach/i386/_structs.h>`.
+    #[repr(C)]
+    struct x86_thread_state64_t {
+        /// `rax` to `r15`, then `rip`, `rflags`, `cs`, `fs`, and `gs`.
+        registers: [u64; 21],
+    }
+
+    /// `arm_thread_state64_t` in `<mach/arm/_structs.h>`.
+    #[repr(C)]
+    struct arm_thread_state64_t {
+        /// `x0` to `x28`, then `fp`, `lr`, `sp`, and `pc`.
+        registers: [u64; 33],
+        cpsr: u32,
+        pad: u32,
+    }
+
+    /// `x86_THREAD_STATE64_COUNT` in `<mach/i386/thread_status.h>`.
+    const x86_THREAD_STATE64_COUNT: mach_msg_type_number_t = 42;
+    /// `ARM_THREAD_STATE64_COUNT` in `<mach/arm/thread_status.h>`.
+    const ARM_THREAD_STATE64_COUNT: mach_msg_type_number_t = 68;
+
+    extern "C" {
+        static mach_task_self_: mach_port_t;
+
+        fn mach_thread_self() -> mach_port_t;
+
+        fn task_threads(
+            target_task: mach_port_t,
+            act_list: *mut *mut thread_act_t,
+            act_list_cnt: *mut mach_msg_type_number_t,
+        ) -> kern_return_t;
+
+        fn thread_get_state(
+            target_act: thread_act_t,
+            flavor: thread_state_flavor_t,
+            old_state: *mut natural_t,
+            old_state_cnt: *mut mach_msg_type_number_t,
+        ) -> kern_return_t;
+
+        fn thread_get_register_pointer_values(
+            thread: thread_act_t,
+            sp: *mut uintptr_t,
+            length: *mut size_t,
+            values: *mut uintptr_t,
+        ) -> kern_return_t;
+
+        fn mach_port_deallocate(task: mach_port_t, name: mach_port_t) -> kern_return_t;
+
+        fn vm_deallocate(
+            target_task: mach_port_t,
+            address: vm_address_t,
+            size: vm_size_t,
+        ) -> kern_return_t;
+    }
+
+    /// Equivalent to the `mach_task_self()` macro in `<mach/mach_init.h>`.
+    #[inline]
+    unsafe fn mach_task_self() -> mach_port_t {
+        mach_task_self_
+    }
+
+    /// Equivalent to `x86_THREAD_STATE64_COUNT` and `ARM_THREAD_STATE64_COUNT`
+    /// macros in `<mach/thread_status.h>`
+    const fn thread_state64_count() -> u32 {
+        cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                (mem::size_of::<x86_thread_state64_t>() / mem::size_of::<u32>()) as u32
+            } else {
+                (mem::size_of::<arm_thread_state64_t>() / mem::size_of::<u32>()) as u32
+            }
+        }
+    }
+
+    /// The largest thread count for which the thread list is a valid slice.
+    const MAX_THREAD_COUNT: usize = isize::MAX as usize / mem::size_of::<thread_act_t>();
+
+    /// Returns `ret` as the error of a Mach call, unless it is `KERN_SUCCESS`.
+    #[inline]
+    fn check(ret: kern_return_t) -> Result<(), kern_return_t> {
+        if ret == KERN_SUCCESS {
+            Ok(())
+        } else {
+            Err(ret)
+        }
+    }
+
+    /// Checks the result of `thread_get_register_pointer_values`.
+    ///
+    /// The register values are never read: only the side effect of the call matters, which is
+    /// that the thread is interrupted and emits a barrier. The kernel returns
+    /// `KERN_INSUFFICIENT_BUFFER_SIZE` after it has interrupted the thread and found more
+    /// values than fit in the buffer, so that is a success as well.
+    #[inline]
+    fn check_register_values(ret: kern_return_t) -> Result<(), kern_return_t> {
+        if ret == KERN_INSUFFICIENT_BUFFER_SIZE {
+            Ok(())
+        } else {
+            check(ret)
+        }
+    }
+
+    /// The threads of the current task, as returned by `task_threads`.
+    ///
+    /// Dropping it releases the send right of every thread and deallocates the list itself.
+    struct ThreadList {
+        acts: *mut thread_act_t,
+        count: usize,
+    }
+
+    impl ThreadList {
+        /// Fetches the threads of the current task, or returns the error of `task_threads`.
+        unsafe fn fetch() -> Result<ThreadList, kern_return_t> {
+            let mut thread_count: mach_msg_type_number_t = mem::zeroed();
+            let mut thread_acts: *mut thread_act_t = mem::zeroed();
+
+            check(task_threads(
+                mach_task_self(),
+                &mut thread_acts,
+                &mut thread_count,
+            ))?;
+
+            // Never trust the kernel-returned count blindly: the thread list must be a valid
+            // slice, and its size in bytes must not overflow when we deallocate it.
+            fatal_assert!(thread_count as usize <= MAX_THREAD_COUNT);
+            fatal_assert!((thread_count as usize)
+                .checked_mul(mem::size_of::<thread_act_t>())
+                .is_some());
+
+            Ok(ThreadList::from_raw(thread_acts, thread_count as usize))
+        }
+
+        /// Takes ownership of a thread list returned by `task_threads`.
+        ///
+        /// A running task always has a thread, but an empty list, whose pointer may be null,
+        /// is still accepted and owns nothing.
+        unsafe fn from_raw(acts: *mut thread_act_t, count: usize) -> ThreadList {
+            if acts.is_null() || count == 0 {
+                return ThreadList {
+                    acts: ptr::null_mut(),
+                    count: 0,
+                };
+            }
+            ThreadList { acts, count }
+        }
+
+        fn as_slice(&self) -> &[thread_act_t] {
+            if self.acts.is_null() {
+                return &[];
+            }
+            unsafe { slice::from_raw_parts(self.acts, self.count) }
+        }
+    }
+
+    impl Drop for ThreadList {
+        fn drop(&mut self) {
+            if self.acts.is_null() {
+                return;
+            }
+            // A failure only leaks a send right or the list, which is no reason to abort.
+            unsafe {
+                for act in self.as_slice() {
+                    let _ = mach_port_deallocate(mach_task_self(), *act);
+                }
+
+                let _ = vm_deallocate(
+                    mach_task_self(),
+                    self.acts as vm_address_t,
+                    self.count * mem::size_of::<thread_act_t>(),
+                );
+            }
+        }
+    }
+
+    /// Returns the number of threads of the current process, or `None` if they can't be
+    /// fetched.
+    pub unsafe fn thread_count() -> Option<usize> {
+        ThreadList::fetch().ok().map(|threads| threads.count)
+    }
+
+    /// Returns whether the thread list of the current task includes the current thread, as
+    /// it must if `task_threads` works.
+    pub unsafe fn thread_list_sane() -> bool {
+        let threads = match ThreadList::fetch() {
+            Ok(threads) => threads,
+            Err(_) => return false,
+        };
+        let current = mach_thread_self();
+        let sane = threads.as_slice().contains(&current);
+        let _ = mach_port_deallocate(mach_task_self(), current);
+        sane
+    }
+
+    /// Issue a heavy memory barrier, and returns the number of threads it interrupted, or the
+    /// error of the first Mach call that failed.
+    ///
+    /// It flushes write buffers of executing threads of the current process,
+    /// and is equivalent to `membarrier` on latest Linux and `FlushProcessWriteBuffers` on Windows.
+    #[inline]
+    pub unsafe fn flush_process_write_buffers() -> Result<usize, kern_return_t> {
+        let threads = ThreadList::fetch()?;
+        #[cfg(register_pointer_values)]
+        let mut sp: uintptr_t = 0;
+        #[cfg(register_pointer_values)]
+        let mut register_values: [uintptr_t; 128] = [0; 128];
+
+        for act in threads.as_slice() {
+            cfg_if! {
+                if #[cfg(register_pointer_values)] {
+                    let mut registers: size_t = 128;
+                    // The buffer may be too small for the values, which are ignored anyway.
+                    check_register_values(
+                        thread_get_register_pointer_values(*act, &mut sp, &mut registers, register_values.as_mut_ptr()),
+                    )?;
+                } else if #[cfg(target_arch = "x86_64")] {
+                    let mut thread_state: x86_thread_state64_t = mem::zeroed();
+                    let mut count = thread_state64_count();
+                    check(
+                        thread_get_state(*act, x86_THREAD_STATE64, (&mut thread_state) as *mut _ as _, &mut count),
+                    )?;
+                } else {
+                    let mut thread_state: arm_thread_state64_t = mem::zeroed();
+                    let mut count = thread_state64_count();
+                    check(
+                        thread_get_state(*act, ARM_THREAD_STATE64, (&mut thread_state) as *mut _ as _, &mut count),
+                    )?;
+                }
+            };
+        }
+        Ok(threads.count)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn thread_state_sizes() {
+            // The kernel copies out as many `natural_t`s as the count passed in, so the structs
+            // must be exactly as large as the counts of `<mach/thread_status.h>` say.
+            assert_eq!(
+                mem::size_of::<x86_thread_state64_t>(),
+                x86_THREAD_STATE64_COUNT as usize * mem::size_of::<natural_t>()
+            );
+            assert_eq!(
+                mem::size_of::<arm_thread_state64_t>(),
+                ARM_THREAD_STATE64_COUNT as usize * mem::size_of::<natural_t>()
+            );
+            let expected = if cfg!(target_arch = "x86_64") {
+                x86_THREAD_STATE64_COUNT
+            } else {
+                ARM_THREAD_STATE64_COUNT
+            };
+            assert_eq!(thread_state64_count(), expected);
+        }
+
+        #[test]
+        fn empty_thread_list() {
+            // What `task_threads` would return for a task without threads: no list, or an
+            // empty one. Neither is released to the kernel.
+            for &(acts, count) in &[
+                (ptr::null_mut(), 0),
+                (ptr::null_mut(), 1),
+                (ptr::NonNull::dangling().as_ptr(), 0),
+            ] {
+                let threads = unsafe { ThreadList::from_raw(acts, count) };
+                assert!(threads.as_slice().is_empty());
+            }
+        }
+
+        #[test]
+        fn register_values_buffer_too_small() {
+            // What `thread_get_register_pointer_values` returns for a thread with more than
+            // 128 register values.
+            assert_eq!(check_register_values(KERN_SUCCESS), Ok(()));
+            assert_eq!(check_register_values(KERN_INSUFFICIENT_BUFFER_SIZE), Ok(()));
+        }
+
+        #[test]
+        fn register_values_failure() {
+            // `KERN_INVALID_ARGUMENT`, for a thread that is no longer there.
+            assert_eq!(check_register_values(4), Err(4));
+        }
+
+        /// `MACH_PORT_RIGHT_SEND` in `<mach/port.h>`.
+        const MACH_PORT_RIGHT_SEND: natural_t = 0;
+
+        extern "C" {
+            fn mach_port_get_refs(
+                task: mach_port_t,
+                name: mach_port_t,
+                right: natural_t,
+                refs: *mut natural_t,
+            ) -> kern_return_t;
+        }
+
+        #[test]
+        fn thread_ports_released() {
+            const ROUNDS: natural_t = 10_000;
+            // Other tests may issue barriers concurrently, each holding a reference to every
+            // thread port while it is in flight.
+            const IN_FLIGHT: natural_t = 64;
+
+            // `task_threads` hands out another reference to the send right of each thread
+            // under the same port name, so a leak shows in the reference count of the right
+            // rather than in the number of port names.
+            unsafe {
+                let current = mach_thread_self();
+                let refs = || {
+                    let mut refs: natural_t = 0;
+                    assert_eq!(
+                        mach_port_get_refs(
+                            mach_task_self(),
+                            current,
+                            MACH_PORT_RIGHT_SEND,
+                            &mut refs,
+                        ),
+                        KERN_SUCCESS
+                    );
+                    refs
+                };
+
+                let before = refs();
+                for _ in 0..ROUNDS {
+                    flush_process_write_buffers().unwrap();
+                }
+                let after = refs();
+                assert_eq!(
+                    mach_port_deallocate(mach_task_self(), current),
+                    KERN_SUCCESS
+                );
+                assert!(
+                    after < before + IN_FLIGHT,
+                    "{} references to the thread port after {} barriers, {} before",
+                    after,
+                    ROUNDS,
+                    before
+                );
+            }
+        }
+    }
+}
+
+/// Issues a light memory barrier for fast path.
+///
+/// It issues a compiler fence, which disallows compiler optimizations across itself. It incurs
+/// basically no costs in run-time.
+#[inline]
+pub fn light() {
+    #[cfg(feature = "tsan")]
+    super::tsan::light();
+    if trusted() {
+        atomic::compiler_fence(atomic::Ordering::SeqCst);
+    } else {
+        atomic::fence(atomic::Ordering::SeqCst);
+    }
+    #[cfg(feature = "metrics")]
+    super::metrics::light();
+}
+
+/// Issues heavy memory barrier for slow path.
+///
+/// It flushes write buffers of executing threads of the current process
+/// by Inter Process Interrupt(IPI) mechanism.
+///
+/// In the latest version of MacOS(at least 10.14) and iOS(at least 12),
+/// it requests the threads pointer values to force the thread to emit a
+/// memory barrier. In older versions, it falls back to the `thread_get_state`
+/// -based method.
+///
+/// Its cost grows with the number of threads, as Mach has no task-level call that interrupts
+/// every thread at once: `task_suspend()` would suspend the calling thread as well, and
+/// `task_set_state()` only sets the debug state new threads inherit, without interrupting
+/// anyone.
+///
+/// # Aborts
+///
+/// Aborts if a Mach call fails.
+#[inline]
+pub fn heavy() {
+    fatal_assert!(try_heavy().is_ok());
+}
+
+/// Implements `try_heavy()`, returning the `kern_return_t` of a failed Mach call.
+pub fn try_heavy() -> Result<(), BarrierError> {
+    if super::single_caller::heavy() {
+        return Ok(());
+    }
+    #[cfg(feature = "metrics")]
+    let started = super::metrics::heavy_started();
+    flush()?;
+    #[cfg(feature = "metrics")]
+    super::metrics::heavy_finished(started);
+    Ok(())
+}
+
+/// Implements `sync_core()` with the barrier of `heavy()`, whose exception returns serialize
+/// the instruction streams.
+pub fn sync_core() -> Result<(), RegisterError> {
+    match flush() {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(RegisterError::Unsupported),
+        Err(error) => Err(RegisterError::Other(error.errno())),
+    }
+}
+
+/// Issues the heavy barrier, and returns the number of threads it interrupted, or `None` if it
+/// fell back to a fence.
+#[inline]
+fn flush() -> Result<Option<usize>, BarrierError> {
+    let generation = super::generation::begin();
+    let threads = if trusted() {
+        let threads = unsafe { barrier::flush_process_write_buffers() }
+            .map_err(|ret| BarrierError::new(Syscall::Mach, ret))?;
+        Some(threads)
+    } else {
+        atomic::fence(atomic::Ordering::SeqCst);
+        None
+    };
+    super::generation::end(generation);
+    Ok(threads)
+}
+
+/// Implements `try_heavy_timeout()`, which never waits.
+#[inline]
+pub fn try_heavy_timeout(_timeout: Duration) -> Result<(), Timeout> {
+    heavy();
+    Ok(())
+}
+
+/// Implements `heavy_reporting()`, reporting the threads the barrier interrupted.
+#[cfg(feature = "diagnostics")]
+pub fn heavy_reporting() -> BarrierReport {
+    // `heavy()` aborts if the barrier fails again.
+    let threads = flush().unwrap_or_else(|_| {
+        heavy();
+        None
+    });
+    BarrierReport::new(backend(), threads, None)
+}
+
+/// Implements `init()` by running the checks of the `paranoid` feature.
+#[inline]
+pub fn init() {
+    trusted();
+}
+
+/// Implements `reinit_after_fork()`, which is a no-op as the Mach calls keep no state.
+#[inline]
+pub unsafe fn reinit_after_fork() {}
+
+/// Implements `heavy_signal_safe()`, which never issues the barrier as it allocates the thread
+/// list.
+#[inline]
+pub fn heavy_signal_safe() -> bool {
+    false
+}
+
+/// Implements `has_signal_safe_heavy()`, which never holds.
+#[inline]
+pub fn has_signal_safe_heavy() -> bool {
+    false
+}
+
+/// Implements `is_supported()`, which only fails the checks of the `paranoid` feature.
+pub fn is_supported() -> bool {
+    backend() != Backend::Fence
+}
+
+/// Implements `backend()`, which is the Mach thread-state barrier unless it failed the checks.
+#[inline]
+pub fn backend() -> Backend {
+    if trusted() {
+        Backend::MachThreadState
+    } else {
+        Backend::Fence
+    }
+}
+
+/// Implements `expected_heavy_cost()` from the current number of threads.
+pub fn expected_heavy_cost() -> HeavyCost {
+    if !trusted() {
+        return HeavyCost::Cheap;
+    }
+    HeavyCost::of_reach(unsafe { barrier::thread_count() })
+}
+
+/// Implements `capabilities()`, which only knows the backend on macOS and iOS.
+#[inline]
+pub fn capabilities() -> Capabilities {
+    Capabilities::new(backend())
+}
@@ -0,0 +1,167 @@
+//! Defers `heavy_deferred()` on other threads while a thread is in a latency-critical region.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread_local;
+
+/// The critical regions and the deferred barriers, numbered like the requests of a
+/// `BarrierService`.
+struct State {
+    /// The number of `CriticalGuard`s alive.
+    holders: usize,
+    /// Whether the last guard to drop is issuing the barrier for the deferred requests.
+    flushing: bool,
+    /// The sequence number of the latest deferred barrier.
+    requested: u64,
+    /// The sequence number of the latest deferred barrier that was issued.
+    completed: u64,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    holders: 0,
+    flushing: false,
+    requested: 0,
+    completed: 0,
+});
+
+/// Signaled when a barrier for the deferred requests completes.
+static FLUSHED: Condvar = Condvar::new();
+
+/// `State::holders`, which `heavy()` reads without locking the mutex.
+static HOLDERS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The number of `CriticalGuard`s of the current thread, whose own barriers are never
+    /// deferred.
+    static HELD: Cell<usize> = const { Cell::new(0) };
+}
+
+fn lock() -> MutexGuard<'static, State> {
+    // The lock is never held across `heavy()` or user code, so it can't be poisoned.
+    STATE.lock().unwrap()
+}
+
+/// Returns whether another thread is in a critical region, so that a heavy barrier of the
+/// current thread would be deferred.
+#[inline]
+fn deferring() -> bool {
+    HOLDERS.load(Ordering::SeqCst) != 0 && HELD.with(|held| held.get() == 0)
+}
+
+/// Defers a heavy barrier of the current thread while another thread is in a critical region.
+///
+/// Returns `true` once a barrier that started after the call has completed, so that the
+/// caller needn't issue its own, or `false` right away if no region is in progress.
+#[inline]
+fn defer() -> bool {
+    deferring() && defer_slow()
+}
+
+#[cold]
+fn defer_slow() -> bool {
+    let mut state = lock();
+    // The regions may have ended, or the last guard may be issuing the barrier for the
+    // requests made before, since `deferring()`.
+    if state.holders == 0 {
+        return false;
+    }
+    state.requested += 1;
+    let seq = state.requested;
+    // The last guard to drop issues a barrier for every request made before, and no region
+    // starts before it is done.
+    while state.completed < seq {
+        state = FLUSHED.wait(state).unwrap();
+    }
+    true
+}
+
+/// Issues a heavy memory barrier for slow path, deferring it while another thread is in a
+/// latency-critical region started by `critical_region()`.
+///
+/// If no other thread is in a critical region, this is `heavy()`. Otherwise, it blocks until
+/// the last region of the process ends, when a single `heavy()` serves all the deferred
+/// requests, so each of them still completes after a barrier that started after it was
+/// requested. On a thread that is itself in a critical region, it is issued right away. This
+/// trades the latency of the slow path for the determinism of the critical threads, which
+/// `heavy()` and the other barriers never wait for. It is only available with the `std`
+/// feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::thread;
+///
+/// let region = membarrier::critical_region();
+/// let reclaimer = thread::spawn(|| membarrier::heavy_deferred());
+/// membarrier::light(); // the reclaimer doesn't interrupt the current thread
+/// drop(region); // the deferred barrier is issued
+/// reclaimer.join().unwrap();
+/// ```
+pub fn heavy_deferred() {
+    if !defer() {
+        super::heavy();
+    }
+}
+
+/// Starts a latency-critical region on the current thread, in which `heavy_deferred()` on
+/// other threads is deferred.
+///
+/// While the returned guard is alive, `heavy_deferred()` on the other threads blocks instead of
+/// interrupting the CPUs of the process. When the last guard of the process is dropped, a
+/// single `heavy()` serves all the deferred requests. `heavy()` and the other barriers are
+/// never deferred, so a region only keeps away the threads that opted in with
+/// `heavy_deferred()`. A thread in a critical region must not wait for another thread's
+/// `heavy_deferred()`, which would deadlock. It is only available with the `std` feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// let region = membarrier::critical_region();
+/// membarrier::light(); // no other thread's `heavy_deferred()` interrupts the current one
+/// drop(region); // the deferred barriers are issued
+/// ```
+pub fn critical_region() -> CriticalGuard {
+    let mut state = lock();
+    // The barrier for the requests deferred by the previous regions mustn't land in this one.
+    while state.flushing {
+        state = FLUSHED.wait(state).unwrap();
+    }
+    state.holders += 1;
+    HOLDERS.store(state.holders, Ordering::SeqCst);
+    drop(state);
+    HELD.with(|held| held.set(held.get() + 1));
+    CriticalGuard(PhantomData)
+}
+
+/// A latency-critical region started by `critical_region()`, which ends when it is dropped.
+///
+/// It belongs to the thread that started the region, so it is neither `Send` nor `Sync`.
+#[derive(Debug)]
+pub struct CriticalGuard(PhantomData<*const ()>);
+
+impl Drop for CriticalGuard {
+    fn drop(&mut self) {
+        let mut state = lock();
+        state.holders -= 1;
+        HOLDERS.store(state.holders, Ordering::SeqCst);
+        if state.holders == 0 && state.completed != state.requested {
+            let target = state.requested;
+            state.flushing = true;
+            drop(state);
+
+            super::heavy();
+
+            state = lock();
+            state.flushing = false;
+            state.completed = target;
+            FLUSHED.notify_all();
+        }
+        drop(state);
+        HELD.with(|held| held.set(held.get() - 1));
+    }
+}
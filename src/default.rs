@@ -0,0 +1,306 @@
+#[allow(unused_imports)]
+use core::sync::atomic::{self, fence, Ordering};
+
+use core::time::Duration;
+
+use super::{Backend, BarrierError, Capabilities, HeavyCost, Timeout};
+
+#[cfg(feature = "diagnostics")]
+use super::BarrierReport;
+
+/// Reports once, via `defmt` or `log`, that this platform only has fence-based barriers.
+///
+/// Only plain loads and stores are used so that this works on targets without atomic
+/// read-modify-write instructions. Two racing threads may therefore both report, which is
+/// harmless.
+#[cfg(any(feature = "defmt", feature = "log"))]
+#[inline]
+fn report() {
+    use core::sync::atomic::AtomicBool;
+
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+
+    if !REPORTED.load(Ordering::Relaxed) {
+        REPORTED.store(true, Ordering::Relaxed);
+        #[cfg(feature = "defmt")]
+        defmt::info!("membarrier: no process-wide barrier available, using SeqCst fences");
+        #[cfg(feature = "log")]
+        log::info!("membarrier: no process-wide barrier available, using SeqCst fences");
+    }
+}
+
+#[cfg(not(any(feature = "defmt", feature = "log")))]
+#[inline(always)]
+fn report() {}
+
+/// The heavy barrier provided by `set_heavy_impl()` as the address of a `fn()`, or 0 if none
+/// was provided.
+///
+/// Like `report()`, it only uses plain loads and stores, as bare-metal targets often lack
+/// atomic read-modify-write instructions.
+#[cfg(target_os = "none")]
+static HEAVY_IMPL: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// Returns the heavy barrier provided by `set_heavy_impl()`, if any.
+#[cfg(all(target_os = "none", not(feature = "force-fence")))]
+#[inline]
+fn heavy_impl() -> Option<fn()> {
+    match HEAVY_IMPL.load(Ordering::Acquire) {
+        0 => None,
+        f => Some(unsafe { core::mem::transmute::<usize, fn()>(f) }),
+    }
+}
+
+/// Provides the heavy barrier of a bare-metal system, e.g. a routine of the HAL that
+/// interrupts every other core with an IPI and has it issue a barrier before returning.
+///
+/// From then on, `heavy()` calls `f`, and `light()` is a compiler fence. Until then, both are
+/// normal memory barriers. Call it once, while the other cores don't issue barriers yet, e.g.
+/// before they are started: a `light()` racing with it may already be a compiler fence while
+/// a racing `heavy()` is still a fence. With the `force-fence` feature, `f` is never called.
+///
+/// It is only available on systems without an operating system, i.e. `target_os = "none"`.
+///
+/// # Examples
+///
+/// ```ignore
+/// extern crate membarrier;
+///
+/// fn broadcast_barrier() {
+///     // Send an IPI to every other core, and wait until each has issued a barrier.
+/// }
+///
+/// membarrier::set_heavy_impl(broadcast_barrier);
+/// membarrier::heavy(); // calls `broadcast_barrier()`
+/// ```
+#[cfg(target_os = "none")]
+pub fn set_heavy_impl(f: fn()) {
+    HEAVY_IMPL.store(f as usize, Ordering::Release);
+}
+
+/// Issues a light memory barrier for fast path.
+///
+/// It just issues the normal memory barrier instruction. With the `signal-barrier` feature on
+/// Unix, it instead registers the current thread for `heavy()` and issues a compiler fence, or
+/// still the normal memory barrier instruction if the thread can't be registered. On bare-metal
+/// systems, it is a compiler fence once a heavy barrier was provided with `set_heavy_impl()`.
+#[inline]
+pub fn light() {
+    #[cfg(feature = "tsan")]
+    super::tsan::light();
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            if super::signal::register() {
+                atomic::compiler_fence(Ordering::SeqCst);
+            } else {
+                report();
+                fence(Ordering::SeqCst);
+            }
+        } else if #[cfg(all(target_os = "none", not(feature = "force-fence")))] {
+            if heavy_impl().is_some() {
+                atomic::compiler_fence(Ordering::SeqCst);
+            } else {
+                report();
+                fence(Ordering::SeqCst);
+            }
+        } else {
+            report();
+            fence(Ordering::SeqCst);
+        }
+    }
+    #[cfg(feature = "metrics")]
+    super::metrics::light();
+}
+
+/// Issues a heavy memory barrier for slow path.
+///
+/// It just issues the normal memory barrier instruction. With the `signal-barrier` feature on
+/// Unix, it instead sends the last realtime signal to every thread that issued `light()`, and
+/// waits until each of them has issued a barrier in the signal handler, provided the system
+/// has realtime signals and no handler is installed for that one yet. It never returns if a
+/// thread blocks the signal for good. On bare-metal systems, it calls the heavy barrier
+/// provided with `set_heavy_impl()`, if any.
+///
+/// # Aborts
+///
+/// With the `signal-barrier` feature, aborts if a thread cannot be signaled.
+#[inline]
+pub fn heavy() {
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            fatal_assert!(try_heavy().is_ok());
+        } else {
+            // Only the signal-based barrier can fail, and `abort()` may not even exist here.
+            let _ = try_heavy();
+        }
+    }
+}
+
+/// Implements `try_heavy()`, which only fails with the signal-based barrier.
+pub fn try_heavy() -> Result<(), BarrierError> {
+    if super::single_caller::heavy() {
+        return Ok(());
+    }
+    issue()
+}
+
+/// Issues the barrier of `heavy()`.
+#[inline]
+fn issue() -> Result<(), BarrierError> {
+    #[cfg(feature = "metrics")]
+    let started = super::metrics::heavy_started();
+    let generation = super::generation::begin();
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            super::signal::try_barrier()?;
+        } else if #[cfg(all(target_os = "none", not(feature = "force-fence")))] {
+            match heavy_impl() {
+                Some(f) => {
+                    fence(Ordering::SeqCst);
+                    f();
+                    fence(Ordering::SeqCst);
+                }
+                None => {
+                    report();
+                    fence(Ordering::SeqCst);
+                }
+            }
+        } else {
+            report();
+            fence(Ordering::SeqCst);
+        }
+    }
+    super::generation::end(generation);
+    #[cfg(feature = "metrics")]
+    super::metrics::heavy_finished(started);
+    Ok(())
+}
+
+/// Implements `try_heavy_timeout()`, which only waits with the signal-based barrier.
+#[inline]
+pub fn try_heavy_timeout(timeout: Duration) -> Result<(), Timeout> {
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            let generation = super::generation::begin();
+            match super::signal::barrier(Some(timeout)) {
+                Ok(()) => {}
+                Err(super::signal::Missed::Timeout) => return Err(Timeout),
+                Err(super::signal::Missed::Unsupported(_)) => heavy(),
+            }
+            super::generation::end(generation);
+            Ok(())
+        } else {
+            let _ = timeout;
+            heavy();
+            Ok(())
+        }
+    }
+}
+
+/// Implements `heavy_reporting()`, reporting the threads the signal-based barrier signaled.
+#[cfg(feature = "diagnostics")]
+pub fn heavy_reporting() -> BarrierReport {
+    heavy();
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            let threads = if super::signal::is_supported() {
+                super::signal::thread_count()
+            } else {
+                None
+            };
+        } else {
+            let threads = None;
+        }
+    }
+    BarrierReport::new(backend(), threads, None)
+}
+
+/// Implements `init()` by registering the current thread for the signal-based barrier.
+#[inline]
+pub fn init() {
+    #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))]
+    super::signal::register();
+}
+
+/// Implements `reinit_after_fork()` by unregistering the threads of the parent from the
+/// signal-based barrier.
+#[inline]
+pub unsafe fn reinit_after_fork() {
+    #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))]
+    super::signal::reinit_after_fork();
+}
+
+/// Implements `heavy_signal_safe()`, which only issues the fence.
+#[inline]
+pub fn heavy_signal_safe() -> bool {
+    has_signal_safe_heavy() && issue().is_ok()
+}
+
+/// Implements `has_signal_safe_heavy()`, which holds unless `heavy()` was replaced.
+#[inline]
+pub fn has_signal_safe_heavy() -> bool {
+    backend() == Backend::Fence
+}
+
+/// Implements `is_supported()` without installing the signal handler.
+pub fn is_supported() -> bool {
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            super::signal::is_available()
+        } else {
+            backend() != Backend::Fence
+        }
+    }
+}
+
+/// Implements `backend()`, which is the fence unless the signal-based barrier or
+/// `set_heavy_impl()` replaced it.
+#[inline]
+pub fn backend() -> Backend {
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            if super::signal::is_supported() {
+                Backend::Signal
+            } else {
+                Backend::Fence
+            }
+        } else if #[cfg(all(target_os = "none", not(feature = "force-fence")))] {
+            if heavy_impl().is_some() {
+                Backend::Custom
+            } else {
+                Backend::Fence
+            }
+        } else {
+            Backend::Fence
+        }
+    }
+}
+
+/// Implements `expected_heavy_cost()`, assuming that a bare-metal system has a handful of
+/// cores.
+#[inline]
+pub fn expected_heavy_cost() -> HeavyCost {
+    cfg_if! {
+        if #[cfg(all(unix, feature = "signal-barrier", not(feature = "force-fence")))] {
+            if super::signal::is_supported() {
+                HeavyCost::of_reach(super::signal::thread_count())
+            } else {
+                HeavyCost::Cheap
+            }
+        } else if #[cfg(all(target_os = "none", not(feature = "force-fence")))] {
+            if heavy_impl().is_some() {
+                HeavyCost::Moderate
+            } else {
+                HeavyCost::Cheap
+            }
+        } else {
+            HeavyCost::Cheap
+        }
+    }
+}
+
+/// Implements `capabilities()`, which only knows the backend on these systems.
+#[inline]
+pub fn capabilities() -> Capabilities {
+    Capabilities::new(backend())
+}
@@ -0,0 +1,525 @@
+//! The process-wide barriers on FreeBSD.
+//!
+//! Kernels with `membarrier(2)`, which FreeBSD modeled after the Linux system call, use its
+//! private expedited command. The others fall back to the `mprotect()`-based trick on x86 and
+//! x86-64, whose TLB shootdowns interrupt every CPU the process runs on just like on Linux, and
+//! then to the signal-based barrier of the `signal-barrier` feature or to fences.
+
+use core::sync::atomic;
+use core::time::Duration;
+
+use super::posix::mprotect;
+use super::spin_once::SpinOnce;
+use super::{
+    Backend, BarrierError, Capabilities, Command, HeavyCost, HeldResources, RegisterError, Syscall,
+    Timeout,
+};
+
+#[cfg(feature = "diagnostics")]
+use super::BarrierReport;
+
+mod membarrier {
+    use core::mem;
+
+    use super::super::posix::errno;
+    use super::super::{Command, RegisterError};
+    use super::SpinOnce;
+
+    /// The commands of `membarrier(2)`, which FreeBSD numbers like Linux. You can find them
+    /// in `<sys/membarrier.h>`.
+    #[repr(i32)]
+    #[derive(Clone, Copy)]
+    #[allow(dead_code, non_camel_case_types)]
+    enum membarrier_cmd {
+        MEMBARRIER_CMD_QUERY = 0,
+        MEMBARRIER_CMD_GLOBAL = (1 << 0),
+        MEMBARRIER_CMD_GLOBAL_EXPEDITED = (1 << 1),
+        MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED = (1 << 2),
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED = (1 << 3),
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED = (1 << 4),
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE = (1 << 5),
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE = (1 << 6),
+    }
+
+    /// The signature of the `membarrier()` wrapper of libc.
+    type Membarrier = unsafe extern "C" fn(libc::c_int, libc::c_uint, libc::c_int) -> libc::c_int;
+
+    /// The `membarrier()` wrapper, if libc exports it.
+    static MEMBARRIER: SpinOnce<Option<Membarrier>> = SpinOnce::new();
+
+    /// Returns the `membarrier()` wrapper, looking it up on first use.
+    ///
+    /// FreeBSD sends `SIGSYS` for system calls the kernel lacks rather than failing them with
+    /// `ENOSYS`, so the call is only made through the wrapper of a libc that knows it, which
+    /// comes with a kernel that does too.
+    fn membarrier() -> Option<Membarrier> {
+        *MEMBARRIER.get_or_init(|| unsafe {
+            let symbol = libc::dlsym(
+                libc::RTLD_DEFAULT,
+                b"membarrier\0".as_ptr() as *const libc::c_char,
+            );
+            if symbol.is_null() {
+                None
+            } else {
+                Some(mem::transmute::<*mut libc::c_void, Membarrier>(symbol))
+            }
+        })
+    }
+
+    /// Calls `membarrier(2)` with `cmd`, returning `-1` with `errno` set to `ENOSYS` if it is
+    /// unavailable.
+    fn sys_membarrier(cmd: membarrier_cmd) -> libc::c_int {
+        match membarrier() {
+            Some(membarrier) => unsafe { membarrier(cmd as libc::c_int, 0, 0) },
+            None => {
+                unsafe { *libc::__error() = libc::ENOSYS };
+                -1
+            }
+        }
+    }
+
+    /// What `detect()` found out about `membarrier(2)`.
+    #[derive(Clone, Copy)]
+    pub struct Detection {
+        /// The commands supported by the kernel, or `None` if the call is unavailable.
+        pub commands: Option<u32>,
+        /// Whether private expedited membarrier is supported and registered.
+        pub usable: bool,
+    }
+
+    /// Probes `membarrier(2)`, registering the current process as a user of private expedited
+    /// membarrier if `register` is `true`.
+    pub fn detect(register: bool) -> Detection {
+        let mut detection = Detection {
+            commands: None,
+            usable: false,
+        };
+
+        let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_QUERY);
+        if ret < 0 {
+            return detection;
+        }
+        let commands = ret as u32;
+        detection.commands = Some(commands);
+
+        let required = membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED as u32
+            | membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED as u32;
+        detection.usable = commands & required == required
+            && register
+            && sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED) == 0;
+        detection
+    }
+
+    /// Returns whether the kernel offers private expedited membarrier, without registering the
+    /// current process for it.
+    pub fn is_supported() -> bool {
+        let required = membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED as u32
+            | membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED as u32;
+        let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_QUERY);
+        ret >= 0 && ret as u32 & required == required
+    }
+
+    /// Returns the command to issue for `command` and the one to register for it, or `None`
+    /// for `Command::PrivateExpeditedRseq`, as FreeBSD has no restartable sequences.
+    fn commands_of(command: Command) -> Option<(membarrier_cmd, membarrier_cmd)> {
+        match command {
+            Command::PrivateExpedited => Some((
+                membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED,
+                membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED,
+            )),
+            Command::PrivateExpeditedSyncCore => Some((
+                membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE,
+                membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE,
+            )),
+            Command::GlobalExpedited => Some((
+                membarrier_cmd::MEMBARRIER_CMD_GLOBAL_EXPEDITED,
+                membarrier_cmd::MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED,
+            )),
+            Command::PrivateExpeditedRseq => None,
+        }
+    }
+
+    /// Registers the current process for all of `commands`.
+    ///
+    /// FreeBSD can't tell which commands the process is registered for, but registering
+    /// again is harmless.
+    pub fn register_all(commands: &[Command]) -> Result<(), RegisterError> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_QUERY);
+        if ret < 0 {
+            return Err(RegisterError::from_errno(errno()));
+        }
+        let supported = ret as u32;
+        for &command in commands {
+            let supports = match commands_of(command) {
+                Some((issue, register)) => {
+                    let required = issue as u32 | register as u32;
+                    supported & required == required
+                }
+                None => false,
+            };
+            if !supports {
+                return Err(RegisterError::Unsupported);
+            }
+        }
+        for &command in commands {
+            if let Some((_, register)) = commands_of(command) {
+                if sys_membarrier(register) < 0 {
+                    return Err(RegisterError::from_errno(errno()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a heavy `membarrier(2)`-based barrier, aborting if it fails.
+    ///
+    /// It only fails if the process isn't registered, which `detect()` made sure it is.
+    #[inline]
+    pub fn barrier() {
+        fatal_assert!(try_barrier().is_ok());
+    }
+
+    /// Executes a heavy `membarrier(2)`-based barrier, returning the `errno` of any failure.
+    #[inline]
+    pub fn try_barrier() -> Result<(), libc::c_int> {
+        if sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED) == 0 {
+            Ok(())
+        } else {
+            Err(errno())
+        }
+    }
+
+    /// Executes a heavy barrier with `MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE`, which also
+    /// serializes the instruction stream of the interrupted threads, and returns the `errno`
+    /// of any failure. The process must be registered for the command.
+    #[inline]
+    pub fn try_sync_core() -> Result<(), libc::c_int> {
+        if sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE) == 0 {
+            Ok(())
+        } else {
+            Err(errno())
+        }
+    }
+}
+
+/// A choice between the strategies for process-wide barrier on FreeBSD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Strategy {
+    /// Use `membarrier(2)`.
+    Membarrier,
+    /// Use the `mprotect`-based trick.
+    Mprotect,
+    /// Use a realtime signal sent to every thread that issued `light()`.
+    #[cfg(feature = "signal-barrier")]
+    Signal,
+    /// Use `SeqCst` fences.
+    Fallback,
+}
+
+/// What `membarrier(2)` offers on the current machine.
+static MEMBARRIER: SpinOnce<membarrier::Detection> = SpinOnce::new();
+
+/// The right strategy to use on the current machine.
+static STRATEGY: SpinOnce<Strategy> = SpinOnce::new();
+
+/// Returns what `membarrier(2)` offers, probing it on first use.
+fn detection() -> &'static membarrier::Detection {
+    MEMBARRIER.get_or_init(|| membarrier::detect(super::config().auto_register))
+}
+
+/// Returns the strategy, selecting it on first use.
+#[inline]
+fn strategy() -> Strategy {
+    *STRATEGY.get_or_init(select)
+}
+
+/// Selects the fastest strategy, or the one of `Config::prefer` if it is available.
+#[cold]
+fn select() -> Strategy {
+    let config = super::config();
+    let mprotect = || {
+        config.allow_mprotect
+            && mprotect::is_supported()
+            && mprotect::self_test()
+            && mprotect::is_permitted()
+    };
+
+    let strategy = if config.prefer == Some(Backend::Mprotect) && mprotect() {
+        Strategy::Mprotect
+    } else if detection().usable {
+        Strategy::Membarrier
+    } else if mprotect() {
+        Strategy::Mprotect
+    } else {
+        cfg_if! {
+            if #[cfg(feature = "signal-barrier")] {
+                if super::signal::is_supported() {
+                    Strategy::Signal
+                } else {
+                    Strategy::Fallback
+                }
+            } else {
+                Strategy::Fallback
+            }
+        }
+    };
+    #[cfg(feature = "log")]
+    log::info!("membarrier: using {:?}", backend_of(strategy));
+    strategy
+}
+
+/// Returns the mechanism `strategy` uses.
+fn backend_of(strategy: Strategy) -> Backend {
+    match strategy {
+        Strategy::Membarrier => Backend::Membarrier,
+        Strategy::Mprotect => Backend::Mprotect,
+        #[cfg(feature = "signal-barrier")]
+        Strategy::Signal => Backend::Signal,
+        Strategy::Fallback => Backend::Fence,
+    }
+}
+
+/// Issues a light memory barrier for fast path.
+///
+/// It issues a compiler fence, which disallows compiler optimizations across itself, if a
+/// process-wide barrier is available. With the signal-based barrier, the first call on each
+/// thread also registers it for `heavy()`, and a thread that can't be registered issues the
+/// normal memory barrier instruction. Otherwise, it issues the normal memory barrier
+/// instruction.
+#[inline]
+pub fn light() {
+    use self::Strategy::*;
+    #[cfg(feature = "tsan")]
+    super::tsan::light();
+    match strategy() {
+        Membarrier | Mprotect => atomic::compiler_fence(atomic::Ordering::SeqCst),
+        #[cfg(feature = "signal-barrier")]
+        Signal => {
+            if super::signal::register() {
+                atomic::compiler_fence(atomic::Ordering::SeqCst);
+            } else {
+                atomic::fence(atomic::Ordering::SeqCst);
+            }
+        }
+        Fallback => atomic::fence(atomic::Ordering::SeqCst),
+    }
+    #[cfg(feature = "metrics")]
+    super::metrics::light();
+}
+
+/// Issues a heavy memory barrier for slow path.
+///
+/// It issues a private expedited `membarrier(2)` call if the kernel offers it, and otherwise
+/// uses the `mprotect()`-based trick on x86 and x86-64. Where neither is available, it sends a
+/// realtime signal to every thread that issued `light()` with the `signal-barrier` feature if
+/// no handler is installed for it yet, and just issues the normal memory barrier instruction
+/// otherwise.
+#[inline]
+pub fn heavy() {
+    fatal_assert!(try_heavy().is_ok());
+}
+
+/// Implements `try_heavy()` with the selected strategy, which is never replaced.
+pub fn try_heavy() -> Result<(), BarrierError> {
+    if super::single_caller::heavy() {
+        return Ok(());
+    }
+    #[cfg(feature = "metrics")]
+    let started = super::metrics::heavy_started();
+    let generation = super::generation::begin();
+    match strategy() {
+        Strategy::Membarrier => membarrier::try_barrier()
+            .map_err(|errno| BarrierError::new(Syscall::Membarrier, errno))?,
+        Strategy::Mprotect => mprotect::try_barrier(mprotect::Method::Protect)?,
+        #[cfg(feature = "signal-barrier")]
+        Strategy::Signal => super::signal::try_barrier()?,
+        Strategy::Fallback => atomic::fence(atomic::Ordering::SeqCst),
+    }
+    super::generation::end(generation);
+    #[cfg(feature = "metrics")]
+    super::metrics::heavy_finished(started);
+    Ok(())
+}
+
+/// Whether the process is registered for `MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE`, or why
+/// it couldn't be.
+static SYNC_CORE: SpinOnce<Result<(), RegisterError>> = SpinOnce::new();
+
+/// Implements `sync_core()` with the `membarrier(2)` command.
+pub fn sync_core() -> Result<(), RegisterError> {
+    (*SYNC_CORE.get_or_init(|| {
+        if super::config().auto_register {
+            register_all(&[Command::PrivateExpeditedSyncCore])
+        } else {
+            Ok(())
+        }
+    }))?;
+    let generation = super::generation::begin();
+    membarrier::try_sync_core().map_err(RegisterError::from_errno)?;
+    super::generation::end(generation);
+    Ok(())
+}
+
+/// Implements `try_heavy_timeout()`, only waiting for the `mprotect()`-based and signal-based
+/// barriers.
+pub fn try_heavy_timeout(timeout: Duration) -> Result<(), Timeout> {
+    match strategy() {
+        Strategy::Mprotect => {
+            let generation = super::generation::begin();
+            if !mprotect::barrier_timeout(mprotect::Method::Protect, timeout) {
+                return Err(Timeout);
+            }
+            super::generation::end(generation);
+            Ok(())
+        }
+        #[cfg(feature = "signal-barrier")]
+        Strategy::Signal => {
+            let generation = super::generation::begin();
+            match super::signal::barrier(Some(timeout)) {
+                Ok(()) => {}
+                Err(super::signal::Missed::Timeout) => return Err(Timeout),
+                Err(super::signal::Missed::Unsupported(_)) => heavy(),
+            }
+            super::generation::end(generation);
+            Ok(())
+        }
+        Strategy::Membarrier | Strategy::Fallback => {
+            heavy();
+            Ok(())
+        }
+    }
+}
+
+/// Implements `heavy_reporting()`, reporting the online CPUs or the signaled threads.
+#[cfg(feature = "diagnostics")]
+pub fn heavy_reporting() -> BarrierReport {
+    heavy();
+    let (threads, cpus) = match strategy() {
+        Strategy::Membarrier | Strategy::Mprotect => (None, online_cpus()),
+        #[cfg(feature = "signal-barrier")]
+        Strategy::Signal => (super::signal::thread_count(), None),
+        Strategy::Fallback => (None, None),
+    };
+    BarrierReport::new(backend(), threads, cpus)
+}
+
+/// Implements `init()` by selecting the strategy and registering the current thread for the
+/// signal-based barrier.
+#[inline]
+pub fn init() {
+    let _ = strategy();
+    #[cfg(feature = "signal-barrier")]
+    super::signal::register();
+}
+
+/// Implements `reinit_after_fork()`, replacing the page of the `mprotect()`-based barrier and
+/// registering for `membarrier(2)` again.
+pub unsafe fn reinit_after_fork() {
+    mprotect::reinit_after_fork();
+    #[cfg(feature = "signal-barrier")]
+    super::signal::reinit_after_fork();
+    if STRATEGY.get() == Some(&Strategy::Membarrier) {
+        let _ = membarrier::register_all(&[Command::PrivateExpedited]);
+    }
+    if SYNC_CORE.get() == Some(&Ok(())) {
+        let _ = membarrier::register_all(&[Command::PrivateExpeditedSyncCore]);
+    }
+}
+
+/// Implements `heavy_signal_safe()` with `membarrier(2)` or the fence.
+pub fn heavy_signal_safe() -> bool {
+    if !has_signal_safe_heavy() {
+        return false;
+    }
+    let generation = super::generation::begin();
+    match STRATEGY.get() {
+        Some(&Strategy::Membarrier) => membarrier::barrier(),
+        _ => atomic::fence(atomic::Ordering::SeqCst),
+    }
+    super::generation::end(generation);
+    true
+}
+
+/// Implements `has_signal_safe_heavy()` for the selected strategy.
+pub fn has_signal_safe_heavy() -> bool {
+    let strategy = STRATEGY.get();
+    strategy == Some(&Strategy::Membarrier) || strategy == Some(&Strategy::Fallback)
+}
+
+/// Implements `is_supported()` by probing `membarrier(2)` and the strategies the configuration
+/// allows until one is selected.
+pub fn is_supported() -> bool {
+    if let Some(&strategy) = STRATEGY.get() {
+        return strategy != Strategy::Fallback;
+    }
+    let config = super::CONFIG.get().cloned().unwrap_or_default();
+    let membarrier = match MEMBARRIER.get() {
+        Some(detection) => detection.usable,
+        None => config.auto_register && membarrier::is_supported(),
+    };
+    #[cfg(feature = "signal-barrier")]
+    let signal = super::signal::is_available();
+    #[cfg(not(feature = "signal-barrier"))]
+    let signal = false;
+    membarrier || config.allow_mprotect && mprotect::is_supported() || signal
+}
+
+/// Implements `backend()` by selecting the strategy.
+#[inline]
+pub fn backend() -> Backend {
+    backend_of(strategy())
+}
+
+/// Implements `expected_heavy_cost()` from the online CPUs or the registered threads.
+pub fn expected_heavy_cost() -> HeavyCost {
+    match strategy() {
+        Strategy::Membarrier | Strategy::Mprotect => HeavyCost::of_reach(online_cpus()),
+        #[cfg(feature = "signal-barrier")]
+        Strategy::Signal => HeavyCost::of_reach(super::signal::thread_count()),
+        Strategy::Fallback => HeavyCost::Cheap,
+    }
+}
+
+/// Returns the number of online CPUs, or `None` if it is unknown.
+fn online_cpus() -> Option<usize> {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus > 0 {
+        Some(cpus as usize)
+    } else {
+        None
+    }
+}
+
+/// Implements `capabilities()`, adding the `membarrier(2)` commands the kernel offers.
+pub fn capabilities() -> Capabilities {
+    let mut capabilities = Capabilities::new(backend());
+    capabilities.membarrier_commands = detection().commands;
+    capabilities
+}
+
+/// Implements `register_all()` with `membarrier(2)`.
+pub fn register_all(commands: &[Command]) -> Result<(), RegisterError> {
+    membarrier::register_all(commands)
+}
+
+/// Implements `fds()` with the page of the `mprotect()`-based barrier.
+pub fn fds() -> HeldResources {
+    let mut resources = HeldResources::default();
+    if let Some(mapping) = mprotect::mapping(mprotect::Method::Protect) {
+        resources.push_mapping(mapping);
+    }
+    resources
+}
+
+/// Implements `mprotect_page_locked()` for the `mprotect()`-based strategy.
+pub fn mprotect_page_locked() -> Option<bool> {
+    if STRATEGY.get() == Some(&Strategy::Mprotect) {
+        mprotect::page_locked()
+    } else {
+        None
+    }
+}
@@ -0,0 +1,237 @@
+//! GNU Mach only runs on x86 and x86-64, which are the only architectures whose thread state
+//! flavor we know. The other architectures use the `default` module instead.
+
+use core::sync::atomic;
+use core::time::Duration;
+
+use super::{Backend, BarrierError, Capabilities, HeavyCost, Timeout};
+
+#[cfg(feature = "diagnostics")]
+use super::BarrierReport;
+
+mod barrier {
+    #![allow(non_camel_case_types)]
+    #![allow(non_upper_case_globals)]
+
+    use core::mem;
+    use core::ptr;
+    use core::slice;
+    use core::sync::atomic;
+
+    use libc::{c_int, c_uint};
+
+    type natural_t = c_uint;
+    type kern_return_t = c_int;
+    type mach_port_t = natural_t;
+    type thread_t = mach_port_t;
+    type mach_msg_type_number_t = natural_t;
+    type vm_address_t = usize;
+    type vm_size_t = usize;
+
+    const KERN_SUCCESS: kern_return_t = 0;
+
+    /// `i386_THREAD_STATE` in `<mach/i386/thread_status.h>`.
+    const i386_THREAD_STATE: c_int = 1;
+
+    /// The capacity of the thread state buffer, in `natural_t`s.
+    ///
+    /// GNU Mach only rejects buffers smaller than the requested flavor, so a buffer that is
+    /// large enough for both i686 and x86_64 lets us avoid mirroring `i386_thread_state`.
+    const THREAD_STATE_CAPACITY: usize = 64;
+
+    // The RPC stubs live in `libmachuser`, while the task port is a variable in glibc.
+    #[link(name = "machuser")]
+    extern "C" {
+        static __mach_task_self_: mach_port_t;
+
+        fn task_threads(
+            target_task: mach_port_t,
+            act_list: *mut *mut thread_t,
+            act_list_cnt: *mut mach_msg_type_number_t,
+        ) -> kern_return_t;
+
+        fn thread_get_state(
+            target_thread: thread_t,
+            flavor: c_int,
+            old_state: *mut natural_t,
+            old_state_count: *mut mach_msg_type_number_t,
+        ) -> kern_return_t;
+
+        fn mach_port_deallocate(task: mach_port_t, name: mach_port_t) -> kern_return_t;
+
+        fn vm_deallocate(
+            target_task: mach_port_t,
+            address: vm_address_t,
+            size: vm_size_t,
+        ) -> kern_return_t;
+    }
+
+    /// Returns the number of threads of the current task, or `None` if they could not be
+    /// enumerated.
+    pub unsafe fn thread_count() -> Option<usize> {
+        let task = __mach_task_self_;
+        let mut thread_count: mach_msg_type_number_t = 0;
+        let mut thread_acts: *mut thread_t = ptr::null_mut();
+
+        if task_threads(task, &mut thread_acts, &mut thread_count) != KERN_SUCCESS {
+            return None;
+        }
+
+        for act in slice::from_raw_parts(thread_acts, thread_count as usize) {
+            let _ = mach_port_deallocate(task, *act);
+        }
+        let _ = vm_deallocate(
+            task,
+            thread_acts as vm_address_t,
+            thread_count as usize * mem::size_of::<thread_t>(),
+        );
+
+        Some(thread_count as usize)
+    }
+
+    /// Issue a heavy memory barrier, and returns the number of threads it interrupted, or `None`
+    /// if they cannot be enumerated.
+    ///
+    /// Fetching the state of a thread makes GNU Mach halt it at a clean point, which
+    /// serializes the thread in the same way the Apple backend relies on. If the threads
+    /// cannot be enumerated, it falls back to the normal memory barrier instruction.
+    ///
+    /// Failing to fetch the state of an individual thread is ignored: it either has already
+    /// exited, or it is the current thread, which is covered by the fence issued up front.
+    #[inline]
+    pub unsafe fn flush_process_write_buffers() -> Option<usize> {
+        atomic::fence(atomic::Ordering::SeqCst);
+
+        let task = __mach_task_self_;
+        let mut thread_count: mach_msg_type_number_t = 0;
+        let mut thread_acts: *mut thread_t = ptr::null_mut();
+
+        if task_threads(task, &mut thread_acts, &mut thread_count) != KERN_SUCCESS {
+            return None;
+        }
+
+        let thread_acts_arr = slice::from_raw_parts(thread_acts, thread_count as usize);
+        let mut thread_state: [natural_t; THREAD_STATE_CAPACITY] = mem::zeroed();
+
+        for act in thread_acts_arr {
+            let mut count = THREAD_STATE_CAPACITY as mach_msg_type_number_t;
+            let _ = thread_get_state(
+                *act,
+                i386_THREAD_STATE,
+                thread_state.as_mut_ptr(),
+                &mut count,
+            );
+            let _ = mach_port_deallocate(task, *act);
+        }
+
+        let _ = vm_deallocate(
+            task,
+            thread_acts as vm_address_t,
+            thread_count as usize * mem::size_of::<thread_t>(),
+        );
+        Some(thread_count as usize)
+    }
+}
+
+/// Issues a light memory barrier for fast path.
+///
+/// It issues a compiler fence, which disallows compiler optimizations across itself. It incurs
+/// basically no costs in run-time.
+#[inline]
+pub fn light() {
+    #[cfg(feature = "tsan")]
+    super::tsan::light();
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+    #[cfg(feature = "metrics")]
+    super::metrics::light();
+}
+
+/// Issues heavy memory barrier for slow path.
+///
+/// It fetches the state of every thread of the current task, which forces GNU Mach to halt
+/// each of them. This is a best-effort port of the Apple backend: if the threads cannot be
+/// enumerated, it falls back to the normal memory barrier instruction.
+#[inline]
+pub fn heavy() {
+    fatal_assert!(try_heavy().is_ok());
+}
+
+/// Implements `try_heavy()`, which never fails as the barrier falls back to a fence.
+pub fn try_heavy() -> Result<(), BarrierError> {
+    if super::single_caller::heavy() {
+        return Ok(());
+    }
+    #[cfg(feature = "metrics")]
+    let started = super::metrics::heavy_started();
+    flush();
+    #[cfg(feature = "metrics")]
+    super::metrics::heavy_finished(started);
+    Ok(())
+}
+
+/// Issues the heavy barrier, and returns the number of threads it interrupted, or `None` if it
+/// fell back to a fence.
+#[inline]
+fn flush() -> Option<usize> {
+    let generation = super::generation::begin();
+    let threads = unsafe { barrier::flush_process_write_buffers() };
+    super::generation::end(generation);
+    threads
+}
+
+/// Implements `try_heavy_timeout()`, which never waits.
+#[inline]
+pub fn try_heavy_timeout(_timeout: Duration) -> Result<(), Timeout> {
+    heavy();
+    Ok(())
+}
+
+/// Implements `heavy_reporting()`, reporting the threads the barrier halted.
+#[cfg(feature = "diagnostics")]
+pub fn heavy_reporting() -> BarrierReport {
+    let threads = flush();
+    BarrierReport::new(backend(), threads, None)
+}
+
+/// Implements `init()`, which is a no-op on the Hurd.
+#[inline]
+pub fn init() {}
+
+/// Implements `reinit_after_fork()`, which is a no-op as the Mach calls keep no state.
+#[inline]
+pub unsafe fn reinit_after_fork() {}
+
+/// Implements `heavy_signal_safe()`, which never issues the barrier, as it allocates the thread
+/// list.
+#[inline]
+pub fn heavy_signal_safe() -> bool {
+    false
+}
+
+/// Implements `has_signal_safe_heavy()`, which never holds on the Hurd.
+#[inline]
+pub fn has_signal_safe_heavy() -> bool {
+    false
+}
+
+/// Implements `is_supported()`, which always holds on the Hurd.
+pub fn is_supported() -> bool {
+    backend() != Backend::Fence
+}
+
+/// Implements `backend()`, which always is the Mach thread-state barrier.
+#[inline]
+pub fn backend() -> Backend {
+    Backend::MachThreadState
+}
+
+/// Implements `expected_heavy_cost()` from the current number of threads of the task.
+pub fn expected_heavy_cost() -> HeavyCost {
+    HeavyCost::of_reach(unsafe { barrier::thread_count() })
+}
+
+/// Implements `capabilities()`, which only knows the backend on the Hurd.
+#[inline]
+pub fn capabilities() -> Capabilities {
+    Capabilities::new(backend())
+}
@@ -25,9 +25,60 @@
 //! systems and hardware. It is implemented as follows. For recent Linux systems, we use the
 //! `sys_membarrier()` system call; and for those old Linux systems without support for
 //! `sys_membarrier()`, we fall back to the `mprotect()` system call that is known to provide
-//! process-wide memory barrier semantics. For Windows, we use the `FlushProcessWriteBuffers()`
-//! API. For all the other systems, we fall back to the normal `SeqCst` fence for both fast and slow
-//! paths.
+//! process-wide memory barrier semantics on x86, x86-64, and riscv64. FreeBSD gets the same, with
+//! its `membarrier(2)` modeled after the Linux system call, and the `mprotect()` trick on x86 and
+//! x86-64. For Windows, we use the `FlushProcessWriteBuffers()` API, or the
+//! `NtFlushProcessWriteBuffers()` entry point of `ntdll.dll` behind it with the `ntdll-flush`
+//! feature. On macOS, iOS, and GNU/Hurd, we interrupt every thread of the process by fetching its
+//! Mach thread state. For all the other systems, we fall back to the normal `SeqCst` fence for both
+//! fast and slow paths. On bare-metal systems, the HAL can provide a heavy barrier, e.g. an IPI to
+//! every other core, with `set_heavy_impl()`. With the `signal-barrier` feature, the other Unix
+//! systems instead get a slow but process-wide `heavy()` that interrupts every thread that issued
+//! `light()` with a realtime signal, unless a handler is already installed for it. With the
+//! `perf-barrier` feature, x86 and x86-64 Linux systems with neither `sys_membarrier()` nor the
+//! `mprotect()` trick interrupt every CPU by reading perf events pinned to them, if the process may
+//! open such events. Other architectures never do, as nothing guarantees that the interrupt orders
+//! the accesses of the CPU it interrupts there.
+//!
+//! `sys_membarrier()` gained its commands over several kernel releases:
+//!
+//! | Linux | Command                             | Used by                       |
+//! |-------|-------------------------------------|-------------------------------|
+//! | 4.3   | `MEMBARRIER_CMD_SHARED`             | `Backend::SharedMembarrier`   |
+//! | 4.14  | `MEMBARRIER_CMD_PRIVATE_EXPEDITED`  | `Backend::Membarrier`         |
+//! | 4.16  | `MEMBARRIER_CMD_GLOBAL_EXPEDITED`   | `register_all()`              |
+//! | 4.16  | `..._PRIVATE_EXPEDITED_SYNC_CORE`   | `sync_core()`                 |
+//! | 6.3   | `MEMBARRIER_CMD_GET_REGISTRATIONS`  | `init()` and `capabilities()` |
+//!
+//! Linux 4.16 renamed `MEMBARRIER_CMD_SHARED` to `MEMBARRIER_CMD_GLOBAL`. The shared command
+//! waits for every CPU to pass through a scheduler quiescent state, which takes milliseconds, so it
+//! is only preferred over fences. Kernels booted with `nohz_full` don't offer it.
+//!
+//! On Linux, the strategy is selected at run time by probing the running kernel, never at build
+//! time, so a binary cross-compiled on another machine or deployed to another kernel picks the
+//! right strategy for where it runs. The build script only looks at the target's deployment
+//! version on Apple systems.
+//!
+//! The `force-fence` feature selects the `SeqCst` fence fallback on every system, removing the
+//! system call and FFI code from the build entirely. In this mode `light()` is a full `SeqCst`
+//! fence, just like `heavy()`.
+//!
+//! Building with `RUSTFLAGS="--cfg membarrier_unsound_noop_heavy"` makes `heavy()` a `SeqCst`
+//! fence while `light()` stays cheap, so the barriers no longer synchronize. It is **unsound** for
+//! real concurrency, and only meant to speed up test suites whose correctness doesn't depend on
+//! the barriers. It is a `--cfg` rather than a Cargo feature so that no dependency can turn it on
+//! behind the back of the final binary.
+//!
+//! A coordinator that is the only thread ever issuing barriers can call the `unsafe`
+//! `assume_single_caller()`, which makes `heavy()` a `SeqCst` fence from then on: without another
+//! thread issuing `light()`, a fence orders everything a heavy barrier has to. It is a run-time
+//! opt-in of the final binary, so `light()` doesn't pay for it and no dependency can enable it.
+//!
+//! ThreadSanitizer only understands atomics and fences, so it reports data races in code that is
+//! synchronized by the system calls, page faults or interrupts behind `heavy()`. The `tsan`
+//! feature annotates the barriers for it: every `heavy()` synchronizes with the `light()` calls
+//! that ThreadSanitizer saw before it, and every `light()` with the `heavy()` calls before it. It
+//! calls into the ThreadSanitizer runtime, so it only links in builds with `-Zsanitizer=thread`.
 //!
 //!
 //! # Usage
@@ -43,6 +94,37 @@
 //! fence(Ordering::SeqCst); // normal barrier
 //! ```
 //!
+//! The fast path issues `light()` where it would otherwise need a `SeqCst` fence, and the slow path
+//! `heavy()`, e.g. in a store-load handshake between frequent readers and a rare writer:
+//!
+//! ```
+//! extern crate membarrier;
+//! use std::sync::atomic::{AtomicBool, Ordering};
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! let reading = Arc::new(AtomicBool::new(false));
+//! let retired = Arc::new(AtomicBool::new(false));
+//!
+//! let reader = {
+//!     let (reading, retired) = (reading.clone(), retired.clone());
+//!     thread::spawn(move || {
+//!         reading.store(true, Ordering::Relaxed);
+//!         membarrier::light(); // cheap, as readers are frequent
+//!         let usable = !retired.load(Ordering::Relaxed);
+//!         reading.store(false, Ordering::Release);
+//!         usable
+//!     })
+//! };
+//!
+//! retired.store(true, Ordering::Relaxed);
+//! membarrier::heavy(); // expensive, but writers are rare
+//! if !reading.load(Ordering::Relaxed) {
+//!     // The reader either is done or will see `retired`, so the retired data can be freed.
+//! }
+//! reader.join().unwrap();
+//! ```
+//!
 //! # Semantics
 //!
 //! Formally, there are three kinds of memory barrier: light one (`membarrier::light()`), heavy one
@@ -54,6 +136,34 @@
 //! - Either of A's or B's barrier is heavy; or
 //! - Both of A's and B's barriers are normal.
 //!
+//! # Completion
+//!
+//! `heavy()` returns only once the barrier has completed, not merely when it has been initiated: by
+//! then, every other thread has executed a barrier, so the writes it made before its last `light()`
+//! are visible to the caller. Every backend waits for that. `sys_membarrier()`, with the expedited
+//! commands or the legacy shared one, and `FlushProcessWriteBuffers()` are synchronous;
+//! `mprotect()` and `madvise()` return only after the interrupts that flush the TLBs have been
+//! acknowledged; the Mach calls return only after the thread was interrupted; the signal-based
+//! barrier waits until every thread acknowledged the signal; and the perf-event-based barrier reads
+//! each event on its CPU. Where both barriers are `SeqCst` fences, the other thread's own fence
+//! already made its writes visible. `heavy()` may thus be followed by plain loads of data that
+//! other threads published with `light()`, with no further synchronization.
+//!
+//! # Failures
+//!
+//! `light()` never fails, and on Linux never panics, which the `no-panic` directory checks at link
+//! time. Once the strategy was selected, e.g. by `init()`, it also never allocates, blocks, or
+//! issues a system call. If the system call behind `heavy()` unexpectedly fails after the strategy
+//! was selected, the process is aborted. `heavy()` is built on `try_heavy()`, which returns the
+//! failed system call and its `errno` as a `BarrierError` instead, for callers that would rather
+//! report it or degrade. Security policies that deny `mprotect()`, like PaX `MPROTECT`, are
+//! detected along with the strategy, so that another one is selected. As an exception, if
+//! `sys_membarrier()` is rejected by a sandbox that was tightened after startup, Linux on x86,
+//! x86-64, and riscv64 switches to the `mprotect()`-based barrier for good. No unwinding ever
+//! crosses a system call or FFI frame, as the barriers never panic and the abort happens in place.
+//! Unwinding through a foreign frame is undefined behavior, so any hook this crate calls back into
+//! in the future must uphold the same contract.
+//!
 //! # Reference
 //!
 //! For more information, see the [Linux `man` page for
@@ -64,12 +174,22 @@
 
 #[macro_use]
 extern crate cfg_if;
-#[allow(unused_imports)]
-#[macro_use]
-extern crate lazy_static;
+#[cfg(feature = "defmt")]
+extern crate defmt;
 extern crate libc;
+#[cfg(feature = "log")]
+extern crate log;
 extern crate windows_sys;
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 #[allow(unused_macros)]
 macro_rules! fatal_assert {
     ($cond:expr) => {
@@ -82,436 +202,3211 @@ macro_rules! fatal_assert {
     };
 }
 
-cfg_if! {
-    if #[cfg(all(target_os = "linux"))] {
-        pub use linux::*;
-    } else if #[cfg(target_os = "windows")] {
-        pub use windows::*;
-    } else if #[cfg(any(target_os = "macos", target_os = "ios"))] {
-        pub use apple::*;
-    } else {
-        pub use default::*;
+/// The mechanism `heavy()` uses on the current system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Backend {
+    /// The Linux `sys_membarrier()` system call.
+    Membarrier,
+    /// The legacy shared command of the Linux `sys_membarrier()` system call, now called global,
+    /// on kernels that predate its expedited commands. It waits for every CPU to pass through a
+    /// scheduler quiescent state, which takes milliseconds.
+    SharedMembarrier,
+    /// Changing the access protections of a dedicated page with `mprotect()`.
+    Mprotect,
+    /// Discarding a dedicated page with `madvise(MADV_DONTNEED)`.
+    Madvise,
+    /// The Windows `FlushProcessWriteBuffers()` API.
+    FlushProcessWriteBuffers,
+    /// The `NtFlushProcessWriteBuffers()` entry point of `ntdll.dll` behind it, called directly on
+    /// Windows with the `ntdll-flush` feature.
+    NtFlushProcessWriteBuffers,
+    /// Fetching the state of every Mach thread of the process.
+    MachThreadState,
+    /// Interrupting every thread with the last realtime signal, with the `signal-barrier` feature
+    /// on Unix systems that only have fences, or on Linux if `Config::allow_signals` is set.
+    Signal,
+    /// Reading a perf event pinned to every CPU, which interrupts each of them, on x86 and x86-64
+    /// Linux with the `perf-barrier` feature.
+    PerfEvent,
+    /// A heavy barrier provided with `set_heavy_impl()` on bare-metal systems.
+    Custom,
+    /// The normal `SeqCst` fence, i.e. no process-wide barrier at all.
+    Fence,
+}
+
+/// A hypervisor the process runs under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Hypervisor {
+    /// Linux KVM.
+    Kvm,
+    /// Xen.
+    Xen,
+    /// Microsoft Hyper-V.
+    HyperV,
+    /// VMware.
+    Vmware,
+    /// A hypervisor that is not known to this crate.
+    Other,
+}
+
+/// Why the process can't use private expedited `sys_membarrier()` on Linux.
+///
+/// It is reported by `Capabilities::register_error()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RegisterError {
+    /// The kernel doesn't support `sys_membarrier()` or its private expedited commands.
+    Unsupported,
+    /// The call was rejected with `EPERM`, e.g. by a seccomp filter or a container runtime.
+    PermissionDenied,
+    /// The kernel ran out of memory or another resource for the registration.
+    ResourceLimit,
+    /// The process wasn't registered yet, and `Config::auto_register` is `false`.
+    Disabled,
+    /// The call failed with another `errno`.
+    Other(i32),
+}
+
+impl RegisterError {
+    /// Maps the `errno` of a failed `sys_membarrier()` call to the reason.
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    #[allow(dead_code)]
+    fn from_errno(errno: i32) -> RegisterError {
+        match errno {
+            libc::ENOSYS | libc::EINVAL => RegisterError::Unsupported,
+            libc::EPERM => RegisterError::PermissionDenied,
+            libc::ENOMEM | libc::EAGAIN => RegisterError::ResourceLimit,
+            errno => RegisterError::Other(errno),
+        }
     }
 }
 
-#[allow(dead_code)]
-mod default {
-    use core::sync::atomic::{fence, Ordering};
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegisterError::Unsupported => {
+                f.write_str("private expedited membarrier is not supported by the kernel")
+            }
+            RegisterError::PermissionDenied => f.write_str("membarrier is not permitted"),
+            RegisterError::ResourceLimit => {
+                f.write_str("the kernel is out of resources to register for membarrier")
+            }
+            RegisterError::Disabled => f.write_str("registering for membarrier is disabled"),
+            RegisterError::Other(errno) => write!(f, "membarrier failed with errno {}", errno),
+        }
+    }
+}
 
-    /// Issues a light memory barrier for fast path.
+/// A `sys_membarrier()` command the process can register for with `register_all()` on Linux and
+/// FreeBSD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Command {
+    /// `MEMBARRIER_CMD_PRIVATE_EXPEDITED`, which `heavy()` issues.
+    PrivateExpedited,
+    /// `MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE`, which also makes every thread serialize its
+    /// instruction stream, e.g. after code was modified, and which `sync_core()` issues.
+    PrivateExpeditedSyncCore,
+    /// `MEMBARRIER_CMD_GLOBAL_EXPEDITED`, which other processes issue to reach the threads of this
+    /// one.
+    GlobalExpedited,
+    /// `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ`, which can interrupt a single CPU, and which
+    /// `BarrierScope::heavy()` issues.
+    PrivateExpeditedRseq,
+}
+
+/// A coarse estimate of the cost of `heavy()`.
+///
+/// The variants are ordered from the cheapest to the most expensive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HeavyCost {
+    /// About as cheap as the normal memory barrier instruction.
+    Cheap,
+    /// A system call that interrupts a handful of CPUs or threads.
+    Moderate,
+    /// A system call that interrupts many CPUs or threads.
+    Expensive,
+}
+
+impl HeavyCost {
+    /// The number of CPUs or threads beyond which a process-wide barrier is deemed expensive.
+    const EXPENSIVE_REACH: usize = 32;
+
+    /// Estimates the cost of a process-wide barrier that has to reach `reach` CPUs or threads.
     ///
-    /// It just issues the normal memory barrier instruction.
-    #[inline]
-    pub fn light() {
-        fence(Ordering::SeqCst);
+    /// An unknown reach is conservatively deemed expensive.
+    #[allow(dead_code)]
+    fn of_reach(reach: Option<usize>) -> HeavyCost {
+        match reach {
+            Some(reach) if reach <= Self::EXPENSIVE_REACH => HeavyCost::Moderate,
+            _ => HeavyCost::Expensive,
+        }
+    }
+}
+
+/// What the current system offers for process-wide barriers.
+///
+/// It is returned by `capabilities()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    backend: Backend,
+    membarrier_commands: Option<u32>,
+    membarrier_registrations: Option<u32>,
+    register_error: Option<RegisterError>,
+    hypervisor: Option<Hypervisor>,
+    isolated_cpus: usize,
+    wine: bool,
+}
+
+impl Capabilities {
+    /// Creates capabilities that only report the mechanism `heavy()` uses.
+    #[allow(dead_code)]
+    fn new(backend: Backend) -> Capabilities {
+        Capabilities {
+            backend,
+            membarrier_commands: None,
+            membarrier_registrations: None,
+            register_error: None,
+            hypervisor: None,
+            isolated_cpus: 0,
+            wine: false,
+        }
     }
 
-    /// Issues a heavy memory barrier for slow path.
+    /// Returns the mechanism `heavy()` uses.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Returns the bitmask of `MEMBARRIER_CMD_*` commands supported by the Linux or FreeBSD
+    /// kernel.
     ///
-    /// It just issues the normal memory barrier instruction.
-    #[inline]
-    pub fn heavy() {
-        fence(Ordering::SeqCst);
+    /// Returns `None` on the other systems, or if the kernel doesn't support `sys_membarrier()` at
+    /// all.
+    pub fn membarrier_commands(&self) -> Option<u32> {
+        self.membarrier_commands
+    }
+
+    /// Returns the bitmask of `MEMBARRIER_CMD_REGISTER_*` commands the process was already
+    /// registered for when the strategy was selected, as reported by
+    /// `MEMBARRIER_CMD_GET_REGISTRATIONS`.
+    ///
+    /// If private expedited membarrier was already registered, e.g. by another library in the
+    /// same process, this crate doesn't register it again. Returns `None` if the kernel predates
+    /// Linux 6.3 or the system is not Linux.
+    pub fn membarrier_registrations(&self) -> Option<u32> {
+        self.membarrier_registrations
+    }
+
+    /// Returns why the process can't use private expedited `sys_membarrier()`, and thus falls back
+    /// to another mechanism.
+    ///
+    /// Returns `None` if it can, or if the system is not Linux.
+    pub fn register_error(&self) -> Option<RegisterError> {
+        self.register_error
+    }
+
+    /// Returns the hypervisor the process runs under, as advertised by the CPUID instruction.
+    ///
+    /// Some hypervisors virtualized IPIs, and thus the TLB shootdowns the `mprotect()`-based
+    /// barrier relies on, in slow or even incomplete ways, so this helps telling which barrier a
+    /// guest can trust. `sys_membarrier()` is preferred on every system anyway. Returns `None` on
+    /// bare metal, on systems other than Linux on x86 and x86-64, or if the hypervisor hides
+    /// itself.
+    pub fn hypervisor(&self) -> Option<Hypervisor> {
+        self.hypervisor
+    }
+
+    /// Returns the number of CPUs isolated from the scheduler with `isolcpus`, or running tickless
+    /// with `nohz_full`, as listed in `/sys/devices/system/cpu`.
+    ///
+    /// Returns 0 if there are none, or if the system is not Linux.
+    pub fn isolated_cpus(&self) -> usize {
+        self.isolated_cpus
+    }
+
+    /// Returns whether the process runs under Wine, which makes both barriers `SeqCst` fences.
+    ///
+    /// Wine emulates `FlushProcessWriteBuffers()` with whatever the host system offers, so the
+    /// crate doesn't rely on it. Returns `false` on systems other than Windows.
+    pub fn wine(&self) -> bool {
+        self.wine
+    }
+
+    /// Returns whether `heavy()` may interrupt isolated CPUs, which is slow and disturbs the
+    /// real-time tasks they were isolated for.
+    ///
+    /// `sys_membarrier()` and the `mprotect()`-based and signal-based barriers interrupt the CPUs
+    /// running threads of the process, so a thread pinned to an isolated CPU is interrupted by
+    /// every `heavy()`. The perf-event-based barrier interrupts every CPU. So keep the threads that
+    /// issue `light()` off the isolated CPUs, keep `heavy()` off latency-critical paths, or scope
+    /// the barrier to the threads that need it with `BarrierScope`, which spares the other CPUs on
+    /// Linux 5.10 and later.
+    pub fn heavy_disturbs_isolated_cpus(&self) -> bool {
+        self.isolated_cpus > 0 && self.backend != Backend::Fence
+    }
+
+    /// Returns a human-readable report of the capabilities, one per line, as formatted by
+    /// `Display`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate membarrier;
+    ///
+    /// let report = membarrier::capabilities().describe();
+    /// assert!(report.starts_with("backend: "));
+    /// ```
+    #[cfg(feature = "alloc")]
+    pub fn describe(&self) -> alloc::string::String {
+        use alloc::string::ToString;
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backend: {:?}", self.backend)?;
+        match self.membarrier_commands {
+            Some(commands) => writeln!(f, "membarrier commands: {:#x}", commands)?,
+            None => writeln!(f, "membarrier commands: unsupported")?,
+        }
+        match self.membarrier_registrations {
+            Some(registrations) => writeln!(f, "membarrier registrations: {:#x}", registrations)?,
+            None => writeln!(f, "membarrier registrations: unknown")?,
+        }
+        if let Some(error) = self.register_error {
+            writeln!(f, "register error: {}", error)?;
+        }
+        match self.hypervisor {
+            Some(hypervisor) => writeln!(f, "hypervisor: {:?}", hypervisor)?,
+            None => writeln!(f, "hypervisor: none")?,
+        }
+        if self.wine {
+            writeln!(f, "running under Wine")?;
+        }
+        write!(f, "isolated CPUs: {}", self.isolated_cpus)
     }
 }
 
-#[cfg(target_os = "linux")]
-mod linux {
-    use core::sync::atomic;
+/// The Cargo features of the crate, and whether each is enabled.
+const FEATURES: &[(&str, bool)] = &[
+    ("nightly", cfg!(feature = "nightly")),
+    ("force-fence", cfg!(feature = "force-fence")),
+    ("alloc", cfg!(feature = "alloc")),
+    ("std", cfg!(feature = "std")),
+    ("thread-tracking", cfg!(feature = "thread-tracking")),
+    ("coalesce-mprotect", cfg!(feature = "coalesce-mprotect")),
+    ("diagnostics", cfg!(feature = "diagnostics")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("capi", cfg!(feature = "capi")),
+    ("signal-barrier", cfg!(feature = "signal-barrier")),
+    ("perf-barrier", cfg!(feature = "perf-barrier")),
+    ("rseq-barrier", cfg!(feature = "rseq-barrier")),
+    ("memfd-mprotect", cfg!(feature = "memfd-mprotect")),
+    ("paranoid", cfg!(feature = "paranoid")),
+    ("ntdll-flush", cfg!(feature = "ntdll-flush")),
+    ("tsan", cfg!(feature = "tsan")),
+    ("defmt", cfg!(feature = "defmt")),
+    ("log", cfg!(feature = "log")),
+];
 
-    /// A choice between three strategies for process-wide barrier on Linux.
-    #[derive(Clone, Copy, PartialEq, Eq)]
-    enum Strategy {
-        /// Use the `membarrier` system call.
-        Membarrier,
-        /// Use the `mprotect`-based trick.
-        Mprotect,
-        /// Use `SeqCst` fences.
-        Fallback,
+/// How the crate was compiled, for bug reports and audits.
+///
+/// It is returned by `build_info()`, and its `Debug` output lists everything it knows.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo(());
+
+impl BuildInfo {
+    /// Returns the version of the crate.
+    pub fn version(&self) -> &'static str {
+        env!("CARGO_PKG_VERSION")
     }
 
-    lazy_static! {
-        /// The right strategy to use on the current machine.
-        static ref STRATEGY: Strategy = {
-            if membarrier::is_supported() {
-                Strategy::Membarrier
-            } else if mprotect::is_supported() {
-                Strategy::Mprotect
+    /// Returns the name of the backend module compiled in: `"linux"`, `"freebsd"`, `"windows"`,
+    /// `"apple"`, `"hurd"`, or `"default"`, which only has fences unless a feature or `set_heavy_impl()`
+    /// provides a heavy barrier.
+    ///
+    /// Unlike `backend()`, it doesn't depend on the system the binary runs on.
+    pub fn module(&self) -> &'static str {
+        cfg_if! {
+            if #[cfg(feature = "force-fence")] {
+                "default"
+            } else if #[cfg(target_os = "linux")] {
+                "linux"
+            } else if #[cfg(target_os = "freebsd")] {
+                "freebsd"
+            } else if #[cfg(target_os = "windows")] {
+                "windows"
+            } else if #[cfg(all(
+                any(target_os = "macos", target_os = "ios"),
+                any(target_arch = "aarch64", target_arch = "x86_64"),
+            ))] {
+                "apple"
+            } else if #[cfg(all(target_os = "hurd", any(target_arch = "x86", target_arch = "x86_64")))] {
+                "hurd"
             } else {
-                Strategy::Fallback
+                "default"
             }
-        };
+        }
     }
 
-    mod membarrier {
-        /// Commands for the membarrier system call.
-        ///
-        /// # Caveat
-        ///
-        /// We're defining it here because, unfortunately, the `libc` crate currently doesn't
-        /// expose `membarrier_cmd` for us. You can find the numbers in the [Linux source
-        /// code](https://github.com/torvalds/linux/blob/master/include/uapi/linux/membarrier.h).
-        ///
-        /// This enum should really be `#[repr(libc::c_int)]`, but Rust currently doesn't allow it.
-        #[repr(i32)]
-        #[allow(dead_code, non_camel_case_types)]
-        enum membarrier_cmd {
-            MEMBARRIER_CMD_QUERY = 0,
-            MEMBARRIER_CMD_GLOBAL = (1 << 0),
-            MEMBARRIER_CMD_GLOBAL_EXPEDITED = (1 << 1),
-            MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED = (1 << 2),
-            MEMBARRIER_CMD_PRIVATE_EXPEDITED = (1 << 3),
-            MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED = (1 << 4),
-            MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE = (1 << 5),
-            MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE = (1 << 6),
-        }
-
-        /// Call the `sys_membarrier` system call.
-        #[inline]
-        fn sys_membarrier(cmd: membarrier_cmd) -> libc::c_long {
-            unsafe { libc::syscall(libc::SYS_membarrier, cmd as libc::c_int, 0 as libc::c_int) }
-        }
-
-        /// Returns `true` if the `sys_membarrier` call is available.
-        pub fn is_supported() -> bool {
-            // Queries which membarrier commands are supported. Checks if private expedited
-            // membarrier is supported.
-            let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_QUERY);
-            if ret < 0
-                || ret & membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED as libc::c_long == 0
-                || ret & membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED as libc::c_long
-                    == 0
-            {
-                return false;
-            }
+    /// Returns the enabled Cargo features, including the optional dependencies.
+    pub fn features(&self) -> impl Iterator<Item = &'static str> {
+        FEATURES
+            .iter()
+            .filter(|&&(_, enabled)| enabled)
+            .map(|&(name, _)| name)
+    }
 
-            // Registers the current process as a user of private expedited membarrier.
-            if sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED) < 0 {
-                return false;
-            }
+    /// Returns whether the Cargo feature `name` is enabled.
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features().any(|feature| feature == name)
+    }
 
-            true
-        }
+    /// Returns whether `heavy()` was built as a mere fence with the unsound
+    /// `--cfg membarrier_unsound_noop_heavy`.
+    pub fn unsound_noop_heavy(&self) -> bool {
+        cfg!(membarrier_unsound_noop_heavy)
+    }
 
-        /// Executes a heavy `sys_membarrier`-based barrier.
-        #[inline]
-        pub fn barrier() {
-            fatal_assert!(sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED) >= 0);
+    /// Returns whether the Apple backend calls `thread_get_register_pointer_values`, as the build
+    /// script decides from the deployment target.
+    pub fn register_pointer_values(&self) -> bool {
+        cfg!(register_pointer_values)
+    }
+}
+
+impl fmt::Debug for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /// Lists the enabled features.
+        struct Features;
+
+        impl fmt::Debug for Features {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_list().entries(BuildInfo(()).features()).finish()
+            }
         }
+
+        f.debug_struct("BuildInfo")
+            .field("version", &self.version())
+            .field("module", &self.module())
+            .field("features", &Features)
+            .field("register_pointer_values", &self.register_pointer_values())
+            .field("unsound_noop_heavy", &self.unsound_noop_heavy())
+            .finish()
     }
+}
 
-    mod mprotect {
-        use core::{cell::UnsafeCell, mem::MaybeUninit, ptr, sync::atomic};
-        use libc;
+/// How the strategy for process-wide barriers is selected, set by `configure()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Whether `configure()` selects the strategy right away, like `init()`, rather than on the
+    /// first barrier. Defaults to `false`.
+    pub eager_init: bool,
+    /// Whether the process registers itself for private expedited membarrier on Linux and FreeBSD.
+    /// Otherwise, `sys_membarrier()` is only used if the process was already registered, e.g. by
+    /// another library, which only Linux can tell. Defaults to `true`.
+    pub auto_register: bool,
+    /// The mechanism to use if it is available on Linux, instead of the fastest available one. On
+    /// FreeBSD, only `Backend::Mprotect` can be preferred. Defaults to `None`.
+    pub prefer: Option<Backend>,
+    /// Whether the `mprotect()`-based barriers may be used on Linux and FreeBSD. Otherwise, the
+    /// fence fallback is used if `sys_membarrier()` is unavailable. Defaults to `true`.
+    pub allow_mprotect: bool,
+    /// Whether the signal-based barrier may be used on Linux, if it is faster than the
+    /// `mprotect()`-based ones as measured with `Config::benchmark`, or if they are unavailable. It
+    /// takes over the last realtime signal, unless a handler is already installed for it, and
+    /// `heavy()` never returns while a thread blocks that signal, e.g. a helper thread of another
+    /// library, so it must be allowed explicitly. Defaults to `false`.
+    pub allow_signals: bool,
+    /// Whether selecting the strategy on Linux measures which of the `mprotect()`-based barrier,
+    /// its `madvise()`-based variant, and the signal-based barrier, if allowed, is fastest, and
+    /// whether the `mprotect()`-based barrier measures if granting its page read-only access is
+    /// faster than read + write. Otherwise, no micro-benchmark runs at startup: the
+    /// `mprotect()`-based barrier is used, granting read + write, and the signal-based barrier
+    /// only where it is unavailable. Defaults to `false`.
+    pub benchmark: bool,
+    /// The NUMA node to place the dedicated pages of the `mprotect()`-based barriers on, on Linux.
+    /// Otherwise, they are placed on the node of the thread that creates them. The node is only
+    /// preferred, so a page is still placed elsewhere if the node is out of memory, and an unknown
+    /// node is ignored. The mutex serializing the barriers is a static of the process, so it is
+    /// not moved. Defaults to `None`.
+    pub mprotect_numa_node: Option<u32>,
+}
 
-        struct Barrier {
-            lock: UnsafeCell<libc::pthread_mutex_t>,
-            page: u64,
-            page_size: libc::size_t,
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            eager_init: false,
+            auto_register: true,
+            prefer: None,
+            allow_mprotect: true,
+            allow_signals: false,
+            benchmark: false,
+            mprotect_numa_node: None,
         }
+    }
+}
+
+/// The error returned by `configure()` when the configuration is already in effect, and by
+/// `set_metrics()` when a sink is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyInitialized;
+
+impl fmt::Display for AlreadyInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the process-wide barrier is already configured")
+    }
+}
 
-        unsafe impl Sync for Barrier {}
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod selection;
+#[cfg(not(fuzzing))]
+#[allow(dead_code)]
+mod selection;
 
-        impl Barrier {
-            /// Issues a process-wide barrier by changing access protections of a single mmap-ed
-            /// page. This method is not as fast as the `sys_membarrier()` call, but works very
-            /// similarly.
-            #[inline]
-            fn barrier(&self) {
-                let page = self.page as *mut libc::c_void;
+/// The configuration, which is frozen by `configure()` or by the first barrier.
+static CONFIG: spin_once::SpinOnce<Config> = spin_once::SpinOnce::new();
 
-                unsafe {
-                    // Lock the mutex.
-                    fatal_assert!(libc::pthread_mutex_lock(self.lock.get()) == 0);
+/// Returns the configuration, freezing it.
+#[allow(dead_code)]
+fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
 
-                    // Set the page access protections to read + write.
-                    fatal_assert!(
-                        libc::mprotect(page, self.page_size, libc::PROT_READ | libc::PROT_WRITE,)
-                            == 0
-                    );
+/// Configures how the strategy for process-wide barriers is selected.
+///
+/// It must be called at most once, before the first barrier or any other function that selects
+/// the strategy, such as `init()` or `backend()`. Otherwise, it returns `Err(AlreadyInitialized)`
+/// and the configuration in effect is left unchanged. Only Linux has a choice of strategies, so
+/// the other systems ignore everything but `eager_init`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::Config;
+///
+/// let config = Config {
+///     eager_init: true,
+///     ..Config::default()
+/// };
+/// membarrier::configure(config).unwrap();
+/// assert!(membarrier::configure(config).is_err()); // already in effect
+/// ```
+pub fn configure(config: Config) -> Result<(), AlreadyInitialized> {
+    let mut configured = false;
+    CONFIG.get_or_init(|| {
+        configured = true;
+        config
+    });
+    if !configured {
+        return Err(AlreadyInitialized);
+    }
 
-                    // Ensure that the page is dirty before we change the protection so that we
-                    // prevent the OS from skipping the global TLB flush.
-                    let atomic_usize = &*(page as *const atomic::AtomicUsize);
-                    atomic_usize.fetch_add(1, atomic::Ordering::SeqCst);
+    if config.eager_init {
+        init();
+    }
+    Ok(())
+}
 
-                    // Set the page access protections to none.
-                    //
-                    // Changing a page protection from read + write to none causes the OS to issue
-                    // an interrupt to flush TLBs on all processors. This also results in flushing
-                    // the processor buffers.
-                    fatal_assert!(libc::mprotect(page, self.page_size, libc::PROT_NONE) == 0);
+/// Why `try_init()` couldn't set up a process-wide barrier, leaving `heavy()` and `light()` as
+/// `SeqCst` fences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum InitError {
+    /// `sys_membarrier()` can't be used for the given reason on Linux, and neither can any other
+    /// process-wide barrier the configuration allows.
+    Membarrier(RegisterError),
+    /// The system offers no process-wide barrier at all.
+    Unsupported,
+}
 
-                    // Unlock the mutex.
-                    fatal_assert!(libc::pthread_mutex_unlock(self.lock.get()) == 0);
-                }
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InitError::Membarrier(error) => {
+                write!(f, "{}, and no other process-wide barrier is usable", error)
             }
+            InitError::Unsupported => f.write_str("the system offers no process-wide barrier"),
         }
+    }
+}
 
-        lazy_static! {
-            /// An alternative solution to `sys_membarrier` that works on older Linux kernels and
-            /// x86/x86-64 systems.
-            static ref BARRIER: Barrier = {
-                unsafe {
-                    // Find out the page size on the current system.
-                    let page_size = libc::sysconf(libc::_SC_PAGESIZE);
-                    fatal_assert!(page_size > 0);
-                    let page_size = page_size as libc::size_t;
-
-                    // Create a dummy page.
-                    let page = libc::mmap(
-                        ptr::null_mut(),
-                        page_size,
-                        libc::PROT_NONE,
-                        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                        -1 as libc::c_int,
-                        0 as libc::off_t,
-                    );
-                    fatal_assert!(page != libc::MAP_FAILED);
-                    fatal_assert!(page as libc::size_t % page_size == 0);
-
-                    // Locking the page ensures that it stays in memory during the two mprotect
-                    // calls in `Barrier::barrier()`. If the page was unmapped between those calls,
-                    // they would not have the expected effect of generating IPI.
-                    libc::mlock(page, page_size as libc::size_t);
-
-                    // Initialize the mutex.
-                    let lock = UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER);
-                    let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
-                    fatal_assert!(libc::pthread_mutexattr_init(attr.as_mut_ptr()) == 0);
-                    let mut attr = attr.assume_init();
-                    fatal_assert!(
-                        libc::pthread_mutexattr_settype(&mut attr, libc::PTHREAD_MUTEX_NORMAL) == 0
-                    );
-                    fatal_assert!(libc::pthread_mutex_init(lock.get(), &attr) == 0);
-                    fatal_assert!(libc::pthread_mutexattr_destroy(&mut attr) == 0);
-
-                    let page = page as u64;
-
-                    Barrier { lock, page, page_size }
-                }
-            };
-        }
+/// Selects the strategy for process-wide barriers eagerly, like `init()`, and returns the
+/// mechanism `heavy()` uses, or why it is only a `SeqCst` fence.
+///
+/// The barriers work either way, so this is for startup code that wants to log or abort
+/// deliberately when it is left without a process-wide barrier. Fences that were asked for, with
+/// `Config::prefer` or the `force-fence` feature, are not an error.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// match membarrier::try_init() {
+///     Ok(backend) => println!("using {:?}", backend),
+///     Err(error) => println!("`light()` is a full fence: {}", error),
+/// }
+/// ```
+pub fn try_init() -> Result<Backend, InitError> {
+    init();
+    let capabilities = capabilities();
+    let deliberate = cfg!(feature = "force-fence") || config().prefer == Some(Backend::Fence);
+    match capabilities.backend() {
+        Backend::Fence if !deliberate => Err(match capabilities.register_error() {
+            Some(error) => InitError::Membarrier(error),
+            None => InitError::Unsupported,
+        }),
+        backend => Ok(backend),
+    }
+}
 
-        /// Returns `true` if the `mprotect`-based trick is supported.
-        pub fn is_supported() -> bool {
-            cfg!(target_arch = "x86") || cfg!(target_arch = "x86_64")
-        }
+/// Returns how the crate was compiled: its version, the backend module and Cargo features compiled
+/// in, and the configuration the build script detected.
+///
+/// Everything is fixed at compile time, so this neither selects the strategy nor touches the
+/// system. Include its `Debug` output in bug reports along with that of `capabilities()`, which
+/// tells what the running system offers.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// let info = membarrier::build_info();
+/// assert_eq!(info.has_feature("force-fence"), cfg!(feature = "force-fence"));
+/// println!("{:?}", info);
+/// ```
+pub fn build_info() -> BuildInfo {
+    BuildInfo(())
+}
 
-        /// Executes a heavy `mprotect`-based barrier.
-        #[inline]
-        pub fn barrier() {
-            BARRIER.barrier();
-        }
+/// A memory mapping held by this crate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    address: usize,
+    len: usize,
+}
+
+impl Mapping {
+    /// Returns the start address of the mapping.
+    pub fn address(&self) -> usize {
+        self.address
     }
 
-    /// Issues a light memory barrier for fast path.
-    ///
-    /// It issues a compiler fence, which disallows compiler optimizations across itself. It incurs
-    /// basically no costs in run-time.
-    #[inline]
+    /// Returns the length of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the mapping is empty, which never happens.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The kernel resources this crate holds, returned by `fds()`.
+///
+/// It lets sandboxed deployments audit the resources they have to allow.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeldResources {
+    mappings: [Mapping; 2],
+    mapping_count: usize,
+    fds: &'static [i32],
+}
+
+impl HeldResources {
+    /// Records that the crate holds `mapping`.
     #[allow(dead_code)]
-    pub fn light() {
-        use self::Strategy::*;
-        match *STRATEGY {
-            Membarrier | Mprotect => atomic::compiler_fence(atomic::Ordering::SeqCst),
-            Fallback => atomic::fence(atomic::Ordering::SeqCst),
-        }
+    fn push_mapping(&mut self, mapping: Mapping) {
+        self.mappings[self.mapping_count] = mapping;
+        self.mapping_count += 1;
     }
 
-    /// Issues a heavy memory barrier for slow path.
-    ///
-    /// It issues a private expedited membarrier using the `sys_membarrier()` system call, if
-    /// supported; otherwise, it falls back to `mprotect()`-based process-wide memory barrier.
-    #[inline]
+    /// Returns the memory mappings the crate holds, namely the dedicated pages of the
+    /// `mprotect()`-based barriers on Linux and FreeBSD once they are created.
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings[..self.mapping_count]
+    }
+
+    /// Records that the crate holds `fds` for the rest of the process's lifetime.
     #[allow(dead_code)]
-    pub fn heavy() {
-        use self::Strategy::*;
-        match *STRATEGY {
-            Membarrier => membarrier::barrier(),
-            Mprotect => mprotect::barrier(),
-            Fallback => atomic::fence(atomic::Ordering::SeqCst),
-        }
+    fn set_fds(&mut self, fds: &'static [i32]) {
+        self.fds = fds;
+    }
+
+    /// Returns the file descriptors the crate holds, namely the perf events of the
+    /// perf-event-based barrier on Linux once they are opened, and the `memfd` backing the page of
+    /// the `mprotect()`-based barrier with the `memfd-mprotect` feature.
+    pub fn fds(&self) -> &[i32] {
+        self.fds
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "force-fence")] {
+        pub use default::*;
+        use default as sys;
+    } else if #[cfg(all(target_os = "linux"))] {
+        pub use linux::*;
+        use linux as sys;
+    } else if #[cfg(target_os = "freebsd")] {
+        pub use freebsd::*;
+        use freebsd as sys;
+    } else if #[cfg(target_os = "windows")] {
+        pub use windows::*;
+        use windows as sys;
+    } else if #[cfg(all(
+        any(target_os = "macos", target_os = "ios"),
+        any(target_arch = "aarch64", target_arch = "x86_64"),
+    ))] {
+        pub use apple::*;
+        use apple as sys;
+    } else if #[cfg(all(target_os = "hurd", any(target_arch = "x86", target_arch = "x86_64")))] {
+        pub use hurd::*;
+        use hurd as sys;
+    } else {
+        pub use default::*;
+        use default as sys;
     }
 }
 
-#[cfg(target_os = "windows")]
-mod windows {
-    use core::sync::atomic;
-    use windows_sys;
+/// Issues a `SeqCst` fence in place of a heavy memory barrier, to speed up test suites.
+///
+/// # Warning
+///
+/// This is unsound for real concurrency: `light()` stays as cheap as the selected strategy makes
+/// it, typically a compiler fence, so it no longer synchronizes with `heavy()`. It is meant for
+/// downstream tests that run millions of iterations whose correctness doesn't depend on the
+/// barriers, and is only available when building with `--cfg membarrier_unsound_noop_heavy`,
+/// which must never be set for a release build. Unlike the `force-fence` feature, which keeps the
+/// barriers sound by making `light()` a `SeqCst` fence as well, it keeps `light()` cheap, too.
+///
+/// `barrier_generation()` still advances and the `metrics` sink still sees the barrier, so the
+/// logic built on them can be tested. The other heavy barriers, e.g. `try_heavy_timeout()`, are
+/// unchanged.
+#[cfg(membarrier_unsound_noop_heavy)]
+#[inline]
+pub fn heavy() {
+    #[cfg(feature = "metrics")]
+    let started = metrics::heavy_started();
+    let generation = generation::begin();
+    core::sync::atomic::fence(Ordering::SeqCst);
+    generation::end(generation);
+    #[cfg(feature = "metrics")]
+    metrics::heavy_finished(started);
+}
+
+/// The error returned when a barrier could not be issued within a timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
 
-    /// Issues light memory barrier for fast path.
-    ///
-    /// It issues compiler fence, which disallows compiler optimizations across itself.
-    #[inline]
-    pub fn light() {
-        atomic::compiler_fence(atomic::Ordering::SeqCst);
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("timed out waiting to issue a process-wide barrier")
     }
+}
 
-    /// Issues heavy memory barrier for slow path.
-    ///
-    /// It invokes the `FlushProcessWriteBuffers()` system call.
-    #[inline]
-    pub fn heavy() {
-        unsafe {
-            windows_sys::Win32::System::Threading::FlushProcessWriteBuffers();
-        }
+/// The error returned by `refresh_topology()` when the heavy barrier can't reach the CPUs that
+/// were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UncoveredCpus;
+
+impl fmt::Display for UncoveredCpus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the process-wide barrier can't reach the added CPUs")
     }
 }
 
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-mod apple {
-    use core::sync::atomic;
+/// A system call behind a heavy barrier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Syscall {
+    /// `sys_membarrier()` on Linux, or `membarrier(2)` on FreeBSD.
+    Membarrier,
+    /// `mprotect()`, with which the `mprotect()`-based barrier changes the protections of its page.
+    Mprotect,
+    /// `madvise()`, with which the `madvise(MADV_DONTNEED)`-based barrier discards its page.
+    Madvise,
+    /// Listing the threads or sending them the realtime signal of the signal-based barrier.
+    Signal,
+    /// `read()` of the perf events behind the perf-event-based barrier. It fails with `ENODEV`
+    /// once they can't cover CPUs that were added.
+    PerfEvent,
+    /// A Mach call of the barrier on macOS and iOS, which fails with a `kern_return_t` rather
+    /// than an `errno`.
+    Mach,
+}
 
-    mod barrier {
-        #![allow(non_camel_case_types)]
-        #![allow(unused)]
-        #![allow(non_snake_case)]
+/// The error returned by `try_heavy()` when a system call behind the heavy barrier failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BarrierError {
+    syscall: Syscall,
+    errno: i32,
+}
 
-        use core::mem;
-        use core::slice;
+impl BarrierError {
+    /// Creates the error of `syscall` failing with `errno`.
+    #[allow(dead_code)]
+    fn new(syscall: Syscall, errno: i32) -> BarrierError {
+        BarrierError { syscall, errno }
+    }
 
-        use libc::{
-            mach_task_self, task_threads, thread_act_t, uintptr_t, vm_address_t, vm_deallocate,
-            KERN_SUCCESS,
-        };
+    /// Returns the system call that failed.
+    pub fn syscall(&self) -> Syscall {
+        self.syscall
+    }
 
-        // Include Raw FFI for `is_supported` and `flush_process_write_buffers`.
-        include!(concat!(env!("OUT_DIR"), "/mach.rs"));
-
-        /// Equivalent to `x86_THREAD_STATE64_COUNT` and `ARM_THREAD_STATE64_COUNT`
-        /// macros in `<mach/thread_status.h>`
-        const fn thread_state64_count() -> u32 {
-            cfg_if! {
-                if #[cfg(target_arch = "x86_64")] {
-                    (mem::size_of::<x86_thread_state64_t>() / mem::size_of::<u32>()) as u32
-                } else if #[cfg(target_arch = "aarch64")] {
-                    (mem::size_of::<arm_thread_state64_t>() / mem::size_of::<u32>()) as u32
-                } else {
-                    // This path should not be reachable!
-                    // Because we check if the heavy barrier is supported
-                    // by `is_supported` function before using `flush_process_write_buffers`.
-                    unreachable!()
-                }
-            }
-        }
+    /// Returns the `errno` the system call failed with, or the `kern_return_t` of a Mach call.
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+}
 
-        /// Check if the heavy membarrier using an inter processor interrupt
-        /// mechanism is supported on the host environment.
-        ///
-        /// An inter processor interrupt mechanism on Apple environments
-        /// is implementable for only x64 and ARM64.
-        #[inline]
-        pub const fn is_supported() -> bool {
-            cfg_if! {
-                if #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))] {
-                    true
-                } else {
-                    false
-                }
-            }
-        }
+impl fmt::Display for BarrierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let syscall = match self.syscall {
+            Syscall::Membarrier => "sys_membarrier()",
+            Syscall::Mprotect => "mprotect()",
+            Syscall::Madvise => "madvise()",
+            Syscall::Signal => "signaling a thread",
+            Syscall::PerfEvent => "reading a perf event",
+            Syscall::Mach => return write!(f, "a Mach call failed with {}", self.errno),
+        };
+        write!(f, "{} failed with errno {}", syscall, self.errno)
+    }
+}
 
-        #[inline]
-        fn assert_success(ret: kern_return_t, err_msg: &'static str) {
-            if ret != KERN_SUCCESS as kern_return_t {
-                panic!("{}", err_msg);
-            }
-        }
+/// A pair of light and heavy memory barriers.
+///
+/// The trait is object safe, so that the barrier can be chosen at run time and passed around as a
+/// `&dyn Barrier`, e.g. to substitute a fence-only or counting barrier in plugins or tests.
+pub trait Barrier {
+    /// Issues a light memory barrier for fast path.
+    fn light(&self);
 
-        /// Issue a heavy memory barrier.
-        ///
-        /// It flushes write buffers of executing threads of the current process,
-        /// and is equivalent to `membarrier` on latest Linux and `FlushProcessWriteBuffers` on Windows.
-        #[inline]
-        pub unsafe fn flush_process_write_buffers() {
-            let mut thread_count: mach_msg_type_number_t = mem::zeroed();
-            let mut thread_acts: *mut thread_act_t = mem::zeroed();
-
-            assert_success(
-                task_threads(mach_task_self(), &mut thread_acts, &mut thread_count),
-                "Failed to fetch thread information!",
-            );
-
-            let thread_acts_arr = slice::from_raw_parts_mut(thread_acts, thread_count as usize);
-            let mut sp = mem::zeroed();
-            let mut register_values: [uintptr_t; 128] = mem::zeroed();
-
-            for act in thread_acts_arr {
-                cfg_if! {
-                    if #[cfg(register_pointer_values)] {
-                        let mut registers = 128;
-                        assert_success(
-                            thread_get_register_pointer_values(*act, &mut sp, &mut registers, register_values.as_mut_ptr()),
-                            "`thread_get_register_pointer_values` system call failed!"
-                        );
-                    } else if #[cfg(target_arch = "x86_64")] {
-                        let mut thread_state: x86_thread_state64_t = mem::zeroed();
-                        let mut count = thread_state64_count();
-                        assert_success(
-                            thread_get_state(*act, x86_THREAD_STATE64 as i32, (&mut thread_state) as *mut _ as _, &mut count),
-                            "`thread_get_state` system call for x86 failed!"
-                        );
-                    } else if #[cfg(target_arch = "aarch64")] {
-                        let mut thread_state: arm_thread_state64_t = mem::zeroed();
-                        let mut count = thread_state64_count();
-                        assert_success(
-                            thread_get_state(*act, ARM_THREAD_STATE64 as i32, (&mut thread_state) as *mut _ as _, &mut count),
-                            "`thread_get_state` system call for AARCH64 failed!"
-                        );
-                    } else {
-                        // This path should not be reachable!
-                        // Because we check if the heavy barrier is supported
-                        // by `is_supported` function before using `flush_process_write_buffers`.
-                        unreachable!()
-                    }
-                };
+    /// Issues a heavy memory barrier for slow path.
+    fn heavy(&self);
+}
 
-                assert_success(
-                    mach_port_deallocate(mach_task_self(), *act),
-                    "Failed to decrement the port right's reference count!",
-                );
-            }
+/// The process-wide memory barrier, i.e. `light()` and `heavy()`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessBarrier;
 
-            assert_success(
-                vm_deallocate(
-                    mach_task_self(),
-                    thread_acts as vm_address_t,
-                    thread_count as usize * mem::size_of::<thread_act_t>(),
-                ),
-                "Failed to deallocate the used thread list!",
-            );
-        }
+impl Barrier for ProcessBarrier {
+    #[inline]
+    fn light(&self) {
+        light();
     }
 
-    /// Issues a light memory barrier for fast path.
-    ///
-    /// It issues a compiler fence, which disallows compiler optimizations across itself. It incurs
-    /// basically no costs in run-time.
     #[inline]
-    pub fn light() {
-        if barrier::is_supported() {
-            atomic::compiler_fence(atomic::Ordering::SeqCst);
-        } else {
-            atomic::fence(atomic::Ordering::SeqCst);
-        }
+    fn heavy(&self) {
+        heavy();
+    }
+}
+
+/// Returns the process-wide memory barrier as a trait object.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::Barrier;
+///
+/// fn publish(barrier: &dyn Barrier) {
+///     barrier.heavy();
+/// }
+///
+/// publish(membarrier::process_barrier());
+/// ```
+pub fn process_barrier() -> &'static dyn Barrier {
+    static PROCESS_BARRIER: ProcessBarrier = ProcessBarrier;
+    &PROCESS_BARRIER
+}
+
+/// A light memory barrier for fast path whose strength is chosen once, at setup.
+///
+/// `light()` is as strong as a `SeqCst` fence, as seen by `heavy()`. A fast path that only reads
+/// shared data before the barrier, or only writes shared data after it, can do with an `Acquire`
+/// or a `Release` barrier, which gives the compiler more freedom. A data structure can pick the
+/// strength once and then issue the barrier with a cheap `issue()`, rather than choosing at every
+/// call site.
+///
+/// Where there is no process-wide barrier, i.e. `backend()` is `Backend::Fence`, it issues a
+/// fence of the chosen ordering instead of a compiler fence. With the signal-based barriers, it
+/// always issues `light()`, which may have to register the current thread.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::LightBarrier;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// struct Table {
+///     version: AtomicUsize,
+///     barrier: LightBarrier,
+/// }
+///
+/// let table = Table {
+///     version: AtomicUsize::new(0),
+///     barrier: LightBarrier::reader(),
+/// };
+/// let _ = table.version.load(Ordering::Relaxed);
+/// table.barrier.issue();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LightBarrier {
+    ordering: Ordering,
+    kind: LightKind,
+}
+
+/// How a `LightBarrier` is issued, depending on the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LightKind {
+    /// A compiler fence, as `heavy()` interrupts every thread.
+    Compiler,
+    /// A fence, as `heavy()` is a fence too.
+    Fence,
+    /// `light()`, as it registers the current thread for `heavy()` with some backends, or
+    /// annotates it with the `tsan` feature.
+    Light,
+}
+
+impl LightBarrier {
+    /// Creates a barrier of `ordering`, selecting the strategy for process-wide barriers if no
+    /// barrier has been issued yet.
+    fn new(ordering: Ordering) -> LightBarrier {
+        let kind = match backend() {
+            Backend::Fence => LightKind::Fence,
+            Backend::Signal => LightKind::Light,
+            // `light()` is annotated for ThreadSanitizer.
+            _ if cfg!(feature = "tsan") => LightKind::Light,
+            _ => LightKind::Compiler,
+        };
+        LightBarrier { ordering, kind }
     }
 
-    /// Issues heavy memory barrier for slow path.
+    /// Creates an `Acquire` barrier, for fast paths that only read shared data before it.
+    pub fn reader() -> LightBarrier {
+        LightBarrier::new(Ordering::Acquire)
+    }
+
+    /// Creates a `Release` barrier, for fast paths that only write shared data after it.
+    pub fn writer() -> LightBarrier {
+        LightBarrier::new(Ordering::Release)
+    }
+
+    /// Creates a `SeqCst` barrier, which is as strong as `light()`.
+    pub fn full() -> LightBarrier {
+        LightBarrier::new(Ordering::SeqCst)
+    }
+
+    /// Returns the ordering of the barrier.
     ///
-    /// It flushes write buffers of executing threads of the current process
-    /// by Inter Process Interrupt(IPI) mechanism.
+    /// # Examples
     ///
-    /// In the latest version of MacOS(at least 10.14) and iOS(at least 12),
-    /// it requests the threads pointer values to force the thread to emit a
-    /// memory barrier. In older versions, it falls back to the `thread_get_state`
-    /// -based method.
-    #[inline]
-    pub fn heavy() {
-        if barrier::is_supported() {
-            unsafe { barrier::flush_process_write_buffers() };
+    /// ```
+    /// extern crate membarrier;
+    /// use membarrier::LightBarrier;
+    /// use std::sync::atomic::Ordering;
+    ///
+    /// assert_eq!(LightBarrier::reader().ordering(), Ordering::Acquire);
+    /// ```
+    pub fn ordering(&self) -> Ordering {
+        self.ordering
+    }
+
+    /// Issues the barrier.
+    #[inline]
+    pub fn issue(&self) {
+        match self.kind {
+            LightKind::Compiler => core::sync::atomic::compiler_fence(self.ordering),
+            LightKind::Fence => core::sync::atomic::fence(self.ordering),
+            LightKind::Light => {
+                light();
+            }
+        }
+    }
+}
+
+/// The proof that the current thread issued a light barrier, returned by `light_guard()`.
+///
+/// APIs that need a light barrier at some point can take it, so that passing a `HeavyGuard` there
+/// by mistake doesn't compile. It is zero-sized, so it costs nothing at run time.
+#[derive(Debug)]
+#[must_use]
+pub struct LightGuard(());
+
+/// The proof that the current thread issued a heavy barrier, returned by `heavy_guard()`.
+///
+/// APIs that need a heavy barrier at some point can take it, so that passing a `LightGuard` there
+/// by mistake doesn't compile. It is zero-sized, so it costs nothing at run time.
+#[derive(Debug)]
+#[must_use]
+pub struct HeavyGuard(());
+
+/// Issues `light()` and returns the proof that it did.
+///
+/// The guard is only built once the barrier was issued, so that it can't be had without one.
+#[inline]
+pub fn light_guard() -> LightGuard {
+    light();
+    LightGuard(())
+}
+
+/// Issues `heavy()` and returns the proof that it did.
+///
+/// The guard is only built once the barrier was issued, so that it can't be had without one. Like
+/// `heavy()`, it aborts the process if the barrier fails.
+#[inline]
+pub fn heavy_guard() -> HeavyGuard {
+    heavy();
+    HeavyGuard(())
+}
+
+/// A slot in which a thread announces that it is in a critical section of an epoch, for the
+/// asymmetric pattern the barriers are made for.
+///
+/// On the fast path, the thread announces its epoch and issues `light()` before entering the
+/// critical section. On the slow path, another thread issues `heavy()` before reading the
+/// announcement. Each step takes the function that issues the barrier it needs and calls it
+/// itself, so that the barrier can be neither swapped nor issued too early:
+///
+/// ```compile_fail
+/// extern crate membarrier;
+/// use membarrier::EpochSlot;
+///
+/// let slot = EpochSlot::new();
+/// let _section = slot.announce(1).enter(membarrier::heavy_guard);
+/// ```
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::EpochSlot;
+///
+/// let slot = EpochSlot::new();
+/// {
+///     // The fast path.
+///     let _section = slot.announce(7).enter(membarrier::light_guard);
+///     // The slow path, usually on another thread.
+///     assert_eq!(slot.observe(membarrier::heavy_guard), Some(7));
+/// }
+/// assert_eq!(slot.observe(membarrier::heavy_guard), None);
+/// ```
+#[derive(Debug, Default)]
+pub struct EpochSlot {
+    /// The announced epoch, or 0 outside of critical sections.
+    epoch: AtomicUsize,
+}
+
+impl EpochSlot {
+    /// Creates a slot without an announcement.
+    pub const fn new() -> EpochSlot {
+        EpochSlot {
+            epoch: AtomicUsize::new(0),
+        }
+    }
+
+    /// Announces that the current thread is about to enter a critical section of `epoch`, which
+    /// must not be 0.
+    ///
+    /// The critical section only starts with `Announced::enter()`, which issues `light()`.
+    pub fn announce(&self, epoch: usize) -> Announced<'_> {
+        assert!(epoch != 0, "epoch 0 marks the slot as empty");
+        self.epoch.store(epoch, Ordering::Relaxed);
+        Announced { slot: self }
+    }
+
+    /// Issues a heavy barrier with `heavy`, normally `heavy_guard`, and returns the announced
+    /// epoch, or `None` if the slot's thread is not in a critical section.
+    ///
+    /// The heavy barrier makes sure that an announcement made before it is observed. It is issued
+    /// on every call, after the caller's earlier accesses, so that no guard of an older barrier
+    /// can stand in for it.
+    pub fn observe(&self, heavy: fn() -> HeavyGuard) -> Option<usize> {
+        let _heavy = heavy();
+        match self.epoch.load(Ordering::Acquire) {
+            0 => None,
+            epoch => Some(epoch),
+        }
+    }
+}
+
+/// An announcement made with `EpochSlot::announce()`, which has yet to be ordered before the
+/// critical section by `light()`. It is withdrawn when dropped.
+#[derive(Debug)]
+#[must_use = "the critical section only starts with `enter()`"]
+pub struct Announced<'a> {
+    slot: &'a EpochSlot,
+}
+
+impl<'a> Announced<'a> {
+    /// Issues a light barrier with `light`, normally `light_guard`, after the announcement and
+    /// enters the critical section.
+    pub fn enter(self, light: fn() -> LightGuard) -> CriticalSection<'a> {
+        let _light = light();
+        CriticalSection { _announced: self }
+    }
+}
+
+impl<'a> Drop for Announced<'a> {
+    fn drop(&mut self) {
+        self.slot.epoch.store(0, Ordering::Release);
+    }
+}
+
+/// A critical section entered with `Announced::enter()`, which clears the announcement when
+/// dropped.
+#[derive(Debug)]
+pub struct CriticalSection<'a> {
+    _announced: Announced<'a>,
+}
+
+/// Issues a heavy memory barrier for slow path that the issuing thread only needs for publishing
+/// its writes.
+///
+/// The process-wide barrier always fully synchronizes with the other threads' light barriers.
+/// Only when there is no process-wide barrier, i.e. `backend()` is `Backend::Fence`, is it
+/// weakened to a `Release` fence. Use it only if the algorithm needs no ordering of the issuing
+/// thread's later accesses, e.g. no store-buffering pattern; otherwise use `heavy()`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// let data = AtomicUsize::new(0);
+/// data.store(42, Ordering::Relaxed);
+/// membarrier::heavy_release(); // publishes the store to threads issuing `light()`
+/// ```
+#[inline]
+pub fn heavy_release() {
+    if backend() == Backend::Fence {
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+    } else {
+        heavy();
+    }
+}
+
+/// Issues a heavy memory barrier for slow path that the issuing thread only needs for observing
+/// the other threads' writes.
+///
+/// The process-wide barrier always fully synchronizes with the other threads' light barriers.
+/// Only when there is no process-wide barrier, i.e. `backend()` is `Backend::Fence`, is it
+/// weakened to an `Acquire` fence. Use it only if the algorithm needs no ordering of the issuing
+/// thread's earlier accesses, e.g. no store-buffering pattern; otherwise use `heavy()`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// let data = AtomicUsize::new(0);
+/// membarrier::heavy_acquire(); // observes the stores threads made before their `light()`
+/// let _ = data.load(Ordering::Relaxed);
+/// ```
+#[inline]
+pub fn heavy_acquire() {
+    if backend() == Backend::Fence {
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+    } else {
+        heavy();
+    }
+}
+
+/// Returns the barrier generation, which advances as `heavy()` barriers complete.
+///
+/// It lets a thread check that a heavy barrier happened since a point in time without issuing one:
+/// take a snapshot of the generation, do some work, and later compare the generation to the
+/// snapshot. A heavy barrier only advances it from the generation it read before it started, so
+/// concurrent barriers that read the same generation advance it by one together. Thus once the
+/// generation has advanced by at least two since the snapshot, a heavy barrier that started after
+/// the snapshot has completed: it synchronized with every `light()` issued before the snapshot, and
+/// the load that observed the generation happens after that barrier. An advance by one only means
+/// that a barrier completed, which may have started before the snapshot.
+///
+/// `heavy()` advances it, as do `try_heavy_timeout()` and `heavy_signal_safe()` when they issue a
+/// barrier. It is a `usize`, as wide as the atomic counter behind it, and wraps around after
+/// `usize::MAX` barriers, so compare it with `wrapping_sub()`. It is only available on targets with
+/// atomic compare-and-swap.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// let snapshot = membarrier::barrier_generation();
+/// // ... work that a heavy barrier must have completed after ...
+/// membarrier::heavy();
+/// membarrier::heavy();
+/// assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 2);
+/// ```
+#[cfg(target_has_atomic = "ptr")]
+pub fn barrier_generation() -> usize {
+    generation::GENERATION.load(Ordering::SeqCst)
+}
+
+/// Waits until `barrier_generation()` has reached `target`, without issuing a barrier itself.
+///
+/// It is the way to wait for the heavy barriers of other threads, e.g. for one that started after
+/// a snapshot of the generation, by waiting for the snapshot plus two. It polls the generation with
+/// `spin_loop()` for a while, and then, with the `std` feature, parks the thread until a heavy
+/// barrier advances the generation: with a futex on Linux, `WaitOnAddress()` on Windows, and short
+/// sleeps elsewhere. Without `std`, it keeps spinning.
+///
+/// A generation that wrapped around past `target` counts as having reached it, as long as it is
+/// less than half the range of the generation ahead. It never returns if no other thread issues
+/// heavy barriers.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::thread;
+///
+/// let snapshot = membarrier::barrier_generation();
+/// let barriers = thread::spawn(|| {
+///     membarrier::heavy();
+///     membarrier::heavy();
+/// });
+/// // Returns once a heavy barrier that started after the snapshot has completed.
+/// membarrier::wait_for_generation(snapshot.wrapping_add(2));
+/// barriers.join().unwrap();
+/// ```
+#[cfg(target_has_atomic = "ptr")]
+pub fn wait_for_generation(target: usize) {
+    generation::wait(target);
+}
+
+/// Maintains the generation returned by `barrier_generation()`.
+mod generation {
+    #[cfg(target_has_atomic = "ptr")]
+    use core::hint;
+    #[cfg(target_has_atomic = "ptr")]
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[cfg(target_has_atomic = "ptr")]
+    pub static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+    /// The number of times `wait()` polls the generation before it parks.
+    #[cfg(all(feature = "std", target_has_atomic = "ptr"))]
+    const SPINS: u32 = 1 << 10;
+
+    /// Returns the generation before a heavy barrier starts, to be passed to `end()` once it
+    /// completed.
+    #[inline]
+    pub fn begin() -> usize {
+        // Every heavy barrier starts here.
+        #[cfg(feature = "tsan")]
+        super::tsan::heavy();
+        cfg_if! {
+            if #[cfg(target_has_atomic = "ptr")] {
+                GENERATION.load(Ordering::SeqCst)
+            } else {
+                0
+            }
+        }
+    }
+
+    /// Advances the generation past `begun`, unless a concurrent barrier already did.
+    ///
+    /// It is async-signal-safe, as it never blocks.
+    #[inline]
+    pub fn end(begun: usize) {
+        #[cfg(target_has_atomic = "ptr")]
+        let advanced = GENERATION
+            .compare_exchange(
+                begun,
+                begun.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        #[cfg(all(feature = "std", target_has_atomic = "ptr"))]
+        {
+            if advanced {
+                park::wake();
+            }
+        }
+        #[cfg(all(not(feature = "std"), target_has_atomic = "ptr"))]
+        let _ = advanced;
+        #[cfg(not(target_has_atomic = "ptr"))]
+        let _ = begun;
+    }
+
+    /// Returns whether `generation` has reached `target`, possibly wrapping around past it.
+    #[cfg(target_has_atomic = "ptr")]
+    #[inline]
+    fn reached(generation: usize, target: usize) -> bool {
+        generation.wrapping_sub(target) as isize >= 0
+    }
+
+    /// Waits until the generation reaches `target`, spinning and then parking with `std`.
+    #[cfg(target_has_atomic = "ptr")]
+    pub fn wait(target: usize) {
+        #[cfg(feature = "std")]
+        let mut spins = 0;
+        loop {
+            let current = GENERATION.load(Ordering::SeqCst);
+            if reached(current, target) {
+                return;
+            }
+            #[cfg(feature = "std")]
+            {
+                if spins == SPINS {
+                    park::wait_while(current);
+                    continue;
+                }
+                spins += 1;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Parks the threads waiting for the generation to advance, and wakes them when it does.
+    #[cfg(all(feature = "std", target_has_atomic = "ptr"))]
+    mod park {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        use super::GENERATION;
+
+        /// The number of threads that are parked or about to park, so that a heavy barrier only
+        /// makes a system call to wake them if there may be any.
+        static WAITERS: AtomicUsize = AtomicUsize::new(0);
+
+        /// Blocks while the generation is `current`, though it may also return spuriously.
+        pub fn wait_while(current: usize) {
+            WAITERS.fetch_add(1, Ordering::SeqCst);
+            // Either `wake()` sees the waiter, or the waiter sees the advanced generation.
+            if GENERATION.load(Ordering::SeqCst) == current {
+                block(current);
+            }
+            WAITERS.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        /// Wakes the parked threads after the generation advanced. It is async-signal-safe.
+        #[inline]
+        pub fn wake() {
+            if WAITERS.load(Ordering::SeqCst) != 0 {
+                unblock();
+            }
+        }
+
+        cfg_if! {
+            if #[cfg(target_os = "linux")] {
+                use core::{mem, ptr};
+
+                /// Returns the low half of the generation, which a futex can wait on.
+                fn word() -> *const u32 {
+                    let low = if cfg!(target_endian = "big") {
+                        mem::size_of::<usize>() / mem::size_of::<u32>() - 1
+                    } else {
+                        0
+                    };
+                    (&GENERATION as *const AtomicUsize as *const u32).wrapping_add(low)
+                }
+
+                fn block(current: usize) {
+                    // Returns right away if the low half changed, and the caller polls again.
+                    unsafe {
+                        libc::syscall(
+                            libc::SYS_futex,
+                            word(),
+                            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                            current as u32,
+                            ptr::null::<libc::timespec>(),
+                        );
+                    }
+                }
+
+                fn unblock() {
+                    unsafe {
+                        libc::syscall(
+                            libc::SYS_futex,
+                            word(),
+                            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+                            libc::c_int::MAX,
+                        );
+                    }
+                }
+            } else if #[cfg(windows)] {
+                use core::ffi::c_void;
+                use core::mem;
+                use windows_sys::Win32::System::Threading::{
+                    WaitOnAddress, WakeByAddressAll, INFINITE,
+                };
+
+                fn block(current: usize) {
+                    unsafe {
+                        WaitOnAddress(
+                            &GENERATION as *const AtomicUsize as *const c_void,
+                            &current as *const usize as *const c_void,
+                            mem::size_of::<usize>(),
+                            INFINITE,
+                        );
+                    }
+                }
+
+                fn unblock() {
+                    unsafe { WakeByAddressAll(&GENERATION as *const AtomicUsize as *const c_void) };
+                }
+            } else {
+                use core::time::Duration;
+                use std::thread;
+
+                /// Sleeps briefly, as there is no portable way to wait on an address.
+                fn block(_: usize) {
+                    thread::sleep(Duration::from_micros(100));
+                }
+
+                fn unblock() {}
+            }
+        }
+    }
+
+    #[cfg(all(test, target_has_atomic = "ptr"))]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn overlapping_barriers_advance_once() {
+            // Other tests issue barriers concurrently, so retry until none interfered.
+            loop {
+                let (first, second) = (begin(), begin());
+                end(first);
+                end(second);
+                let advanced = GENERATION.load(Ordering::SeqCst).wrapping_sub(first);
+                if advanced == 1 {
+                    break;
+                }
+                assert!(advanced > 1);
+            }
+
+            let begun = begin();
+            end(begun);
+            assert_ne!(GENERATION.load(Ordering::SeqCst), begun);
+        }
+
+        #[test]
+        fn reached_wraps_around() {
+            assert!(reached(5, 5));
+            assert!(reached(6, 5));
+            assert!(!reached(4, 5));
+            assert!(reached(1, usize::MAX));
+            assert!(!reached(usize::MAX, 1));
+
+            wait(GENERATION.load(Ordering::SeqCst));
+        }
+    }
+}
+
+/// Issues the normal memory barrier on the current thread only.
+///
+/// It is a `SeqCst` fence on every system, i.e. the hardware barrier instruction, which makes the
+/// current thread's writes globally visible without interrupting the other threads. Unlike
+/// `light()`, it never degrades to a compiler fence, and unlike `heavy()`, it only synchronizes
+/// with the other threads' normal or heavy barriers.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+///
+/// let ready = AtomicBool::new(false);
+/// ready.store(true, Ordering::Relaxed);
+/// membarrier::flush_self(); // the store is visible before any later access of this thread
+/// ```
+#[inline]
+pub fn flush_self() {
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Issues a light memory barrier for fast path that also stops speculative execution.
+///
+/// On x86 and x86-64 with SSE2, it issues an `LFENCE` instruction after `light()`, so that no
+/// later instruction, in particular no load, executes even speculatively before the barrier. This
+/// is a middle option for Spectre-hardened code paths, much cheaper than a full `MFENCE`. On the
+/// other systems it is just `light()`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// let table = [1, 2, 3];
+/// let index = 1;
+/// if index < table.len() {
+///     membarrier::light_hardened(); // no load below runs speculatively out of bounds
+///     assert_eq!(table[index], 2);
+/// }
+/// ```
+#[inline]
+pub fn light_hardened() {
+    light();
+
+    cfg_if! {
+        if #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))] {
+            #[allow(unused_unsafe)]
+            unsafe { core::arch::x86_64::_mm_lfence() };
+        } else if #[cfg(all(target_arch = "x86", target_feature = "sse2"))] {
+            #[allow(unused_unsafe)]
+            unsafe { core::arch::x86::_mm_lfence() };
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    /// The number of `light_bounded()` calls of the current thread since it last issued `heavy()`.
+    static BOUNDED_LIGHTS: core::cell::Cell<u32> = const { core::cell::Cell::new(0) };
+}
+
+/// Issues a light memory barrier for fast path, escalating to `heavy()` every `max_staleness`
+/// calls on the current thread.
+///
+/// Pairing `light()` with the `heavy()` of a coordinator leaves the other threads' view of the fast
+/// path as stale as the coordinator is idle. With this instead, every thread issues a process-wide
+/// barrier on its own after at most `max_staleness` calls, which bounds the staleness without a
+/// coordinator. It counts the calls of each thread separately, and issues `heavy()` on every call
+/// if `max_staleness` is 0 or 1. The escalated calls are as slow as `heavy()`. It is only available
+/// with the `std` feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// let published = AtomicUsize::new(0);
+/// for i in 1..=100 {
+///     published.store(i, Ordering::Relaxed);
+///     membarrier::light_bounded(64); // a `heavy()` once every 64 calls
+/// }
+/// ```
+#[cfg(feature = "std")]
+#[inline]
+pub fn light_bounded(max_staleness: u32) {
+    let due = BOUNDED_LIGHTS.with(|lights| {
+        let count = lights.get() + 1;
+        let due = count >= max_staleness;
+        lights.set(if due { 0 } else { count });
+        due
+    });
+    if due {
+        // A heavy barrier orders the accesses of the current thread like a light one.
+        heavy();
+    } else {
+        light();
+    }
+}
+
+/// Issues a heavy memory barrier from C if it is async-signal-safe, e.g. from a signal handler
+/// that stops the world, and returns whether it did.
+///
+/// See `heavy_signal_safe()`. It is only available with the `capi` feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// // Declared in C as `bool membarrier_heavy_signal_safe(void);`.
+/// membarrier::init();
+/// if !membarrier::membarrier_heavy_signal_safe() {
+///     membarrier::heavy();
+/// }
+/// ```
+#[cfg(feature = "capi")]
+#[no_mangle]
+pub extern "C" fn membarrier_heavy_signal_safe() -> bool {
+    heavy_signal_safe()
+}
+
+/// Returns from C whether `membarrier_heavy_signal_safe()` issues barriers.
+///
+/// See `has_signal_safe_heavy()`. It is only available with the `capi` feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// // Declared in C as `bool membarrier_has_signal_safe_heavy(void);`.
+/// membarrier::init();
+/// assert_eq!(
+///     membarrier::membarrier_has_signal_safe_heavy(),
+///     membarrier::has_signal_safe_heavy()
+/// );
+/// ```
+#[cfg(feature = "capi")]
+#[no_mangle]
+pub extern "C" fn membarrier_has_signal_safe_heavy() -> bool {
+    has_signal_safe_heavy()
+}
+
+/// Runs `f` between two calls to `barrier`.
+#[inline]
+fn bracket<R, F: FnOnce() -> R>(barrier: fn(), f: F) -> R {
+    barrier();
+    let result = f();
+    barrier();
+    result
+}
+
+/// Runs `f` between two light memory barriers, and returns its result.
+///
+/// The barriers bracket the memory accesses of `f`, so that none of them is reordered before the
+/// first or after the second barrier, sparing the common mistake of forgetting the trailing one.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// let counter = AtomicUsize::new(0);
+/// let previous = membarrier::with_light(|| counter.fetch_add(1, Ordering::Relaxed));
+/// assert_eq!(previous, 0);
+/// ```
+#[inline]
+pub fn with_light<R, F: FnOnce() -> R>(f: F) -> R {
+    bracket(light, f)
+}
+
+/// Runs `f` between two heavy memory barriers, and returns its result.
+///
+/// The barriers bracket the memory accesses of `f`, so that none of them is reordered before the
+/// first or after the second barrier, sparing the common mistake of forgetting the trailing one.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// let counter = AtomicUsize::new(0);
+/// let previous = membarrier::with_heavy(|| counter.swap(1, Ordering::Relaxed));
+/// assert_eq!(previous, 0);
+/// ```
+#[inline]
+pub fn with_heavy<R, F: FnOnce() -> R>(f: F) -> R {
+    bracket(heavy, f)
+}
+
+/// Diagnostics about a heavy barrier, returned by `heavy_with_stats()`.
+#[cfg(feature = "diagnostics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeavyStats {
+    backend: Backend,
+    cpus: Option<usize>,
+}
+
+#[cfg(feature = "diagnostics")]
+impl HeavyStats {
+    /// Returns the mechanism the barrier used.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Returns the number of distinct CPUs the threads of the process occupied right after the
+    /// barrier, which estimates how many CPUs the barrier had to interrupt.
+    ///
+    /// It is sampled from `/proc/self/task` on Linux, and is `None` on the other systems or if
+    /// `/proc` is not available.
+    pub fn cpus(&self) -> Option<usize> {
+        self.cpus
+    }
+}
+
+/// What a heavy barrier reached, returned by `heavy_reporting()`.
+///
+/// Each backend reports what it knows about the barrier it just issued: the threads it interrupted
+/// one by one, or the CPUs it had to interrupt.
+#[cfg(feature = "diagnostics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierReport {
+    backend: Backend,
+    threads: Option<usize>,
+    cpus: Option<usize>,
+}
+
+#[cfg(feature = "diagnostics")]
+impl BarrierReport {
+    #[allow(dead_code)]
+    fn new(backend: Backend, threads: Option<usize>, cpus: Option<usize>) -> BarrierReport {
+        BarrierReport {
+            backend,
+            threads,
+            cpus,
+        }
+    }
+
+    /// Returns the mechanism the barrier used.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Returns the number of threads the barrier interrupted one by one.
+    ///
+    /// It is exact for the Mach thread-state barrier on macOS, iOS, and GNU/Hurd, which iterates
+    /// the threads of the process, and for the signal-based barrier, which signals them. It is
+    /// `None` for the other backends.
+    pub fn threads(&self) -> Option<usize> {
+        self.threads
+    }
+
+    /// Returns the number of CPUs the barrier had to interrupt.
+    ///
+    /// For the `mprotect()`-based barrier on Linux, it is the number of distinct CPUs the threads
+    /// of the process occupied right after the barrier, as sampled from `/proc/self/task`. For
+    /// `sys_membarrier()`, which tells nothing itself, it is estimated as the number of CPUs the
+    /// threads may run on according to `sched_getaffinity()`. For the perf-event-based barrier it
+    /// is the number of CPUs with a pinned event, for `FlushProcessWriteBuffers()` the number of
+    /// active processors. It is `None` for the other backends, or if it could not be determined.
+    pub fn cpus(&self) -> Option<usize> {
+        self.cpus
+    }
+}
+
+/// Issues a heavy memory barrier for slow path, and reports diagnostics about it.
+///
+/// Sampling the diagnostics takes much longer than the barrier itself, so it is meant for
+/// investigating barrier costs rather than for production use. It is only available with the
+/// `diagnostics` feature.
+#[cfg(feature = "diagnostics")]
+pub fn heavy_with_stats() -> HeavyStats {
+    heavy();
+
+    cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            let cpus = procfs::occupied_cpus();
+        } else {
+            let cpus = None;
+        }
+    }
+    HeavyStats {
+        backend: backend(),
+        cpus,
+    }
+}
+
+/// A sink for barrier events, set with `set_metrics()`, e.g. to feed them into the metrics
+/// pipeline of a service.
+///
+/// It is called on the thread issuing the barrier, right after it, so it must be cheap and must
+/// not issue barriers itself. It is only available with the `metrics` feature.
+#[cfg(feature = "metrics")]
+pub trait BarrierMetrics: Sync {
+    /// Called after `heavy()` with the mechanism it used and how long it took.
+    fn on_heavy(&self, backend: Backend, duration: core::time::Duration);
+
+    /// Called after `light()`.
+    fn on_light(&self);
+}
+
+/// Routes the barrier events of the process to `metrics`.
+///
+/// It can be set only once, so a sink set by a library can't be replaced behind its back. Later
+/// calls return `Err(AlreadyInitialized)`. Until it is set, the barriers only check that it isn't,
+/// and without the `metrics` feature, not even that.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::{Backend, BarrierMetrics};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::time::Duration;
+///
+/// struct Counters {
+///     heavy: AtomicUsize,
+/// }
+///
+/// impl BarrierMetrics for Counters {
+///     fn on_heavy(&self, _backend: Backend, _duration: Duration) {
+///         self.heavy.fetch_add(1, Ordering::Relaxed);
+///     }
+///
+///     fn on_light(&self) {}
+/// }
+///
+/// static COUNTERS: Counters = Counters {
+///     heavy: AtomicUsize::new(0),
+/// };
+///
+/// membarrier::set_metrics(&COUNTERS).unwrap();
+/// membarrier::heavy();
+/// assert_eq!(COUNTERS.heavy.load(Ordering::Relaxed), 1);
+/// ```
+#[cfg(feature = "metrics")]
+pub fn set_metrics(metrics: &'static dyn BarrierMetrics) -> Result<(), AlreadyInitialized> {
+    let mut set = false;
+    metrics::SINK.get_or_init(|| {
+        set = true;
+        metrics
+    });
+    if set {
+        Ok(())
+    } else {
+        Err(AlreadyInitialized)
+    }
+}
+
+/// Reports the barriers to the sink set with `set_metrics()`.
+#[cfg(feature = "metrics")]
+mod metrics {
+    use std::time::Instant;
+
+    use super::spin_once::SpinOnce;
+    use super::BarrierMetrics;
+
+    pub static SINK: SpinOnce<&'static dyn BarrierMetrics> = SpinOnce::new();
+
+    /// Reports a `light()`.
+    #[inline]
+    pub fn light() {
+        if let Some(sink) = SINK.get() {
+            sink.on_light();
+        }
+    }
+
+    /// Returns when a `heavy()` starts, if there is a sink to report it to.
+    #[inline]
+    pub fn heavy_started() -> Option<Instant> {
+        SINK.get().map(|_| Instant::now())
+    }
+
+    /// Reports a `heavy()` that started at `started`.
+    #[inline]
+    pub fn heavy_finished(started: Option<Instant>) {
+        if let (Some(sink), Some(started)) = (SINK.get(), started) {
+            sink.on_heavy(super::backend(), started.elapsed());
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        feature = "diagnostics",
+        all(feature = "std", not(feature = "force-fence"))
+    )
+))]
+mod procfs;
+
+/// A registered thread of a `BarrierScope`.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct ScopedThread {
+    id: std::thread::ThreadId,
+    /// The kernel's ID of the thread, which tells where it runs.
+    #[cfg(all(target_os = "linux", not(feature = "force-fence")))]
+    tid: libc::pid_t,
+}
+
+/// A set of threads, e.g. the workers of a thread pool, that a heavy barrier can be scoped to.
+///
+/// Each participating thread calls `register()`, and `heavy()` then only synchronizes with the
+/// `light()` of the registered threads, leaving the other threads of the process undisturbed. On
+/// Linux 5.10 and later, it interrupts only the CPUs the registered threads run on with the
+/// `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ` command. Everywhere else, and whenever that command
+/// isn't available, it issues the process-wide `heavy()` instead, which reaches the registered
+/// threads along with the others.
+///
+/// It is only available with the `std` feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::BarrierScope;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let scope = Arc::new(BarrierScope::new());
+/// let worker = {
+///     let scope = scope.clone();
+///     thread::spawn(move || {
+///         scope.register();
+///         membarrier::light();
+///         scope.unregister();
+///     })
+/// };
+/// scope.heavy(); // synchronizes with the `light()` of the worker, if it is registered
+/// worker.join().unwrap();
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct BarrierScope {
+    threads: std::sync::Mutex<std::vec::Vec<ScopedThread>>,
+}
+
+#[cfg(feature = "std")]
+impl BarrierScope {
+    /// Creates a scope without any thread.
+    pub fn new() -> BarrierScope {
+        BarrierScope::default()
+    }
+
+    /// Locks the registered threads. A panic while holding the lock never leaves them
+    /// inconsistent.
+    fn lock(&self) -> std::sync::MutexGuard<'_, std::vec::Vec<ScopedThread>> {
+        self.threads
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Registers the current thread, so that `heavy()` reaches it. Registering a thread again is
+    /// a no-op.
+    pub fn register(&self) {
+        let id = std::thread::current().id();
+        let mut threads = self.lock();
+        if threads.iter().all(|thread| thread.id != id) {
+            threads.push(ScopedThread {
+                id,
+                #[cfg(all(target_os = "linux", not(feature = "force-fence")))]
+                tid: unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t },
+            });
+        }
+    }
+
+    /// Unregisters the current thread, if it is registered.
+    ///
+    /// A thread that exits while registered is skipped by `heavy()`, but should unregister first,
+    /// as the system may reuse its ID for an unrelated thread that `heavy()` would then disturb.
+    pub fn unregister(&self) {
+        let id = std::thread::current().id();
+        self.lock().retain(|thread| thread.id != id);
+    }
+
+    /// Returns the number of registered threads.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns whether no thread is registered.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+}
+
+/// Issues the process-wide barrier, as there is no cheaper one reaching only some threads.
+#[cfg(all(
+    feature = "std",
+    not(all(target_os = "linux", not(feature = "force-fence")))
+))]
+impl BarrierScope {
+    /// Issues a heavy memory barrier that synchronizes with the `light()` of every registered
+    /// thread.
+    #[inline]
+    pub fn heavy(&self) {
+        heavy();
+    }
+}
+
+/// Selects the strategy for process-wide barriers eagerly, so that the first barrier doesn't pay
+/// for the selection.
+///
+/// On Linux, it registers the process for `sys_membarrier()`, or, on older kernels, checks the
+/// `mprotect()` trick and, with `Config::benchmark`, measures its variants and the signal-based
+/// barrier. With the `perf-barrier` feature, it may open the perf events of the perf-event-based
+/// barrier. With the `paranoid` feature, it also checks the selected strategy against a helper
+/// thread, and selects again without it if the check fails. With the `rseq-barrier` feature, it
+/// also registers the process for the command `heavy_rseq()` issues.
+///
+/// On FreeBSD, it probes `membarrier(2)` and registers the process for it, or sets up the page of
+/// the `mprotect()`-based barrier. On Windows, it resolves `NtFlushProcessWriteBuffers()` with the
+/// `ntdll-flush` feature, and on macOS and iOS, it checks the Mach thread-state barrier with the
+/// `paranoid` feature. With the `signal-barrier` feature, it registers the current thread for the
+/// signal-based barrier on the Unix systems other than Linux. Otherwise, it is a no-op.
+#[inline]
+pub fn init() {
+    sys::init()
+}
+
+/// Restores the barriers in the child of a `fork()`, which doesn't inherit all of their state.
+///
+/// Only forking servers need it, whose children go on issuing barriers without calling `exec()`.
+/// The pages of the `mprotect()`-based barriers of Linux and FreeBSD are no longer locked in memory
+/// in the child, and stay shared with the parent until either writes to them, so they are replaced
+/// by freshly mapped and locked ones, whose mutexes are not held by a thread of the parent either.
+/// The process is also registered again for the `sys_membarrier()` or `membarrier(2)` commands the
+/// crate registered it for, as FreeBSD doesn't carry the registrations over to the child, and Linux
+/// may not either. If that fails on Linux, the next `heavy()` switches to another strategy like it
+/// does when `sys_membarrier()` starts failing. The threads of the parent, which don't exist in the
+/// child, are unregistered from the signal-based barrier. Otherwise, the strategy stays the one the
+/// parent selected. On Windows, macOS, iOS, and the Hurd, it is a no-op.
+///
+/// # Safety
+///
+/// It must be called in the child before it starts any thread, e.g. from a child handler registered
+/// with `pthread_atfork()`, as no other thread may use the barriers meanwhile.
+///
+/// # Examples
+///
+/// ```
+/// extern crate libc;
+/// extern crate membarrier;
+///
+/// extern "C" fn child() {
+///     unsafe { membarrier::reinit_after_fork() };
+/// }
+///
+/// membarrier::init();
+/// #[cfg(unix)]
+/// unsafe {
+///     libc::pthread_atfork(None, None, Some(child))
+/// };
+/// ```
+#[inline]
+pub unsafe fn reinit_after_fork() {
+    sys::reinit_after_fork()
+}
+
+/// Issues a heavy memory barrier for slow path, returning the error of the system call behind it
+/// instead of aborting if it fails.
+///
+/// A failure is returned right away, without a barrier, so `light()` may not be relied on until one
+/// succeeds. On Linux, a `sys_membarrier()` call rejected with `EPERM` or `ENOSYS` still makes this
+/// and all future barriers use the `mprotect()`-based trick instead, like `heavy()`, and is only
+/// returned where the trick is not supported. The signal-based barrier is replaced the same way
+/// once the threads can't be signaled. On macOS and iOS, a failed Mach call is returned as
+/// `Syscall::Mach` with its `kern_return_t`. `FlushProcessWriteBuffers()` on Windows never fails,
+/// and neither does the barrier of the Hurd, which falls back to a fence when the threads can't be
+/// enumerated, so this always succeeds there, as it does where `heavy()` is a fence.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// if let Err(error) = membarrier::try_heavy() {
+///     eprintln!("no process-wide barrier: {}", error);
+/// }
+/// ```
+#[inline]
+pub fn try_heavy() -> Result<(), BarrierError> {
+    sys::try_heavy()
+}
+
+/// Issues a heavy memory barrier for slow path, unless it would have to wait for longer than
+/// `timeout`.
+///
+/// Only the `mprotect()`-based barriers of Linux and FreeBSD and the signal-based barrier may wait,
+/// namely for a mutex serializing them, which is held for the duration of a barrier issued by
+/// another thread, and for the signaled threads. If the barrier can't be completed within
+/// `timeout`, `Err(Timeout)` is returned. The other barriers never wait, so they always succeed.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use std::time::Duration;
+///
+/// match membarrier::try_heavy_timeout(Duration::from_secs(1)) {
+///     Ok(()) => {}                   // the barrier was issued
+///     Err(membarrier::Timeout) => {} // it would have waited for too long, so try again later
+/// }
+/// ```
+#[inline]
+pub fn try_heavy_timeout(timeout: core::time::Duration) -> Result<(), Timeout> {
+    sys::try_heavy_timeout(timeout)
+}
+
+/// Issues a heavy memory barrier for slow path, and reports what it reached.
+///
+/// On Linux, the `mprotect()`-based barriers report the CPUs the process occupies,
+/// `sys_membarrier()` the CPUs its threads may run on, its legacy shared command the online CPUs,
+/// and the perf-event-based barrier the CPUs it has events on. On FreeBSD, `membarrier(2)` and the
+/// `mprotect()`-based barrier report the online CPUs, which they may interrupt, and on Windows,
+/// `FlushProcessWriteBuffers()` reports the active processors. The signal-based barrier reports the
+/// threads it signaled, and the Mach thread-state barrier of macOS, iOS, and the Hurd the threads
+/// it interrupted one by one. Sampling the report may take much longer than the barrier itself, so
+/// it is meant for investigating barrier costs. It is only available with the `diagnostics`
+/// feature.
+#[cfg(feature = "diagnostics")]
+#[inline]
+pub fn heavy_reporting() -> BarrierReport {
+    sys::heavy_reporting()
+}
+
+/// Issues `heavy()` if it is async-signal-safe, i.e. callable from a signal handler, and returns
+/// whether it did.
+///
+/// Only the `sys_membarrier()`, `membarrier(2)`, perf-event-based, and fence strategies are, and
+/// `FlushProcessWriteBuffers()`, as they neither block nor allocate. The `mprotect()`-based and
+/// signal-based barriers lock a mutex that the interrupted thread may hold, the Mach thread-state
+/// barrier allocates the thread list, and a heavy barrier provided with `set_heavy_impl()` need not
+/// be safe to call from an interrupt handler. The strategy must have been selected beforehand, e.g.
+/// by `init()`, as selecting it isn't async-signal-safe either. If `sys_membarrier()` starts
+/// failing, switching to another strategy isn't async-signal-safe, so no barrier is issued.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// membarrier::init();
+/// if !membarrier::heavy_signal_safe() {
+///     membarrier::heavy(); // not in a signal handler here, so the regular barrier will do
+/// }
+/// ```
+#[inline]
+pub fn heavy_signal_safe() -> bool {
+    sys::heavy_signal_safe()
+}
+
+/// Returns whether `heavy_signal_safe()` issues barriers with the selected strategy.
+///
+/// On Linux and FreeBSD, it returns `false` until a strategy has been selected, e.g. by `init()`.
+#[inline]
+pub fn has_signal_safe_heavy() -> bool {
+    sys::has_signal_safe_heavy()
+}
+
+/// Returns whether `heavy()` issues a process-wide barrier rather than a `SeqCst` fence, without
+/// selecting the strategy.
+///
+/// On Linux and FreeBSD, once a strategy was selected, e.g. by `init()` or the first barrier, it
+/// reads it. Until then, it asks the kernel which `sys_membarrier()` or `membarrier(2)` commands it
+/// offers, without registering the process for any of them, and checks which other strategies the
+/// configuration allows and whether they are available, without installing the signal handler or
+/// keeping perf events open. So the answer may still turn out wrong if a seccomp filter denies the
+/// registration, or if a check of the `paranoid` feature rejects the strategy. It never freezes the
+/// configuration either, so `configure()` may be called after it.
+///
+/// The other systems need no registration, so it is only `false` where `heavy()` is a fence: under
+/// Wine, on macOS and iOS if the barrier failed the checks of the `paranoid` feature, and on the
+/// remaining systems unless the `signal-barrier` feature finds a free realtime signal or
+/// `set_heavy_impl()` provided a heavy barrier.
+#[inline]
+pub fn is_supported() -> bool {
+    sys::is_supported()
+}
+
+/// Returns the mechanism `heavy()` uses.
+///
+/// On Linux and FreeBSD, it selects the strategy if no barrier has been issued yet. On Windows, it
+/// is `NtFlushProcessWriteBuffers()` if the `ntdll-flush` feature resolved it,
+/// `FlushProcessWriteBuffers()` otherwise, and the fence under Wine. On macOS and iOS, it is the
+/// Mach thread-state barrier unless it failed the checks of the `paranoid` feature, and on the
+/// Hurd, it always is. Elsewhere, it is the fence, unless the `signal-barrier` feature found a free
+/// realtime signal on a Unix system, or `set_heavy_impl()` provided a heavy barrier on a bare-metal
+/// system.
+#[inline]
+pub fn backend() -> Backend {
+    sys::backend()
+}
+
+/// Estimates the cost of `heavy()`.
+///
+/// `sys_membarrier()`, `membarrier(2)`, and the `mprotect()`-based barriers interrupt every online
+/// CPU that runs a thread of the process, and `FlushProcessWriteBuffers()` every active processor
+/// that does, so their estimate is based on the number of online CPUs or active processors. The
+/// perf-event-based barrier interrupts every CPU, online or not, so it is based on their number.
+/// The signal-based and Mach thread-state barriers interrupt every thread, so theirs is based on
+/// the number of threads. The legacy shared `sys_membarrier()` command waits for a scheduler grace
+/// period, so it is always expensive, while a fence is always cheap. A heavy barrier provided with
+/// `set_heavy_impl()` is assumed to interrupt a handful of cores.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::HeavyCost;
+///
+/// // Batch more work per heavy barrier where it is expensive.
+/// let batch = match membarrier::expected_heavy_cost() {
+///     HeavyCost::Cheap => 1,
+///     HeavyCost::Moderate => 16,
+///     HeavyCost::Expensive => 256,
+/// };
+/// assert!(batch > 0);
+/// ```
+#[inline]
+pub fn expected_heavy_cost() -> HeavyCost {
+    sys::expected_heavy_cost()
+}
+
+/// Returns what the current system offers for process-wide barriers.
+///
+/// On Linux and FreeBSD, it selects the strategy if no barrier has been issued yet.
+#[inline]
+pub fn capabilities() -> Capabilities {
+    sys::capabilities()
+}
+
+/// Registers the process for all of `commands` at once.
+///
+/// This keeps the registrations the process needs in one place, e.g. during initialization, rather
+/// than in the lazy initializers of each barrier. It fails before registering for any of them if
+/// the kernel doesn't support one. Linux 6.3 and later skip those the process already is registered
+/// for. FreeBSD has no restartable sequences, so it always fails there for
+/// `Command::PrivateExpeditedRseq`. The other systems have no `sys_membarrier()`, so it fails with
+/// `RegisterError::Unsupported` there unless `commands` is empty.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+/// use membarrier::{Command, RegisterError};
+///
+/// let commands = [Command::PrivateExpedited, Command::PrivateExpeditedSyncCore];
+/// match membarrier::register_all(&commands) {
+///     Ok(()) => {}                          // both are registered
+///     Err(RegisterError::Unsupported) => {} // the kernel doesn't support one of them
+///     Err(error) => println!("{}", error),
+/// }
+/// ```
+pub fn register_all(commands: &[Command]) -> Result<(), RegisterError> {
+    cfg_if! {
+        if #[cfg(all(
+            any(target_os = "linux", target_os = "freebsd"),
+            not(feature = "force-fence")
+        ))] {
+            sys::register_all(commands)
+        } else {
+            if commands.is_empty() {
+                Ok(())
+            } else {
+                Err(RegisterError::Unsupported)
+            }
+        }
+    }
+}
+
+/// Returns the kernel resources the crate holds.
+///
+/// On Linux and FreeBSD, these are the dedicated pages of the `mprotect()`-based barriers that have
+/// been created, with the `memfd` backing one of them with the `memfd-mprotect` feature on Linux,
+/// and the perf events of the perf-event-based barrier if they have been opened. The crate holds
+/// none on the other systems.
+pub fn fds() -> HeldResources {
+    cfg_if! {
+        if #[cfg(all(
+            any(target_os = "linux", target_os = "freebsd"),
+            not(feature = "force-fence")
+        ))] {
+            sys::fds()
+        } else {
+            HeldResources::default()
+        }
+    }
+}
+
+/// Issues a heavy memory barrier for slow path that also restarts the rseq critical sections of the
+/// other threads.
+///
+/// On Linux, it issues `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ`, available since Linux 5.10, which
+/// interrupts the CPUs running a thread of the process like `heavy()`, and also makes every
+/// interrupted thread that is in a restartable sequence abort it to its abort handler. Per-CPU data
+/// structures built on rseq can thus be sure that no thread is still in a critical section that
+/// started before the call. The process is registered for the command by `init()` or the first
+/// call, unless `Config::auto_register` is unset. Where the command is unavailable, and on the
+/// other systems, which have no restartable sequences, it is just `heavy()`, which doesn't restart
+/// anything; `register_all()` with `Command::PrivateExpeditedRseq` tells which it is. It is only
+/// available with the `rseq-barrier` feature.
+#[cfg(feature = "rseq-barrier")]
+pub fn heavy_rseq() {
+    cfg_if! {
+        if #[cfg(all(target_os = "linux", not(feature = "force-fence")))] {
+            linux::heavy_rseq();
+        } else {
+            heavy();
+        }
+    }
+}
+
+/// Issues a heavy memory barrier for slow path that also makes every other thread serialize its
+/// instruction stream, for code that modifies instructions other threads may execute.
+///
+/// On Linux and FreeBSD, it issues `MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE`, available since
+/// Linux 4.16 on x86 and arm64 and since later releases on some other architectures, which
+/// interrupts the CPUs running a thread of the process like `heavy()`, and also makes each of them
+/// execute a core serializing instruction before it returns to user space. A JIT compiler that
+/// wrote instructions, and made them visible to instruction fetches where the architecture requires
+/// it, e.g. with `__builtin___clear_cache()` on arm64, can thus let the other threads branch into
+/// them once it returns. The process is registered for the command by the first call, unless
+/// `Config::auto_register` is unset, in which case `register_all()` with
+/// `Command::PrivateExpeditedSyncCore` must have registered it.
+///
+/// On macOS and iOS, it issues the barrier of `heavy()`: each thread that is interrupted to fetch
+/// its state returns to user space through an exception return, which is context synchronizing on
+/// arm64 and serializing on x86-64. On arm64, the instruction caches are not coherent with the data
+/// caches, so the caller must first invalidate them for the modified code with
+/// `sys_icache_invalidate()`, which reaches every core. The other systems offer no way to make the
+/// other threads serialize their instruction streams, so it only issues a `SeqCst` fence there.
+///
+/// # Errors
+///
+/// Returns `RegisterError::Unsupported` if the kernel doesn't offer the command, if the barrier of
+/// macOS and iOS fell back to a `SeqCst` fence because it failed the checks of the `paranoid`
+/// feature, and on the other systems. Otherwise, it returns the error of registering for or issuing
+/// the command, or of the Mach calls. No barrier is issued then, so the caller has to synchronize
+/// the other threads on its own, e.g. by making them issue a serializing instruction before they
+/// run the code.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// if membarrier::sync_core().is_err() {
+///     // The other threads must be synchronized some other way.
+/// }
+/// ```
+pub fn sync_core() -> Result<(), RegisterError> {
+    cfg_if! {
+        if #[cfg(all(
+            any(
+                target_os = "linux",
+                target_os = "freebsd",
+                all(
+                    any(target_os = "macos", target_os = "ios"),
+                    any(target_arch = "aarch64", target_arch = "x86_64"),
+                ),
+            ),
+            not(feature = "force-fence")
+        ))] {
+            sys::sync_core()
+        } else {
+            core::sync::atomic::fence(Ordering::SeqCst);
+            Err(RegisterError::Unsupported)
+        }
+    }
+}
+
+/// Returns whether the page of the `mprotect()`-based barrier is locked in memory, or `None` if
+/// `heavy()` doesn't use that barrier.
+///
+/// On Linux and FreeBSD, the page is locked with `mlock()`, so that it stays in memory between the
+/// two `mprotect()` calls of a barrier. Locking may fail, e.g. when it would exceed
+/// `RLIMIT_MEMLOCK`, in which case the page may be paged out in between, and the barrier may not
+/// interrupt the other processors as it should. The address and size of the page are reported by
+/// `fds()`.
+///
+/// It doesn't select the strategy, so it returns `None` until `init()` or the first barrier did.
+/// The `madvise()`-based barrier of Linux discards its page on every barrier instead, so it never
+/// locks it, and `None` is returned for it as well, as it is on the other systems, where `heavy()`
+/// never uses the `mprotect()`-based barrier.
+pub fn mprotect_page_locked() -> Option<bool> {
+    cfg_if! {
+        if #[cfg(all(
+            any(target_os = "linux", target_os = "freebsd"),
+            not(feature = "force-fence")
+        ))] {
+            sys::mprotect_page_locked()
+        } else {
+            None
+        }
+    }
+}
+
+/// Issues a heavy memory barrier for slow path, gently on the other CPUs where possible.
+///
+/// On Linux, instead of interrupting every CPU that runs a thread of the process with an IPI, like
+/// `heavy()` does, it issues the non-expedited `sys_membarrier()` command, which waits for every
+/// CPU to pass through a scheduler quiescent state on its own. That takes milliseconds rather than
+/// microseconds, but doesn't disturb the latency-sensitive workloads sharing the CPUs, so it suits
+/// background work such as deferred cleanup. Where the command isn't available, e.g. on Linux
+/// before 4.3 or with `nohz_full`, or the strategy needs no IPIs anyway, and on the other systems,
+/// which have no gentler process-wide barrier, it is just `heavy()`.
+pub fn heavy_gentle() {
+    cfg_if! {
+        if #[cfg(all(target_os = "linux", not(feature = "force-fence")))] {
+            linux::heavy_gentle();
+        } else {
+            heavy();
+        }
+    }
+}
+
+/// Checks again which CPUs the barriers have to reach, after CPUs were brought online or offline,
+/// e.g. when a virtual machine is resized.
+///
+/// On Linux, the `sys_membarrier()`, `mprotect()`-based, and signal-based barriers let the kernel
+/// reach whichever CPUs are online, so they need nothing. The perf-event-based barrier only reaches
+/// the CPUs it opened its events on, though. If CPUs were added since, this opens events on them,
+/// too, or, if it can't, switches to the legacy shared `sys_membarrier()` command for good. Call
+/// it after every known hotplug event, before the next `heavy()`. On the other systems, `heavy()`
+/// reaches every thread wherever it runs, so it is a no-op.
+///
+/// # Errors
+///
+/// Returns `UncoveredCpus` if neither works. `light()` may have relied on the events, so falling
+/// back to fences would be unsound, and every later `heavy()` aborts the process instead.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// membarrier::init();
+/// // ... a CPU is brought online ...
+/// if membarrier::refresh_topology().is_err() {
+///     // Stop before the next `heavy()`, which would abort.
+/// }
+/// ```
+pub fn refresh_topology() -> Result<(), UncoveredCpus> {
+    cfg_if! {
+        if #[cfg(all(target_os = "linux", not(feature = "force-fence")))] {
+            linux::refresh_topology()
         } else {
-            atomic::fence(atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use service::BarrierService;
+
+#[cfg(feature = "std")]
+mod service {
+    use std::io;
+    use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+    use std::thread::{self, JoinHandle};
+
+    /// The sequence numbers shared between a `BarrierService` and its helper thread.
+    #[derive(Debug, Default)]
+    struct State {
+        /// The sequence number of the latest requested barrier.
+        requested: u64,
+        /// The sequence number of the latest barrier the helper thread completed.
+        completed: u64,
+        /// Whether the helper thread should exit.
+        shutdown: bool,
+    }
+
+    #[derive(Debug, Default)]
+    struct Shared {
+        state: Mutex<State>,
+        /// Signaled when a barrier is requested or the service shuts down.
+        requested: Condvar,
+        /// Signaled when the helper thread completes a barrier.
+        completed: Condvar,
+    }
+
+    impl Shared {
+        fn lock(&self) -> MutexGuard<'_, State> {
+            // The lock is never held across `heavy()` or user code, so it can't be poisoned.
+            self.state.lock().unwrap()
+        }
+
+        fn run(&self) {
+            let mut state = self.lock();
+            loop {
+                while state.requested == state.completed && !state.shutdown {
+                    state = self.requested.wait(state).unwrap();
+                }
+                if state.shutdown {
+                    return;
+                }
+
+                // Every request up to `target` was made before this barrier starts, so a single
+                // `heavy()` serves all of them.
+                let target = state.requested;
+                drop(state);
+                super::heavy();
+                state = self.lock();
+                state.completed = target;
+                self.completed.notify_all();
+            }
+        }
+    }
+
+    /// A dedicated helper thread that issues `heavy()` on behalf of other threads.
+    ///
+    /// When many threads need a heavy barrier at about the same time, they can share one instead
+    /// of each issuing its own: `request_barrier()` returns a sequence number, and `wait()` blocks
+    /// until the helper thread has completed a `heavy()` that started after the request. All the
+    /// requests made while the helper thread is busy are served by its next barrier, amortizing
+    /// the system call cost across the waiters.
+    ///
+    /// Dropping the service stops and joins the helper thread. It is only available with the `std`
+    /// feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate membarrier;
+    /// use membarrier::BarrierService;
+    ///
+    /// let service = BarrierService::new().unwrap();
+    /// let seq = service.request_barrier();
+    /// service.wait(seq); // a heavy barrier was issued after the request
+    /// ```
+    #[derive(Debug)]
+    pub struct BarrierService {
+        shared: Arc<Shared>,
+        helper: Option<JoinHandle<()>>,
+    }
+
+    impl BarrierService {
+        /// Spawns the helper thread.
+        ///
+        /// Returns an error if the thread could not be spawned.
+        pub fn new() -> io::Result<BarrierService> {
+            let shared = Arc::new(Shared::default());
+            let helper = {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name("membarrier".into())
+                    .spawn(move || shared.run())?
+            };
+            Ok(BarrierService {
+                shared,
+                helper: Some(helper),
+            })
+        }
+
+        /// Requests a heavy barrier and returns its sequence number, without waiting for it.
+        pub fn request_barrier(&self) -> u64 {
+            let mut state = self.shared.lock();
+            state.requested += 1;
+            self.shared.requested.notify_one();
+            state.requested
+        }
+
+        /// Blocks until the barrier with sequence number `seq` is completed.
+        ///
+        /// Returns immediately if `seq` was already completed.
+        pub fn wait(&self, seq: u64) {
+            let mut state = self.shared.lock();
+            while state.completed < seq {
+                state = self.shared.completed.wait(state).unwrap();
+            }
+        }
+    }
+
+    impl Drop for BarrierService {
+        fn drop(&mut self) {
+            self.shared.lock().shutdown = true;
+            self.shared.requested.notify_one();
+            if let Some(helper) = self.helper.take() {
+                let _ = helper.join();
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::BarrierService;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+        use std::vec::Vec;
+
+        #[test]
+        fn sequence_numbers() {
+            let service = BarrierService::new().unwrap();
+            let first = service.request_barrier();
+            let second = service.request_barrier();
+            assert!(first < second);
+            service.wait(second);
+            // Completed barriers don't block.
+            service.wait(first);
+            service.wait(0);
+        }
+
+        #[test]
+        fn concurrent_waiters() {
+            const THREADS: usize = 8;
+            const ROUNDS: usize = 100;
+
+            let service = Arc::new(BarrierService::new().unwrap());
+            let done = Arc::new(AtomicUsize::new(0));
+            let handles = (0..THREADS)
+                .map(|_| {
+                    let service = service.clone();
+                    let done = done.clone();
+                    thread::spawn(move || {
+                        for _ in 0..ROUNDS {
+                            let seq = service.request_barrier();
+                            service.wait(seq);
+                            done.fetch_add(1, Ordering::Relaxed);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            assert_eq!(done.load(Ordering::Relaxed), THREADS * ROUNDS);
+        }
+    }
+}
+
+/// Makes `heavy()` a `SeqCst` fence from now on, for a coordinator that is the only thread ever
+/// issuing barriers.
+///
+/// Without another thread issuing `light()`, a fence orders everything a heavy barrier has to,
+/// for a fraction of its cost. `barrier_generation()` still advances and the `metrics` sink still
+/// sees the barriers. The other heavy barriers, e.g. `try_heavy()`, are unchanged.
+///
+/// # Safety
+///
+/// The current thread must be the only one that issues `light()` or `heavy()` from now on, and
+/// every barrier other threads issued before must have returned, for as long as the process runs.
+/// Otherwise, a `light()` on another thread no longer synchronizes with `heavy()`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate membarrier;
+///
+/// // Safety: the main thread is the only one that ever issues barriers.
+/// unsafe { membarrier::assume_single_caller() };
+/// membarrier::light();
+/// membarrier::heavy(); // just a fence
+/// ```
+pub unsafe fn assume_single_caller() {
+    single_caller::assume();
+}
+
+mod single_caller;
+
+/// Annotates the barriers for ThreadSanitizer, with the `tsan` feature.
+///
+/// ThreadSanitizer doesn't see the interrupts that make a heavy barrier synchronize with the light
+/// ones, so each barrier releases and acquires a dummy address of its own kind. A heavy barrier
+/// acquires everything the light barriers before it released, and the other way around, but two
+/// light barriers never synchronize, just like the barriers themselves.
+#[cfg(feature = "tsan")]
+mod tsan {
+    use core::ffi::c_void;
+    use core::sync::atomic::AtomicU8;
+
+    /// The address the light barriers release.
+    static LIGHTS: AtomicU8 = AtomicU8::new(0);
+
+    /// The address the heavy barriers release.
+    static HEAVIES: AtomicU8 = AtomicU8::new(0);
+
+    extern "C" {
+        fn __tsan_acquire(addr: *mut c_void);
+        fn __tsan_release(addr: *mut c_void);
+    }
+
+    /// Annotates a light barrier.
+    #[inline]
+    pub fn light() {
+        unsafe {
+            __tsan_release(&LIGHTS as *const AtomicU8 as *mut c_void);
+            __tsan_acquire(&HEAVIES as *const AtomicU8 as *mut c_void);
+        }
+    }
+
+    /// Annotates a heavy barrier.
+    #[inline]
+    pub fn heavy() {
+        unsafe {
+            __tsan_acquire(&LIGHTS as *const AtomicU8 as *mut c_void);
+            __tsan_release(&HEAVIES as *const AtomicU8 as *mut c_void);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use critical::{critical_region, heavy_deferred, CriticalGuard};
+
+#[cfg(feature = "std")]
+mod critical;
+
+#[cfg(feature = "thread-tracking")]
+pub use tracking::{spawn, tracked_thread_count};
+
+#[cfg(feature = "thread-tracking")]
+mod tracking {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::{self, JoinHandle};
+
+    /// The number of live threads spawned by `spawn()`, plus one for the main thread.
+    static THREADS: AtomicUsize = AtomicUsize::new(1);
+
+    /// Accounts for a thread spawned by `spawn()` until it is dropped.
+    struct Tracked;
+
+    impl Tracked {
+        fn new() -> Tracked {
+            THREADS.fetch_add(1, Ordering::SeqCst);
+            Tracked
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            THREADS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Spawns a thread like `std::thread::spawn()`, counting it in `tracked_thread_count()` for its
+    /// lifetime.
+    ///
+    /// The thread is counted as soon as `spawn()` returns, and stops being counted once `f`
+    /// returns or panics.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the OS fails to create a thread, just like `std::thread::spawn()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate membarrier;
+    ///
+    /// let handle = membarrier::spawn(|| membarrier::tracked_thread_count());
+    /// assert!(handle.join().unwrap() >= 2);
+    /// ```
+    pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // If spawning fails, the closure and thus the guard are dropped, uncounting the thread.
+        let tracked = Tracked::new();
+        thread::spawn(move || {
+            let _tracked = tracked;
+            f()
+        })
+    }
+
+    /// Returns the number of live threads spawned by `spawn()`, plus one for the main thread.
+    ///
+    /// The count is only accurate if every thread of the process is spawned by `spawn()`. Threads
+    /// spawned otherwise, e.g. by `std::thread::spawn()` or by a C library, are not counted, so in
+    /// general it is only a lower bound on the number of threads. It must never be used to skip a
+    /// barrier unless all threads are known to be tracked.
+    pub fn tracked_thread_count() -> usize {
+        THREADS.load(Ordering::SeqCst)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::mpsc;
+
+        #[test]
+        fn counts_live_threads() {
+            let (started_sender, started) = mpsc::channel();
+            let (finish, finish_receiver) = mpsc::channel::<()>();
+            let handle = spawn(move || {
+                started_sender.send(tracked_thread_count()).unwrap();
+                finish_receiver.recv().unwrap();
+            });
+
+            // Other tests may spawn tracked threads concurrently, so only lower bounds hold.
+            assert!(started.recv().unwrap() >= 2);
+            finish.send(()).unwrap();
+            handle.join().unwrap();
+
+            let panicked = spawn(|| panic!("uncounted anyway"));
+            assert!(panicked.join().is_err());
+            assert!(tracked_thread_count() >= 1);
         }
     }
 }
+
+#[cfg(all(
+    unix,
+    any(target_os = "linux", feature = "signal-barrier"),
+    not(feature = "force-fence")
+))]
+#[allow(dead_code)]
+mod signal;
+
+/// The self-test of the `paranoid` feature, which checks a heavy barrier against a helper thread
+/// before the barrier is trusted.
+#[cfg(all(feature = "paranoid", unix, not(feature = "force-fence")))]
+#[allow(dead_code)]
+mod paranoid {
+    use core::mem::MaybeUninit;
+    use core::ptr;
+    use core::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+
+    /// The number of rounds of the litmus test.
+    const ROUNDS: usize = 1000;
+
+    /// What the litmus test shares with the helper thread.
+    struct Shared {
+        /// The value the current thread writes before its heavy barrier.
+        value: AtomicUsize,
+        /// The flag the helper thread writes before its compiler fence.
+        flag: AtomicUsize,
+        /// The round the helper thread may start.
+        started: AtomicUsize,
+        /// The round the helper thread has finished.
+        finished: AtomicUsize,
+        /// Whether the helper thread saw `value` of the finished round.
+        seen: AtomicBool,
+    }
+
+    /// Waits until `round` is in `counter`, yielding so that this works on a single CPU, too.
+    fn wait_for(counter: &AtomicUsize, round: usize) {
+        while counter.load(Ordering::Acquire) != round {
+            unsafe { libc::sched_yield() };
+        }
+    }
+
+    extern "C" fn helper(shared: *mut libc::c_void) -> *mut libc::c_void {
+        let shared = unsafe { &*(shared as *const Shared) };
+        for round in 1..=ROUNDS {
+            wait_for(&shared.started, round);
+            shared.flag.store(round, Ordering::Relaxed);
+            atomic::compiler_fence(Ordering::SeqCst);
+            let seen = shared.value.load(Ordering::Relaxed) == round;
+            shared.seen.store(seen, Ordering::Relaxed);
+            shared.finished.store(round, Ordering::Release);
+        }
+        ptr::null_mut()
+    }
+
+    /// Runs a store-buffering litmus test between the current thread issuing `heavy` and a helper
+    /// thread issuing compiler fences: in every round, either the current thread observes the
+    /// helper's flag or the helper observes the current thread's value, but never neither.
+    ///
+    /// Returns `false` if they missed each other, if `heavy` fails, or if the helper thread can't
+    /// be started.
+    pub fn litmus(heavy: &dyn Fn() -> bool) -> bool {
+        let shared = Shared {
+            value: AtomicUsize::new(0),
+            flag: AtomicUsize::new(0),
+            started: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+            seen: AtomicBool::new(false),
+        };
+
+        let mut thread = MaybeUninit::<libc::pthread_t>::uninit();
+        let arg = &shared as *const Shared as *mut libc::c_void;
+        if unsafe { libc::pthread_create(thread.as_mut_ptr(), ptr::null(), helper, arg) } != 0 {
+            return false;
+        }
+
+        let mut passed = true;
+        for round in 1..=ROUNDS {
+            shared.started.store(round, Ordering::Release);
+            shared.value.store(round, Ordering::Relaxed);
+            // Keep the rounds in lockstep even after a failure, so that the helper thread exits.
+            passed &= heavy();
+            let observed = shared.flag.load(Ordering::Relaxed) == round;
+            wait_for(&shared.finished, round);
+            passed &= observed || shared.seen.load(Ordering::Relaxed);
+        }
+
+        unsafe { libc::pthread_join(thread.assume_init(), ptr::null_mut()) };
+        passed
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn heavy_passes() {
+            // The helper thread only issues compiler fences, which a fence can't synchronize with.
+            if super::super::backend() != super::super::Backend::Fence {
+                assert!(litmus(&|| {
+                    super::super::heavy();
+                    true
+                }));
+            }
+        }
+
+        #[test]
+        fn failing_barrier_fails() {
+            assert!(!litmus(&|| false));
+        }
+    }
+}
+
+#[allow(dead_code)]
+mod spin_once {
+    use core::cell::UnsafeCell;
+    #[cfg(not(unix))]
+    use core::hint;
+    use core::mem::{self, MaybeUninit};
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    const INCOMPLETE: u8 = 0;
+    const RUNNING: u8 = 1;
+    const COMPLETE: u8 = 2;
+
+    /// A cell that is initialized once, on first use, without `std`.
+    ///
+    /// Threads that use the cell while another thread initializes it spin until it is done, which
+    /// is fine for the short, one-time initializations of this crate. On Unix, they yield the CPU
+    /// while spinning, so that they don't starve the initializing thread if they share its CPU,
+    /// e.g. while it runs the self-test of the `paranoid` feature.
+    pub struct SpinOnce<T> {
+        state: AtomicU8,
+        value: UnsafeCell<MaybeUninit<T>>,
+    }
+
+    // The value is only written once, before `state` becomes `COMPLETE`, and only shared after.
+    unsafe impl<T: Send + Sync> Sync for SpinOnce<T> {}
+
+    /// Resets the cell if the initializer panics, so that another thread can retry.
+    struct Reset<'a>(&'a AtomicU8);
+
+    impl<'a> Drop for Reset<'a> {
+        fn drop(&mut self) {
+            self.0.store(INCOMPLETE, Ordering::Release);
+        }
+    }
+
+    impl<T> SpinOnce<T> {
+        /// Creates an uninitialized cell.
+        pub const fn new() -> SpinOnce<T> {
+            SpinOnce {
+                state: AtomicU8::new(INCOMPLETE),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }
+
+        /// Returns the value, or `None` if it is not initialized yet.
+        #[inline]
+        pub fn get(&self) -> Option<&T> {
+            if self.state.load(Ordering::Acquire) == COMPLETE {
+                Some(unsafe { &*(*self.value.get()).as_ptr() })
+            } else {
+                None
+            }
+        }
+
+        /// Returns the value, initializing it with `init` if no thread did yet.
+        #[inline]
+        pub fn get_or_init<F: FnOnce() -> T>(&self, init: F) -> &T {
+            if self.state.load(Ordering::Acquire) != COMPLETE {
+                self.init(init);
+            }
+            unsafe { &*(*self.value.get()).as_ptr() }
+        }
+
+        #[cold]
+        fn init<F: FnOnce() -> T>(&self, init: F) {
+            loop {
+                match self.state.compare_exchange_weak(
+                    INCOMPLETE,
+                    RUNNING,
+                    Ordering::Acquire,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => {
+                        let reset = Reset(&self.state);
+                        let value = init();
+                        unsafe { (*self.value.get()).as_mut_ptr().write(value) };
+                        mem::forget(reset);
+                        self.state.store(COMPLETE, Ordering::Release);
+                        return;
+                    }
+                    Err(COMPLETE) => return,
+                    Err(_) => relax(),
+                }
+            }
+        }
+    }
+
+    impl<T> SpinOnce<T> {
+        /// Drops the value, if any, so that the next use initializes the cell again.
+        ///
+        /// # Safety
+        ///
+        /// No reference to the value may be alive, and no other thread may use the cell, which is
+        /// the case in the child of a `fork()` before it starts a thread. A thread of the parent
+        /// that was initializing the cell doesn't exist in the child, so the cell is reset even
+        /// then, leaking whatever the thread had set up.
+        #[allow(dead_code)]
+        pub unsafe fn reset(&self) {
+            if self.state.load(Ordering::Acquire) == COMPLETE {
+                (*self.value.get()).as_mut_ptr().drop_in_place();
+            }
+            self.state.store(INCOMPLETE, Ordering::Release);
+        }
+    }
+
+    /// Waits a little for the initializing thread.
+    #[inline]
+    fn relax() {
+        #[cfg(unix)]
+        unsafe {
+            libc::sched_yield();
+        }
+        #[cfg(not(unix))]
+        hint::spin_loop();
+    }
+
+    impl<T> Drop for SpinOnce<T> {
+        fn drop(&mut self) {
+            if *self.state.get_mut() == COMPLETE {
+                unsafe { (*self.value.get()).as_mut_ptr().drop_in_place() };
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::panic;
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+        use std::vec::Vec;
+
+        #[test]
+        fn concurrent_first_use() {
+            const THREADS: usize = 16;
+
+            let once = Arc::new(SpinOnce::new());
+            let inits = Arc::new(AtomicUsize::new(0));
+            let start = Arc::new(Barrier::new(THREADS));
+            let handles = (0..THREADS)
+                .map(|i| {
+                    let once = once.clone();
+                    let inits = inits.clone();
+                    let start = start.clone();
+                    thread::spawn(move || {
+                        start.wait();
+                        *once.get_or_init(|| {
+                            inits.fetch_add(1, Ordering::SeqCst);
+                            i
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            let values = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>();
+
+            assert_eq!(inits.load(Ordering::SeqCst), 1);
+            assert!(values.iter().all(|&value| value == values[0]));
+        }
+
+        #[test]
+        fn drops_value() {
+            let value = Arc::new(());
+            let once = SpinOnce::new();
+            once.get_or_init(|| value.clone());
+            assert_eq!(Arc::strong_count(&value), 2);
+            drop(once);
+            assert_eq!(Arc::strong_count(&value), 1);
+        }
+
+        #[test]
+        fn reset_initializes_again() {
+            let value = Arc::new(());
+            let once = SpinOnce::new();
+            once.get_or_init(|| value.clone());
+            unsafe { once.reset() };
+            assert_eq!(Arc::strong_count(&value), 1);
+            assert!(once.get().is_none());
+            assert_eq!(Arc::strong_count(once.get_or_init(|| value.clone())), 2);
+        }
+
+        #[test]
+        fn retries_after_panic() {
+            let once = SpinOnce::new();
+            assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                once.get_or_init(|| -> usize { panic!("initializer failed") })
+            }))
+            .is_err());
+            assert_eq!(*once.get_or_init(|| 1), 1);
+        }
+    }
+}
+
+/// The file descriptors the crate holds for the rest of the process's lifetime, which `fds()`
+/// reports.
+///
+/// They are recorded without allocating, in a fixed number of slots that are filled in order, so
+/// that the filled ones can be shared as a slice.
+#[cfg(all(target_os = "linux", not(feature = "force-fence")))]
+mod held_fds {
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::{hint, slice};
+
+    /// Room for a perf event per CPU, and the `memfd` backing the page of an `mprotect()`-based
+    /// barrier.
+    const CAPACITY: usize = 1024 + 1;
+
+    struct Slots(UnsafeCell<[i32; CAPACITY]>);
+
+    // A slot is only written by the thread that reserved it, before it is filled, and only read
+    // after.
+    unsafe impl Sync for Slots {}
+
+    static FDS: Slots = Slots(UnsafeCell::new([-1; CAPACITY]));
+
+    /// The number of slots that have been reserved.
+    static RESERVED: AtomicUsize = AtomicUsize::new(0);
+
+    /// The number of slots that have been filled, which are the first ones.
+    static FILLED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Records that the crate holds `fds` from now on, and returns `true`, or `false` if there is
+    /// no room for them, in which case the caller must not hold them.
+    #[must_use]
+    pub fn push(fds: &[i32]) -> bool {
+        let mut start = RESERVED.load(Ordering::Relaxed);
+        loop {
+            if start + fds.len() > CAPACITY {
+                return false;
+            }
+            match RESERVED.compare_exchange_weak(
+                start,
+                start + fds.len(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => start = current,
+            }
+        }
+
+        unsafe {
+            let slots = FDS.0.get() as *mut i32;
+            for (i, &fd) in fds.iter().enumerate() {
+                slots.add(start + i).write(fd);
+            }
+        }
+
+        // Publish the slots in the order they were reserved, so that the filled ones stay a
+        // prefix. A concurrent caller with earlier slots is about to publish them.
+        while FILLED.load(Ordering::Acquire) != start {
+            hint::spin_loop();
+        }
+        FILLED.store(start + fds.len(), Ordering::Release);
+        true
+    }
+
+    /// Returns the file descriptors the crate holds.
+    pub fn get() -> &'static [i32] {
+        let len = FILLED.load(Ordering::Acquire);
+        unsafe { slice::from_raw_parts(FDS.0.get() as *const i32, len) }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn pushed_fds_are_held() {
+            assert!(push(&[]));
+            let before = get().len();
+            assert!(push(&[-2, -3]));
+            // Other tests may push concurrently, so only look for these.
+            let fds = get();
+            assert!(fds.len() >= before + 2);
+            assert!(fds.windows(2).any(|pair| pair == [-2, -3]));
+        }
+    }
+}
+
+#[allow(dead_code)]
+mod default;
+
+#[cfg(all(
+    any(
+        target_os = "linux",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "solaris",
+    ),
+    not(feature = "force-fence")
+))]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod posix;
+
+#[cfg(all(target_os = "linux", not(feature = "force-fence")))]
+mod linux;
+
+#[cfg(all(target_os = "freebsd", not(feature = "force-fence")))]
+mod freebsd;
+
+#[cfg(all(target_os = "windows", not(feature = "force-fence")))]
+mod windows;
+
+#[cfg(all(
+    any(target_os = "macos", target_os = "ios"),
+    any(target_arch = "aarch64", target_arch = "x86_64"),
+    not(feature = "force-fence")
+))]
+mod apple;
+
+#[cfg(all(
+    target_os = "hurd",
+    any(target_arch = "x86", target_arch = "x86_64"),
+    not(feature = "force-fence")
+))]
+mod hurd;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn register_error_from_errno() {
+        assert_eq!(
+            RegisterError::from_errno(libc::ENOSYS),
+            RegisterError::Unsupported
+        );
+        assert_eq!(
+            RegisterError::from_errno(libc::EPERM),
+            RegisterError::PermissionDenied
+        );
+        assert_eq!(
+            RegisterError::from_errno(libc::ENOMEM),
+            RegisterError::ResourceLimit
+        );
+        assert_eq!(
+            RegisterError::from_errno(libc::EBADF),
+            RegisterError::Other(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn barrier_error_display() {
+        use std::string::ToString;
+
+        let error = BarrierError::new(Syscall::Mprotect, 12);
+        assert_eq!(error.syscall(), Syscall::Mprotect);
+        assert_eq!(error.errno(), 12);
+        assert_eq!(error.to_string(), "mprotect() failed with errno 12");
+    }
+
+    #[test]
+    fn bracket_orders_closure() {
+        static EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+        // Appends a barrier (1) or the closure (2) to the decimal event log.
+        fn barrier() {
+            EVENTS.store(EVENTS.load(Ordering::SeqCst) * 10 + 1, Ordering::SeqCst);
+        }
+        let result = bracket(barrier, || {
+            EVENTS.store(EVENTS.load(Ordering::SeqCst) * 10 + 2, Ordering::SeqCst);
+            42
+        });
+
+        assert_eq!(result, 42);
+        assert_eq!(EVENTS.load(Ordering::SeqCst), 121);
+        assert_eq!(with_light(|| 1), 1);
+        assert_eq!(with_heavy(|| 2), 2);
+    }
+}
@@ -0,0 +1,1673 @@
+use core::sync::atomic;
+use core::time::Duration;
+
+use super::posix::mprotect;
+use super::selection::{self, Probe, Strategy};
+use super::signal::{self, Missed};
+use super::spin_once::SpinOnce;
+use super::{
+    Backend, BarrierError, Capabilities, Command, HeavyCost, HeldResources, RegisterError, Syscall,
+    Timeout, UncoveredCpus,
+};
+
+#[cfg(feature = "diagnostics")]
+use super::BarrierReport;
+#[cfg(feature = "std")]
+use super::BarrierScope;
+
+/// A `Strategy` that is resolved once and can be downgraded at run time, encoded in a single
+/// byte so that reading it is a plain load.
+struct AtomicStrategy(atomic::AtomicU8);
+
+/// The encoding of an `AtomicStrategy` that is not resolved yet.
+const UNRESOLVED: u8 = u8::MAX;
+
+impl AtomicStrategy {
+    const fn unresolved() -> AtomicStrategy {
+        AtomicStrategy(atomic::AtomicU8::new(UNRESOLVED))
+    }
+
+    #[cfg(test)]
+    fn new(strategy: Strategy) -> AtomicStrategy {
+        AtomicStrategy(atomic::AtomicU8::new(strategy as u8))
+    }
+
+    /// Returns the strategy, or `None` if it is not resolved yet.
+    ///
+    /// The load acquires, so that the state a strategy sets up before it is stored, e.g. the
+    /// perf events, is visible along with it.
+    #[inline]
+    fn load(&self) -> Option<Strategy> {
+        use self::Strategy::*;
+        match self.0.load(atomic::Ordering::Acquire) {
+            UNRESOLVED => None,
+            s if s == Membarrier as u8 => Some(Membarrier),
+            s if s == SharedMembarrier as u8 => Some(SharedMembarrier),
+            s if s == Mprotect as u8 => Some(Mprotect),
+            s if s == Madvise as u8 => Some(Madvise),
+            s if s == Signal as u8 => Some(Signal),
+            s if s == Perf as u8 => Some(Perf),
+            _ => Some(Fallback),
+        }
+    }
+
+    /// Stores the resolved strategy.
+    fn resolve(&self, strategy: Strategy) {
+        self.0.store(strategy as u8, atomic::Ordering::Release);
+    }
+
+    /// Replaces `from` with `to`, unless another thread already replaced `from`.
+    fn downgrade(&self, from: Strategy, to: Strategy) {
+        let _ = self.0.compare_exchange(
+            from as u8,
+            to as u8,
+            atomic::Ordering::AcqRel,
+            atomic::Ordering::Acquire,
+        );
+    }
+}
+
+/// The number of times `STRATEGY` has been resolved.
+///
+/// Resolution registers the process for membarrier as a side effect, so it must happen
+/// exactly once.
+#[cfg(test)]
+static DETECTIONS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// What the `sys_membarrier` call offers on the current machine.
+static MEMBARRIER: SpinOnce<membarrier::Detection> = SpinOnce::new();
+
+/// The right strategy to use on the current machine.
+///
+/// It is downgraded from `Strategy::Membarrier` or `Strategy::SharedMembarrier` if the
+/// `sys_membarrier` call starts failing after detection.
+static STRATEGY: AtomicStrategy = AtomicStrategy::unresolved();
+
+/// Makes sure that `STRATEGY` is resolved exactly once, however many threads race for it.
+static RESOLUTION: SpinOnce<()> = SpinOnce::new();
+
+/// Returns what the `sys_membarrier` call offers, probing it on first use.
+fn detection() -> &'static membarrier::Detection {
+    MEMBARRIER.get_or_init(|| membarrier::detect(super::config().auto_register))
+}
+
+/// Returns the strategy, selecting it on first use.
+#[inline]
+fn strategy() -> Strategy {
+    match STRATEGY.load() {
+        Some(strategy) => strategy,
+        None => resolve(),
+    }
+}
+
+/// Selects the strategy, or waits for the thread that is selecting it.
+#[cold]
+fn resolve() -> Strategy {
+    RESOLUTION.get_or_init(|| {
+        #[cfg(test)]
+        DETECTIONS.fetch_add(1, atomic::Ordering::SeqCst);
+
+        #[cfg(not(feature = "paranoid"))]
+        let strategy = selection::select(super::config(), &mut SystemProbe);
+        #[cfg(feature = "paranoid")]
+        let strategy = validated();
+        #[cfg(feature = "log")]
+        report(strategy);
+        STRATEGY.resolve(strategy);
+    });
+    // The strategy may have been downgraded since, but never unresolved.
+    match STRATEGY.load() {
+        Some(strategy) => strategy,
+        None => unreachable!(),
+    }
+}
+
+/// Reports once, via `log`, which strategy was selected, and why `sys_membarrier()` wasn't.
+#[cfg(feature = "log")]
+fn report(strategy: Strategy) {
+    let backend = backend_of(strategy);
+    match MEMBARRIER.get() {
+        _ if strategy == Strategy::Membarrier => log::info!("membarrier: using Membarrier"),
+        Some(&membarrier::Detection {
+            error: Some(error), ..
+        }) => log::info!("membarrier: {}; using {:?}", error, backend),
+        Some(_) => log::info!("membarrier: using {:?} instead of Membarrier", backend),
+        None => log::info!("membarrier: using {:?} as configured", backend),
+    }
+}
+
+/// Selects a strategy, checking it before trusting it and selecting again without it if the
+/// check fails.
+#[cfg(feature = "paranoid")]
+fn validated() -> Strategy {
+    let mut probe = ParanoidProbe { failed: 0 };
+    loop {
+        let strategy = selection::select(super::config(), &mut probe);
+        if check(strategy) {
+            return strategy;
+        }
+        probe.failed |= 1 << strategy as usize;
+    }
+}
+
+/// Checks that `strategy` works as intended on the current machine.
+#[cfg(feature = "paranoid")]
+fn check(strategy: Strategy) -> bool {
+    use super::paranoid::litmus;
+
+    let method = match strategy {
+        Strategy::Membarrier => {
+            return membarrier::registration_stuck() && litmus(&membarrier::barrier);
+        }
+        Strategy::SharedMembarrier => return litmus(&membarrier::shared_barrier),
+        Strategy::Mprotect => mprotect::Method::Protect,
+        Strategy::Madvise => mprotect::Method::Dontneed,
+        Strategy::Signal => return litmus(&|| signal::barrier(None).is_ok()),
+        Strategy::Perf => return litmus(&|| perf::barrier().is_ok()),
+        Strategy::Fallback => return true,
+    };
+    mprotect::round_trip(method)
+        && litmus(&|| {
+            mprotect::barrier(method);
+            true
+        })
+}
+
+/// Probes the current machine, ruling out the strategies that failed their check.
+///
+/// Either failing variant of the `mprotect`-based trick rules out both, as `select()` may
+/// pick either of them once the trick is usable.
+#[cfg(feature = "paranoid")]
+struct ParanoidProbe {
+    /// The bits of the strategies that failed, indexed by their discriminants.
+    failed: usize,
+}
+
+#[cfg(feature = "paranoid")]
+impl ParanoidProbe {
+    fn failed(&self, strategy: Strategy) -> bool {
+        self.failed & (1 << strategy as usize) != 0
+    }
+}
+
+#[cfg(feature = "paranoid")]
+impl Probe for ParanoidProbe {
+    fn membarrier_usable(&mut self) -> bool {
+        !self.failed(Strategy::Membarrier) && SystemProbe.membarrier_usable()
+    }
+
+    fn shared_membarrier_usable(&mut self) -> bool {
+        !self.failed(Strategy::SharedMembarrier) && SystemProbe.shared_membarrier_usable()
+    }
+
+    fn mprotect_usable(&mut self) -> bool {
+        !self.failed(Strategy::Mprotect)
+            && !self.failed(Strategy::Madvise)
+            && SystemProbe.mprotect_usable()
+    }
+
+    fn mprotect_fastest(&mut self) -> Strategy {
+        SystemProbe.mprotect_fastest()
+    }
+
+    fn signal_usable(&mut self) -> bool {
+        !self.failed(Strategy::Signal) && SystemProbe.signal_usable()
+    }
+
+    fn signal_faster(&mut self, than: Strategy) -> bool {
+        SystemProbe.signal_faster(than)
+    }
+
+    fn perf_usable(&mut self) -> bool {
+        !self.failed(Strategy::Perf) && SystemProbe.perf_usable()
+    }
+}
+
+/// Probes the current machine.
+struct SystemProbe;
+
+impl Probe for SystemProbe {
+    fn membarrier_usable(&mut self) -> bool {
+        detection().usable
+    }
+
+    fn shared_membarrier_usable(&mut self) -> bool {
+        detection().shared
+    }
+
+    fn mprotect_usable(&mut self) -> bool {
+        mprotect::is_supported() && mprotect::self_test() && mprotect::is_permitted()
+    }
+
+    fn mprotect_fastest(&mut self) -> Strategy {
+        match mprotect::fastest_method() {
+            mprotect::Method::Protect => Strategy::Mprotect,
+            mprotect::Method::Dontneed => Strategy::Madvise,
+        }
+    }
+
+    fn signal_usable(&mut self) -> bool {
+        signal::is_supported()
+    }
+
+    fn signal_faster(&mut self, than: Strategy) -> bool {
+        let method = if than == Strategy::Madvise {
+            mprotect::Method::Dontneed
+        } else {
+            mprotect::Method::Protect
+        };
+        signal::measure() < mprotect::measure(method)
+    }
+
+    fn perf_usable(&mut self) -> bool {
+        perf::is_supported()
+    }
+}
+
+mod membarrier {
+    use super::super::{Command, RegisterError};
+
+    /// Commands for the membarrier system call.
+    ///
+    /// # Caveat
+    ///
+    /// We're defining it here because, unfortunately, the `libc` crate currently doesn't
+    /// expose `membarrier_cmd` for us. You can find the numbers in the [Linux source
+    /// code](https://github.com/torvalds/linux/blob/master/include/uapi/linux/membarrier.h).
+    ///
+    /// This enum should really be `#[repr(libc::c_int)]`, but Rust currently doesn't allow it.
+    #[repr(i32)]
+    #[derive(Clone, Copy)]
+    #[allow(dead_code, non_camel_case_types)]
+    enum membarrier_cmd {
+        MEMBARRIER_CMD_QUERY = 0,
+        MEMBARRIER_CMD_GLOBAL = (1 << 0),
+        MEMBARRIER_CMD_GLOBAL_EXPEDITED = (1 << 1),
+        MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED = (1 << 2),
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED = (1 << 3),
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED = (1 << 4),
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE = (1 << 5),
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE = (1 << 6),
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ = (1 << 7),
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_RSEQ = (1 << 8),
+        MEMBARRIER_CMD_GET_REGISTRATIONS = (1 << 9),
+    }
+
+    /// Fails to compile unless each of the given commands has the value `libc` gives it.
+    macro_rules! assert_matches_libc {
+        ($($cmd:ident),*) => {
+            $(
+                const _: () =
+                    assert!(membarrier_cmd::$cmd as libc::c_int == libc::$cmd as libc::c_int);
+            )*
+        };
+    }
+
+    // `libc` doesn't know `MEMBARRIER_CMD_GET_REGISTRATIONS` yet, nor the rseq commands in
+    // older versions.
+    assert_matches_libc!(
+        MEMBARRIER_CMD_QUERY,
+        MEMBARRIER_CMD_GLOBAL,
+        MEMBARRIER_CMD_GLOBAL_EXPEDITED,
+        MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED,
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED,
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED,
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE,
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE
+    );
+
+    /// Makes `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ` only interrupt the CPU passed as
+    /// `cpu_id`.
+    #[cfg(feature = "std")]
+    const MEMBARRIER_CMD_FLAG_CPU: libc::c_uint = 1 << 0;
+
+    /// Call the `sys_membarrier` system call.
+    ///
+    /// The kernel declares it as `membarrier(int cmd, unsigned int flags, int cpu_id)`. The
+    /// arguments are passed with exactly those C types so that the variadic `syscall()` puts
+    /// them in the right registers on every ABI, including x32, whose system call numbers
+    /// `libc` already offsets by `__X32_SYSCALL_BIT`.
+    #[inline]
+    fn sys_membarrier(cmd: membarrier_cmd) -> libc::c_long {
+        sys_membarrier_with(cmd, 0, 0)
+    }
+
+    /// Call the `sys_membarrier` system call with `flags` and `cpu_id`.
+    #[inline]
+    fn sys_membarrier_with(
+        cmd: membarrier_cmd,
+        flags: libc::c_uint,
+        cpu_id: libc::c_int,
+    ) -> libc::c_long {
+        unsafe { libc::syscall(libc::SYS_membarrier, cmd as libc::c_int, flags, cpu_id) }
+    }
+
+    /// What `detect()` found out about the `sys_membarrier` call.
+    #[derive(Clone, Copy)]
+    pub struct Detection {
+        /// The commands supported by the kernel, or `None` if the call is unavailable.
+        pub commands: Option<u32>,
+        /// The commands the process was registered for before detection, or `None` if the
+        /// kernel can't tell.
+        pub registrations: Option<u32>,
+        /// Whether private expedited membarrier is supported and registered.
+        pub usable: bool,
+        /// Whether the legacy shared command, now called global, is supported.
+        pub shared: bool,
+        /// Why private expedited membarrier is not usable, if it isn't.
+        pub error: Option<RegisterError>,
+    }
+
+    /// Returns why the last `sys_membarrier` call failed.
+    fn last_error() -> RegisterError {
+        RegisterError::from_errno(unsafe { *libc::__errno_location() })
+    }
+
+    /// Probes the `sys_membarrier` call, registering the current process as a user of
+    /// private expedited membarrier if needed and `register` is `true`.
+    pub fn detect(register: bool) -> Detection {
+        let mut detection = Detection {
+            commands: None,
+            registrations: None,
+            usable: false,
+            shared: false,
+            error: Some(RegisterError::Unsupported),
+        };
+
+        // Queries which membarrier commands are supported. Checks if private expedited
+        // membarrier is supported.
+        let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_QUERY);
+        if ret < 0 {
+            detection.error = Some(last_error());
+            return detection;
+        }
+        let commands = ret as u32;
+        detection.commands = Some(commands);
+        // Supported since Linux 4.3, but left out of the mask when `nohz_full` is enabled.
+        detection.shared = commands & membarrier_cmd::MEMBARRIER_CMD_GLOBAL as u32 != 0;
+
+        let required = membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED as u32
+            | membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED as u32;
+        if commands & required != required {
+            return detection;
+        }
+
+        // Queries which membarrier commands the current process is already registered for,
+        // e.g. by another library. This is supported since Linux 6.3.
+        if commands & membarrier_cmd::MEMBARRIER_CMD_GET_REGISTRATIONS as u32 != 0 {
+            let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_GET_REGISTRATIONS);
+            if ret >= 0 {
+                detection.registrations = Some(ret as u32);
+            }
+        }
+        let registered = match detection.registrations {
+            Some(registrations) => {
+                registrations & membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED as u32
+                    != 0
+            }
+            None => false,
+        };
+
+        // Registers the current process as a user of private expedited membarrier, unless it
+        // already is.
+        detection.error = if registered {
+            None
+        } else if !register {
+            Some(RegisterError::Disabled)
+        } else if sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED) < 0 {
+            Some(last_error())
+        } else {
+            None
+        };
+        detection.usable = detection.error.is_none();
+        detection
+    }
+
+    /// Returns the command to issue for `command` and the one to register for it.
+    fn commands_of(command: Command) -> (membarrier_cmd, membarrier_cmd) {
+        match command {
+            Command::PrivateExpedited => (
+                membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED,
+                membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED,
+            ),
+            Command::PrivateExpeditedSyncCore => (
+                membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE,
+                membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE,
+            ),
+            Command::GlobalExpedited => (
+                membarrier_cmd::MEMBARRIER_CMD_GLOBAL_EXPEDITED,
+                membarrier_cmd::MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED,
+            ),
+            Command::PrivateExpeditedRseq => (
+                membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ,
+                membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_RSEQ,
+            ),
+        }
+    }
+
+    /// Registers the current process for all of `commands`, unless it already is.
+    pub fn register_all(commands: &[Command]) -> Result<(), RegisterError> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_QUERY);
+        if ret < 0 {
+            return Err(last_error());
+        }
+        let supported = ret as u32;
+        for &command in commands {
+            let (issue, register) = commands_of(command);
+            let required = issue as u32 | register as u32;
+            if supported & required != required {
+                return Err(RegisterError::Unsupported);
+            }
+        }
+
+        let mut registered = 0;
+        if supported & membarrier_cmd::MEMBARRIER_CMD_GET_REGISTRATIONS as u32 != 0 {
+            let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_GET_REGISTRATIONS);
+            if ret >= 0 {
+                registered = ret as u32;
+            }
+        }
+        for &command in commands {
+            let (_, register) = commands_of(command);
+            if registered & register as u32 != 0 {
+                continue;
+            }
+            if sys_membarrier(register) < 0 {
+                return Err(last_error());
+            }
+            registered |= register as u32;
+        }
+        Ok(())
+    }
+
+    /// Returns `false` if the kernel reports that the process isn't registered for private
+    /// expedited membarrier. Kernels before Linux 6.3 can't tell, so this returns `true` on
+    /// them.
+    #[cfg(feature = "paranoid")]
+    pub fn registration_stuck() -> bool {
+        let ret = sys_membarrier(membarrier_cmd::MEMBARRIER_CMD_GET_REGISTRATIONS);
+        ret < 0
+            || ret as u32 & membarrier_cmd::MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED as u32 != 0
+    }
+
+    /// Executes a heavy `sys_membarrier`-based barrier.
+    ///
+    /// Returns `false` if the call is rejected with `EPERM` or `ENOSYS`, e.g. by a seccomp
+    /// filter installed after detection. Aborts on any other failure.
+    #[inline]
+    pub fn barrier() -> bool {
+        issue(membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED)
+    }
+
+    /// Executes a heavy barrier with the legacy shared command, which waits for every CPU to
+    /// pass through a scheduler quiescent state.
+    ///
+    /// Returns `false` like `barrier()`.
+    #[inline]
+    pub fn shared_barrier() -> bool {
+        issue(membarrier_cmd::MEMBARRIER_CMD_GLOBAL)
+    }
+
+    /// Executes a heavy barrier with the private expedited command, or with the legacy shared
+    /// one if `shared`, and returns the `errno` of any failure.
+    #[inline]
+    pub fn try_barrier(shared: bool) -> Result<(), libc::c_int> {
+        let cmd = if shared {
+            membarrier_cmd::MEMBARRIER_CMD_GLOBAL
+        } else {
+            membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED
+        };
+        try_issue_with(cmd, 0, 0)
+    }
+
+    /// Executes a heavy barrier with `MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE`, which also
+    /// serializes the instruction stream of the interrupted threads, and returns the `errno`
+    /// of any failure. The process must be registered for the command.
+    #[inline]
+    pub fn try_sync_core() -> Result<(), libc::c_int> {
+        try_issue_with(
+            membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE,
+            0,
+            0,
+        )
+    }
+
+    /// Executes a heavy barrier with `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ`, which also
+    /// restarts the rseq critical sections the interrupted threads are in.
+    ///
+    /// Returns `false` like `barrier()`. The process must be registered for the command.
+    #[cfg(feature = "rseq-barrier")]
+    #[inline]
+    pub fn rseq_barrier() -> bool {
+        issue(membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ)
+    }
+
+    /// Executes a heavy barrier that only interrupts `cpu`, if a thread of the process runs
+    /// on it, with `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ`.
+    ///
+    /// Returns `false` like `barrier()`. The process must be registered for the command.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn barrier_on(cpu: usize) -> bool {
+        issue_with(
+            membarrier_cmd::MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ,
+            MEMBARRIER_CMD_FLAG_CPU,
+            cpu as libc::c_int,
+        )
+    }
+
+    /// Issues `cmd`, returning `false` if it is rejected with `EPERM` or `ENOSYS`.
+    #[inline]
+    fn issue(cmd: membarrier_cmd) -> bool {
+        issue_with(cmd, 0, 0)
+    }
+
+    /// Issues `cmd` with `flags` and `cpu_id` like `issue()`.
+    #[inline]
+    fn issue_with(cmd: membarrier_cmd, flags: libc::c_uint, cpu_id: libc::c_int) -> bool {
+        match try_issue_with(cmd, flags, cpu_id) {
+            Ok(()) => true,
+            Err(errno) => {
+                fatal_assert!(errno == libc::EPERM || errno == libc::ENOSYS);
+                false
+            }
+        }
+    }
+
+    /// Issues `cmd` with `flags` and `cpu_id`, returning the `errno` of any failure.
+    #[inline]
+    fn try_issue_with(
+        cmd: membarrier_cmd,
+        flags: libc::c_uint,
+        cpu_id: libc::c_int,
+    ) -> Result<(), libc::c_int> {
+        if sys_membarrier_with(cmd, flags, cpu_id) >= 0 {
+            Ok(())
+        } else {
+            Err(unsafe { *libc::__errno_location() })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn shared_command() {
+            if detect(false).shared {
+                assert!(shared_barrier());
+            }
+        }
+
+        #[cfg(feature = "std")]
+        #[test]
+        fn single_cpu_command() {
+            if register_all(&[Command::PrivateExpeditedRseq]).is_ok() {
+                let cpu = unsafe { libc::sched_getcpu() };
+                assert!(barrier_on(cpu as usize));
+                assert!(barrier_on(0));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+mod affinity {
+    use core::mem;
+
+    use super::signal::for_each_thread;
+
+    /// Returns the number of CPUs any thread of the process may run on, or `None` if the
+    /// threads can't be listed.
+    pub fn allowed_cpus() -> Option<usize> {
+        let mut allowed: libc::cpu_set_t = unsafe { mem::zeroed() };
+        let listed = for_each_thread(|tid| {
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            let size = mem::size_of::<libc::cpu_set_t>();
+            // The thread may have exited since it was listed.
+            if unsafe { libc::sched_getaffinity(tid, size, &mut set) } == 0 {
+                for cpu in 0..size * 8 {
+                    if unsafe { libc::CPU_ISSET(cpu, &set) } {
+                        unsafe { libc::CPU_SET(cpu, &mut allowed) };
+                    }
+                }
+            }
+        });
+        let count = unsafe { libc::CPU_COUNT(&allowed) };
+        if listed && count > 0 {
+            Some(count as usize)
+        } else {
+            None
+        }
+    }
+}
+
+mod hypervisor {
+    use super::super::Hypervisor;
+
+    /// Detects the hypervisor from the vendor signature of CPUID leaf `0x4000_0000`, which
+    /// hypervisors advertise by setting bit 31 of `ECX` in leaf 1.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn detect() -> Option<Hypervisor> {
+        // Every CPU supported by Rust's x86 targets has the CPUID instruction.
+        #[cfg(target_arch = "x86")]
+        use core::arch::x86::__cpuid;
+        #[cfg(target_arch = "x86_64")]
+        use core::arch::x86_64::__cpuid;
+
+        #[allow(unused_unsafe)]
+        let (features, vendor) = unsafe { (__cpuid(1), __cpuid(0x4000_0000)) };
+        if features.ecx & (1 << 31) == 0 {
+            return None;
+        }
+
+        let mut signature = [0u8; 12];
+        signature[0..4].copy_from_slice(&vendor.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&vendor.ecx.to_le_bytes());
+        signature[8..12].copy_from_slice(&vendor.edx.to_le_bytes());
+        Some(match &signature {
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            b"XenVMMXenVMM" => Hypervisor::Xen,
+            b"Microsoft Hv" => Hypervisor::HyperV,
+            b"VMwareVMware" => Hypervisor::Vmware,
+            _ => Hypervisor::Other,
+        })
+    }
+
+    /// Detects the hypervisor, which is only supported on x86 and x86-64.
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    pub fn detect() -> Option<Hypervisor> {
+        None
+    }
+}
+
+mod isolation {
+    /// The number of CPUs that are told apart. CPUs beyond them are ignored.
+    const MAX_CPUS: usize = 1024;
+
+    /// Adds the CPUs of `list`, in the kernel's format, e.g. `0-3,8`, to `cpus`. Anything
+    /// else, e.g. the `(null)` of an empty `nohz_full` list, adds nothing.
+    fn add_cpu_list(list: &[u8], cpus: &mut [u64; MAX_CPUS / 64]) {
+        let list = match core::str::from_utf8(list) {
+            Ok(list) => list.trim(),
+            Err(_) => return,
+        };
+        for range in list.split(',') {
+            let mut bounds = range.splitn(2, '-');
+            let first = match bounds.next().and_then(|first| first.parse::<usize>().ok()) {
+                Some(first) => first,
+                None => continue,
+            };
+            let last = match bounds.next() {
+                Some(last) => match last.parse::<usize>() {
+                    Ok(last) => last,
+                    Err(_) => continue,
+                },
+                None => first,
+            };
+            for cpu in first..=last.min(MAX_CPUS - 1) {
+                cpus[cpu / 64] |= 1 << (cpu % 64);
+            }
+        }
+    }
+
+    /// Adds the CPUs listed in the sysfs file at the null-terminated `path` to `cpus`.
+    fn add_cpu_file(path: &[u8], cpus: &mut [u64; MAX_CPUS / 64]) {
+        let mut buf = [0u8; 4096];
+        let len = unsafe {
+            let fd = libc::open(
+                path.as_ptr() as *const libc::c_char,
+                libc::O_RDONLY | libc::O_CLOEXEC,
+            );
+            if fd < 0 {
+                return;
+            }
+            let len = libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+            libc::close(fd);
+            len
+        };
+        if len > 0 {
+            add_cpu_list(&buf[..len as usize], cpus);
+        }
+    }
+
+    /// Returns the number of CPUs isolated from the scheduler with `isolcpus`, or running
+    /// tickless with `nohz_full`.
+    pub fn detect() -> usize {
+        let mut cpus = [0u64; MAX_CPUS / 64];
+        add_cpu_file(b"/sys/devices/system/cpu/isolated\0", &mut cpus);
+        add_cpu_file(b"/sys/devices/system/cpu/nohz_full\0", &mut cpus);
+        cpus.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn count(lists: &[&[u8]]) -> usize {
+            let mut cpus = [0u64; MAX_CPUS / 64];
+            for list in lists {
+                add_cpu_list(list, &mut cpus);
+            }
+            cpus.iter().map(|word| word.count_ones() as usize).sum()
+        }
+
+        #[test]
+        fn cpu_lists() {
+            assert_eq!(count(&[b"\n"]), 0);
+            assert_eq!(count(&[b"(null)\n"]), 0);
+            assert_eq!(count(&[b"3\n"]), 1);
+            assert_eq!(count(&[b"0-3,8,10-11\n"]), 7);
+            // The two lists usually overlap.
+            assert_eq!(count(&[b"2-5\n", b"4-7\n"]), 6);
+            assert_eq!(count(&[b"1023-4000\n"]), 1);
+        }
+    }
+}
+
+/// The perf-event-based barrier, the last resort before fences where neither
+/// `sys_membarrier()` nor the `mprotect()`-based trick is available.
+///
+/// Reading a perf event that is active on another CPU makes the kernel interrupt that CPU and
+/// wait for it to read the event, which issues a full barrier on it. With an event pinned to
+/// every CPU, reading them all interrupts every CPU that runs a thread of the process, like
+/// `sys_membarrier()` does, and the idle ones as well.
+///
+/// Opening an event for a CPU, rather than for a thread, takes `CAP_PERFMON` or a
+/// `perf_event_paranoid` setting of at most 0, so it is commonly denied in containers. A CPU
+/// that is offline when the events are opened can't be covered, so the barrier is only
+/// supported if every configured CPU is online then. It is only ever supported with the
+/// `perf-barrier` feature.
+mod perf {
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+    use core::{hint, mem, slice};
+    use libc;
+
+    use super::super::posix::errno;
+    use super::super::{BarrierError, Syscall};
+    use super::SpinOnce;
+
+    /// The first version of `struct perf_event_attr`, which every kernel accepts.
+    #[repr(C)]
+    struct Attr {
+        kind: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+    }
+
+    const PERF_TYPE_SOFTWARE: u32 = 1;
+    /// A software event that never counts anything, available since Linux 3.12.
+    const PERF_COUNT_SW_DUMMY: u64 = 9;
+    /// The `pinned`, `exclude_kernel`, and `exclude_hv` bits of `Attr::flags`.
+    const PINNED_USER_ONLY: u64 = 1 << 2 | 1 << 5 | 1 << 6;
+    const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+    /// The most CPUs the barrier supports.
+    const MAX_CPUS: usize = 1024;
+
+    /// The perf events, one per CPU. Events are only ever added, for CPUs added since, and an
+    /// event is only written before `len` covers it.
+    struct Events {
+        fds: UnsafeCell<[libc::c_int; MAX_CPUS]>,
+        len: AtomicUsize,
+    }
+
+    // The events that are shared are never written again.
+    unsafe impl Sync for Events {}
+
+    impl Events {
+        fn new() -> Events {
+            Events {
+                fds: UnsafeCell::new([-1; MAX_CPUS]),
+                len: AtomicUsize::new(0),
+            }
+        }
+
+        fn fds(&self) -> &[libc::c_int] {
+            let len = self.len.load(Ordering::Acquire);
+            unsafe { slice::from_raw_parts(self.fds.get() as *const libc::c_int, len) }
+        }
+
+        /// Reads every event, which interrupts every other CPU and waits for it to handle the
+        /// interrupt. Returns `false` if a read fails.
+        fn read_all(&self) -> bool {
+            read(self.fds())
+        }
+
+        fn close(&self) {
+            close(self.fds());
+        }
+
+        /// Opens an event on every CPU below `cpus` that has none yet, and adds them, unless
+        /// one of them can't be opened or read, or, if `hold`, there is no room left to hold
+        /// them across `fork()`. Only one thread may add events at a time.
+        fn add(&self, cpus: usize, hold: bool) -> bool {
+            let start = self.len.load(Ordering::Relaxed);
+            if cpus > MAX_CPUS {
+                return false;
+            }
+
+            let mut attr: Attr = unsafe { mem::zeroed() };
+            attr.kind = PERF_TYPE_SOFTWARE;
+            attr.size = mem::size_of::<Attr>() as u32;
+            attr.config = PERF_COUNT_SW_DUMMY;
+            attr.flags = PINNED_USER_ONLY;
+
+            let slots = self.fds.get() as *mut libc::c_int;
+            for cpu in start..cpus {
+                let fd = unsafe {
+                    libc::syscall(
+                        libc::SYS_perf_event_open,
+                        &attr as *const Attr,
+                        -1 as libc::pid_t,
+                        cpu as libc::c_int,
+                        -1 as libc::c_int,
+                        PERF_FLAG_FD_CLOEXEC,
+                    )
+                };
+                if fd < 0 {
+                    close(unsafe { slice::from_raw_parts(slots.add(start), cpu - start) });
+                    return false;
+                }
+                unsafe { slots.add(cpu).write(fd as libc::c_int) };
+            }
+
+            let added = unsafe { slice::from_raw_parts(slots.add(start), cpus - start) };
+            if !read(added) || hold && !super::super::held_fds::push(added) {
+                close(added);
+                return false;
+            }
+            self.len.store(cpus, Ordering::Release);
+            true
+        }
+    }
+
+    /// Reads every one of `fds`. Returns `false` if a read fails.
+    fn read(fds: &[libc::c_int]) -> bool {
+        fds.iter().all(|&fd| {
+            let mut count = 0u64;
+            let buf = &mut count as *mut u64 as *mut libc::c_void;
+            unsafe { libc::read(fd, buf, mem::size_of::<u64>()) == 8 }
+        })
+    }
+
+    fn close(fds: &[libc::c_int]) {
+        for &fd in fds {
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    /// The events, or `None` if they couldn't be opened on every CPU. They are never closed.
+    static EVENTS: SpinOnce<Option<Events>> = SpinOnce::new();
+
+    /// Whether a thread is adding events for CPUs that were added.
+    static ADDING: AtomicBool = AtomicBool::new(false);
+
+    /// Whether CPUs were added that the events can't cover, so the barrier no longer reaches
+    /// every CPU.
+    static UNCOVERED: AtomicBool = AtomicBool::new(false);
+
+    /// Returns the number of CPUs the system has, or `None` if it is unknown.
+    fn configured_cpus() -> Option<usize> {
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+        if cpus > 0 {
+            Some(cpus as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Opens an event on every CPU and holds them across `fork()`, unless one of them can't be
+    /// opened or read, or there is no room left to hold them.
+    fn open() -> Option<Events> {
+        open_events(true)
+    }
+
+    /// Opens an event on every CPU, unless one of them can't be opened or read.
+    fn try_open() -> Option<Events> {
+        open_events(false)
+    }
+
+    /// Opens an event on every CPU, unless one of them can't be opened or read, or, if `hold`,
+    /// there is no room left to hold them across `fork()`.
+    ///
+    /// Outside x86 and x86-64, nothing guarantees that the barrier orders the accesses of the
+    /// CPUs it interrupts, so it is never used there.
+    fn open_events(hold: bool) -> Option<Events> {
+        if !cfg!(all(
+            feature = "perf-barrier",
+            any(target_arch = "x86", target_arch = "x86_64")
+        )) {
+            return None;
+        }
+        let events = Events::new();
+        if events.add(configured_cpus()?, hold) {
+            Some(events)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the perf-event-based barrier is supported, opening its events.
+    pub fn is_supported() -> bool {
+        EVENTS.get_or_init(open).is_some()
+    }
+
+    /// Returns `true` if the perf-event-based barrier is supported, closing its events again
+    /// unless they are open already.
+    pub fn is_available() -> bool {
+        match EVENTS.get() {
+            Some(events) => events.is_some(),
+            None => match try_open() {
+                Some(events) => {
+                    events.close();
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Returns the file descriptors of the events, if they are open.
+    pub fn fds() -> &'static [libc::c_int] {
+        match EVENTS.get() {
+            Some(Some(events)) => events.fds(),
+            _ => &[],
+        }
+    }
+
+    /// Opens events on the CPUs that were added since the events were opened, and returns
+    /// whether the events cover every CPU then. Otherwise, every later `barrier()` aborts,
+    /// unless the caller switches to another barrier, as the events no longer reach every
+    /// CPU.
+    pub fn cover_every_cpu() -> bool {
+        let events = match EVENTS.get() {
+            Some(Some(events)) => events,
+            _ => return false,
+        };
+        while ADDING
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        let covered = match configured_cpus() {
+            Some(cpus) => cpus <= events.fds().len() || events.add(cpus, true),
+            None => false,
+        };
+        if !covered {
+            UNCOVERED.store(true, Ordering::Relaxed);
+        }
+        ADDING.store(false, Ordering::Release);
+        covered
+    }
+
+    /// Issues a barrier on every CPU.
+    ///
+    /// Reading an event that is active on another CPU makes `perf_event_read()` in
+    /// `kernel/events/core.c` run `__perf_event_read()` there with
+    /// `smp_call_function_single()`, and wait until it is done. The handler only takes
+    /// `ctx->lock`, an acquire, and issues no `smp_mb()`, so the kernel guarantees no full
+    /// barrier on the interrupted CPU. On x86 and x86-64, the interrupt is taken between two
+    /// instructions of the interrupted thread, the `csd_unlock()` that completes the call is a
+    /// store that TSO keeps after every earlier store of that CPU, and the `IRET` back to the
+    /// thread is serializing (Intel SDM, vol. 3A, "Serializing Instructions"), which together
+    /// order the thread's accesses around the barrier. Other architectures make no such
+    /// promise, e.g. exception entry is no memory barrier on arm64, so `try_open()` never
+    /// opens the events there.
+    ///
+    /// The barrier must be supported. It is async-signal-safe, as it only reads the events.
+    /// Returns the `errno` of an event that can't be read, or `ENODEV` once
+    /// `cover_every_cpu()` failed.
+    pub fn barrier() -> Result<(), BarrierError> {
+        let events = match EVENTS.get() {
+            Some(Some(events)) if !UNCOVERED.load(Ordering::Relaxed) => events,
+            _ => return Err(BarrierError::new(Syscall::PerfEvent, libc::ENODEV)),
+        };
+        atomic::fence(Ordering::SeqCst);
+        if !events.read_all() {
+            return Err(BarrierError::new(Syscall::PerfEvent, errno()));
+        }
+        atomic::fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn events_cover_every_cpu() {
+            if !is_supported() {
+                assert!(fds().is_empty());
+                return;
+            }
+
+            assert_eq!(Some(fds().len()), configured_cpus());
+            assert!(cover_every_cpu());
+            for _ in 0..100 {
+                barrier().unwrap();
+            }
+        }
+
+        #[test]
+        #[cfg(not(all(
+            feature = "perf-barrier",
+            any(target_arch = "x86", target_arch = "x86_64")
+        )))]
+        fn unsupported_without_feature() {
+            assert!(!is_supported());
+        }
+    }
+}
+
+/// Issues a light memory barrier for fast path.
+///
+/// It issues a compiler fence, which disallows compiler optimizations across itself. It incurs
+/// basically no costs in run-time. Until the strategy is selected, by `init()` or the first
+/// heavy barrier, it issues a `SeqCst` fence instead. It never panics, which the `no-panic`
+/// crate checks at link time in the `no-panic` directory.
+#[inline]
+#[allow(dead_code)]
+pub fn light() {
+    use self::Strategy::*;
+    #[cfg(feature = "tsan")]
+    super::tsan::light();
+    // Never selects the strategy, so that it can't panic: until a `heavy()` or `init()`
+    // selects it, a fence is sound whatever is selected.
+    match STRATEGY.load() {
+        Some(Membarrier)
+        | Some(SharedMembarrier)
+        | Some(Mprotect)
+        | Some(Madvise)
+        | Some(Signal)
+        | Some(Perf) => atomic::compiler_fence(atomic::Ordering::SeqCst),
+        Some(Fallback) | None => atomic::fence(atomic::Ordering::SeqCst),
+    }
+    #[cfg(feature = "metrics")]
+    super::metrics::light();
+}
+
+/// Issues a heavy memory barrier for slow path.
+///
+/// It issues a private expedited membarrier using the `sys_membarrier()` system call, if
+/// supported; otherwise, it falls back to `mprotect()`-based process-wide memory barrier, or,
+/// with `Config::benchmark`, to its `madvise()`-based variant if that is faster on the current
+/// machine. If `Config::allow_signals` is set, it may instead send a realtime signal to every
+/// thread, if the `mprotect()` trick is not supported or `Config::benchmark` found it faster.
+/// With the `perf-barrier` feature, it reads a perf event pinned to every CPU if neither is
+/// available, on x86 and x86-64. On kernels that predate the expedited commands, Linux 4.3 to
+/// 4.13, it issues the legacy shared `sys_membarrier()` command if nothing else is available,
+/// which takes milliseconds but is still process-wide.
+///
+/// If the `sys_membarrier()` call starts failing with `EPERM` or `ENOSYS`, e.g. because the
+/// process tightened its seccomp policy after startup, this and all future barriers use the
+/// `mprotect()`-based trick instead. Where the trick is not supported, the process is aborted:
+/// `light()` may have relied on the failed barrier, so falling back to fences would be unsound.
+/// The same holds for the signal-based barrier once the threads can no longer be signaled.
+#[inline]
+#[allow(dead_code)]
+pub fn heavy() {
+    fatal_assert!(try_heavy().is_ok());
+}
+
+/// Implements `try_heavy()` with the selected strategy, replacing it like `heavy()` when
+/// `sys_membarrier()` or the signals are rejected.
+pub fn try_heavy() -> Result<(), BarrierError> {
+    use self::Strategy::*;
+    if super::single_caller::heavy() {
+        return Ok(());
+    }
+    #[cfg(feature = "metrics")]
+    let started = super::metrics::heavy_started();
+    let generation = super::generation::begin();
+    let strategy = strategy();
+    match strategy {
+        Membarrier | SharedMembarrier => {
+            if let Err(errno) = membarrier::try_barrier(strategy == SharedMembarrier) {
+                let error = BarrierError::new(Syscall::Membarrier, errno);
+                if errno != libc::EPERM && errno != libc::ENOSYS {
+                    return Err(error);
+                }
+                match selection::downgrade(super::config(), &mut SystemProbe) {
+                    Some(to) => {
+                        #[cfg(feature = "log")]
+                        log::warn!("membarrier: {}; using {:?}", error, backend_of(to));
+                        STRATEGY.downgrade(strategy, to)
+                    }
+                    None => return Err(error),
+                }
+                // Issued again with the new strategy, which also advances the generation
+                // and reports the barrier.
+                return try_heavy();
+            }
+        }
+        Mprotect => mprotect::try_barrier(mprotect::Method::Protect)?,
+        Madvise => mprotect::try_barrier(mprotect::Method::Dontneed)?,
+        Signal => {
+            if let Err(error) = signal::try_barrier() {
+                // The threads can't be signaled after all, e.g. as a seccomp filter started
+                // denying the signal, so `is_supported()` no longer holds either.
+                match selection::downgrade(super::config(), &mut SystemProbe) {
+                    Some(to) if to != Signal => {
+                        #[cfg(feature = "log")]
+                        log::warn!("membarrier: {}; using {:?}", error, backend_of(to));
+                        STRATEGY.downgrade(strategy, to)
+                    }
+                    _ => return Err(error),
+                }
+                return try_heavy();
+            }
+        }
+        Perf => perf::barrier()?,
+        Fallback => atomic::fence(atomic::Ordering::SeqCst),
+    }
+    super::generation::end(generation);
+    #[cfg(feature = "metrics")]
+    super::metrics::heavy_finished(started);
+    Ok(())
+}
+
+/// Implements `try_heavy_timeout()`, only waiting for the `mprotect()`-based and signal-based
+/// barriers.
+pub fn try_heavy_timeout(timeout: Duration) -> Result<(), Timeout> {
+    use self::Strategy::*;
+    let strategy = strategy();
+    let generation = super::generation::begin();
+    let issued = match strategy {
+        Mprotect => mprotect::barrier_timeout(mprotect::Method::Protect, timeout),
+        Madvise => mprotect::barrier_timeout(mprotect::Method::Dontneed, timeout),
+        Signal => match signal::barrier(Some(timeout)) {
+            Ok(()) => true,
+            Err(Missed::Timeout) => false,
+            Err(Missed::Unsupported(_)) => {
+                heavy();
+                true
+            }
+        },
+        Membarrier | SharedMembarrier | Perf | Fallback => {
+            heavy();
+            true
+        }
+    };
+    if issued {
+        super::generation::end(generation);
+        Ok(())
+    } else {
+        Err(Timeout)
+    }
+}
+
+/// Implements `heavy_gentle()` with the non-expedited `sys_membarrier()` command, where it is
+/// available and the strategy would interrupt other CPUs.
+pub fn heavy_gentle() {
+    let strategy = strategy();
+    let interrupting = strategy != Strategy::Fallback && strategy != Strategy::SharedMembarrier;
+    if interrupting && detection().shared {
+        let generation = super::generation::begin();
+        if membarrier::shared_barrier() {
+            super::generation::end(generation);
+            return;
+        }
+    }
+    heavy()
+}
+
+/// Whether the process is registered for `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ`, which
+/// `BarrierScope::heavy()` and `heavy_rseq()` issue.
+#[cfg(any(feature = "std", feature = "rseq-barrier"))]
+static RSEQ: SpinOnce<bool> = SpinOnce::new();
+
+/// Returns whether the process is registered for `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ`,
+/// registering it on first use unless `Config::auto_register` is unset.
+#[cfg(any(feature = "std", feature = "rseq-barrier"))]
+fn rseq_registered() -> bool {
+    *RSEQ.get_or_init(|| {
+        super::config().auto_register && register_all(&[Command::PrivateExpeditedRseq]).is_ok()
+    })
+}
+
+/// Implements `heavy_rseq()` with `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ`, falling back to
+/// `heavy()` where it is unavailable.
+#[cfg(feature = "rseq-barrier")]
+pub fn heavy_rseq() {
+    if !rseq_registered() {
+        return heavy();
+    }
+    let generation = super::generation::begin();
+    if !membarrier::rseq_barrier() {
+        return heavy();
+    }
+    super::generation::end(generation);
+}
+
+/// Whether the process is registered for `MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE`, or why
+/// it couldn't be.
+static SYNC_CORE: SpinOnce<Result<(), RegisterError>> = SpinOnce::new();
+
+/// Implements `sync_core()` with `MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE`.
+pub fn sync_core() -> Result<(), RegisterError> {
+    (*SYNC_CORE.get_or_init(|| {
+        if super::config().auto_register {
+            register_all(&[Command::PrivateExpeditedSyncCore])
+        } else {
+            Ok(())
+        }
+    }))?;
+    let generation = super::generation::begin();
+    membarrier::try_sync_core().map_err(RegisterError::from_errno)?;
+    super::generation::end(generation);
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+impl BarrierScope {
+    /// Issues a heavy memory barrier that synchronizes with the `light()` of every registered
+    /// thread.
+    ///
+    /// It reads from `/proc` which CPU each registered thread last ran on, and interrupts
+    /// only those CPUs, one `MEMBARRIER_CMD_PRIVATE_EXPEDITED_RSEQ` call per CPU. A thread
+    /// that moved to another CPU since was switched out in between, which the scheduler
+    /// orders like a full barrier, so it needs no interrupt. The process is registered for
+    /// the command on first use, unless `Config::auto_register` is unset. Where the command
+    /// is unavailable, or the registered threads occupy as many CPUs as are online, it is
+    /// just `heavy()`.
+    pub fn heavy(&self) {
+        use super::procfs::{self, MAX_CPUS};
+
+        if !rseq_registered() {
+            return heavy();
+        }
+
+        // Orders the accesses of the caller before reading where the threads run.
+        atomic::fence(atomic::Ordering::SeqCst);
+        #[cfg(feature = "tsan")]
+        super::tsan::heavy();
+        let mut cpus = [0u64; MAX_CPUS / 64];
+        let threads = self.lock();
+        for thread in threads.iter() {
+            match procfs::task_cpu(thread.tid) {
+                Some(cpu) if cpu < MAX_CPUS => cpus[cpu / 64] |= 1 << (cpu % 64),
+                Some(_) => return heavy(),
+                // The thread exited.
+                None => {}
+            }
+        }
+        let count = cpus
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum::<usize>();
+        if let Some(online) = online_cpus() {
+            if count >= online {
+                return heavy();
+            }
+        }
+
+        for cpu in 0..MAX_CPUS {
+            if cpus[cpu / 64] & 1 << (cpu % 64) != 0 && !membarrier::barrier_on(cpu) {
+                return heavy();
+            }
+        }
+    }
+}
+
+/// Implements `heavy_reporting()`, sampling the CPUs or threads the selected strategy reached.
+#[cfg(feature = "diagnostics")]
+pub fn heavy_reporting() -> BarrierReport {
+    use self::Strategy::*;
+    heavy();
+    let (threads, cpus) = match strategy() {
+        Membarrier => (None, affinity::allowed_cpus()),
+        SharedMembarrier => (None, online_cpus()),
+        Mprotect | Madvise => (None, super::procfs::occupied_cpus()),
+        Signal => (signal::thread_count(), None),
+        Perf => (None, Some(perf::fds().len())),
+        Fallback => (None, None),
+    };
+    BarrierReport::new(backend(), threads, cpus)
+}
+
+/// Implements `init()` by selecting the strategy, and registering for the rseq command with the
+/// `rseq-barrier` feature.
+pub fn init() {
+    strategy();
+    #[cfg(feature = "rseq-barrier")]
+    rseq_registered();
+}
+
+/// Implements `reinit_after_fork()`, replacing the pages and mutexes of the `mprotect()`-based
+/// barriers and renewing the registrations.
+pub unsafe fn reinit_after_fork() {
+    mprotect::reinit_after_fork();
+    signal::reinit_after_fork();
+    if STRATEGY.load() == Some(Strategy::Membarrier) {
+        let _ = register_all(&[Command::PrivateExpedited]);
+    }
+    #[cfg(any(feature = "std", feature = "rseq-barrier"))]
+    {
+        if RSEQ.get() == Some(&true) {
+            let _ = register_all(&[Command::PrivateExpeditedRseq]);
+        }
+    }
+    if SYNC_CORE.get() == Some(&Ok(())) {
+        let _ = register_all(&[Command::PrivateExpeditedSyncCore]);
+    }
+}
+
+/// Implements `refresh_topology()`, opening perf events on the CPUs added since the
+/// perf-event-based barrier opened its events, or leaving that barrier if they can't be opened.
+pub fn refresh_topology() -> Result<(), UncoveredCpus> {
+    if strategy() != Strategy::Perf || perf::cover_every_cpu() {
+        return Ok(());
+    }
+    // `light()` is a compiler fence for both, so the shared command covers the barriers that
+    // relied on the events.
+    if !SystemProbe.shared_membarrier_usable() {
+        return Err(UncoveredCpus);
+    }
+    #[cfg(feature = "log")]
+    log::warn!(
+        "membarrier: perf events can't be opened on the added CPUs; using {:?}",
+        Backend::SharedMembarrier
+    );
+    STRATEGY.downgrade(Strategy::Perf, Strategy::SharedMembarrier);
+    Ok(())
+}
+
+/// Implements `heavy_signal_safe()` with the strategies that take no lock, without ever
+/// switching strategies.
+pub fn heavy_signal_safe() -> bool {
+    use self::Strategy::*;
+    let generation = super::generation::begin();
+    let issued = match STRATEGY.load() {
+        Some(Membarrier) => membarrier::barrier(),
+        Some(SharedMembarrier) => membarrier::shared_barrier(),
+        Some(Perf) => perf::barrier().is_ok(),
+        Some(Fallback) => {
+            atomic::fence(atomic::Ordering::SeqCst);
+            true
+        }
+        Some(Mprotect) | Some(Madvise) | Some(Signal) | None => false,
+    };
+    if issued {
+        super::generation::end(generation);
+    }
+    issued
+}
+
+/// Implements `has_signal_safe_heavy()` for the selected strategy.
+pub fn has_signal_safe_heavy() -> bool {
+    let strategy = STRATEGY.load();
+    strategy == Some(Strategy::Membarrier)
+        || strategy == Some(Strategy::SharedMembarrier)
+        || strategy == Some(Strategy::Perf)
+        || strategy == Some(Strategy::Fallback)
+}
+
+/// Implements `is_supported()` by probing the strategies the configuration allows until one is
+/// selected.
+pub fn is_supported() -> bool {
+    if let Some(strategy) = STRATEGY.load() {
+        return strategy != Strategy::Fallback;
+    }
+    let config = super::CONFIG.get().cloned().unwrap_or_default();
+    if config.prefer == Some(Backend::Fence) {
+        return false;
+    }
+    let detection = match MEMBARRIER.get() {
+        Some(detection) => *detection,
+        None => membarrier::detect(false),
+    };
+    let membarrier = detection.usable
+        || config.auto_register && detection.error == Some(RegisterError::Disabled);
+    membarrier
+        || detection.shared
+        || config.allow_mprotect && mprotect::is_supported()
+        || config.allow_signals && signal::is_available()
+        || perf::is_available()
+}
+
+/// Implements `backend()` by selecting the strategy.
+#[inline]
+pub fn backend() -> Backend {
+    backend_of(strategy())
+}
+
+/// Returns the mechanism `strategy` uses.
+#[inline]
+fn backend_of(strategy: Strategy) -> Backend {
+    use self::Strategy::*;
+    match strategy {
+        Membarrier => Backend::Membarrier,
+        SharedMembarrier => Backend::SharedMembarrier,
+        Mprotect => Backend::Mprotect,
+        Madvise => Backend::Madvise,
+        Signal => Backend::Signal,
+        Perf => Backend::PerfEvent,
+        Fallback => Backend::Fence,
+    }
+}
+
+/// Implements `expected_heavy_cost()` from the number of CPUs or threads the selected strategy
+/// interrupts.
+pub fn expected_heavy_cost() -> HeavyCost {
+    use self::Strategy::*;
+    match strategy() {
+        Membarrier | Mprotect | Madvise => HeavyCost::of_reach(online_cpus()),
+        SharedMembarrier => HeavyCost::Expensive,
+        Signal => HeavyCost::of_reach(signal::thread_count()),
+        Perf => HeavyCost::of_reach(Some(perf::fds().len())),
+        Fallback => HeavyCost::Cheap,
+    }
+}
+
+/// Returns the number of online CPUs, or `None` if it is unknown.
+fn online_cpus() -> Option<usize> {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus > 0 {
+        Some(cpus as usize)
+    } else {
+        None
+    }
+}
+
+/// Implements `capabilities()`, adding what the detection of `sys_membarrier()`, hypervisors,
+/// and isolated CPUs found.
+pub fn capabilities() -> Capabilities {
+    let mut capabilities = Capabilities::new(backend());
+    capabilities.membarrier_commands = detection().commands;
+    capabilities.membarrier_registrations = detection().registrations;
+    capabilities.register_error = detection().error;
+    capabilities.hypervisor = hypervisor::detect();
+    capabilities.isolated_cpus = isolation::detect();
+    capabilities
+}
+
+/// Implements `register_all()` with `sys_membarrier()`.
+pub fn register_all(commands: &[Command]) -> Result<(), RegisterError> {
+    membarrier::register_all(commands)
+}
+
+/// Implements `fds()` with the pages of the `mprotect()`-based barriers and the perf events.
+pub fn fds() -> HeldResources {
+    let mut resources = HeldResources::default();
+    for &method in &[mprotect::Method::Protect, mprotect::Method::Dontneed] {
+        if let Some(mapping) = mprotect::mapping(method) {
+            resources.push_mapping(mapping);
+        }
+    }
+    resources.set_fds(super::held_fds::get());
+    resources
+}
+
+/// Implements `mprotect_page_locked()` for the `mprotect()`-based strategy, but not the
+/// `madvise()`-based one.
+pub fn mprotect_page_locked() -> Option<bool> {
+    if STRATEGY.load() == Some(Strategy::Mprotect) {
+        mprotect::page_locked()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn strategy_downgrades_once() {
+        let strategy = AtomicStrategy::new(Strategy::Membarrier);
+        strategy.downgrade(Strategy::Membarrier, Strategy::Madvise);
+        assert!(strategy.load() == Some(Strategy::Madvise));
+
+        // Only the first of concurrent downgrades takes effect.
+        strategy.downgrade(Strategy::Membarrier, Strategy::Mprotect);
+        assert!(strategy.load() == Some(Strategy::Madvise));
+    }
+
+    #[test]
+    fn strategy_is_resolved_once() {
+        let handles = (0..16)
+            .map(|i| {
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        if i % 2 == 0 {
+                            light();
+                        } else {
+                            heavy();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(DETECTIONS.load(atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn mprotect_methods() {
+        if !mprotect::is_supported() {
+            return;
+        }
+
+        for _ in 0..100 {
+            mprotect::barrier(mprotect::Method::Protect);
+            mprotect::barrier(mprotect::Method::Dontneed);
+        }
+        mprotect::fastest_method();
+    }
+
+    /// On x86 and x86-64, including 32-bit `i686` targets, and on riscv64, the
+    /// `mprotect()`-based trick is always available as a replacement for `sys_membarrier()`.
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "riscv64"))]
+    fn mprotect_strategy_is_selected() {
+        assert!(mprotect::is_supported());
+        let strategy = AtomicStrategy::new(Strategy::Membarrier);
+        strategy.downgrade(Strategy::Membarrier, SystemProbe.mprotect_fastest());
+        let strategy = strategy.load();
+        assert!(strategy == Some(Strategy::Mprotect) || strategy == Some(Strategy::Madvise));
+    }
+
+    /// Runs a store-buffering litmus test between a writer issuing `barrier` and oversubscribed
+    /// readers issuing `light()`: in every round, either the writer observes a reader's flag or
+    /// the reader observes the writer's value, but never neither.
+    fn litmus(barrier: fn()) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{Arc, Barrier};
+
+        const ROUNDS: usize = 1000;
+
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as usize;
+        let readers = (2 * cpus).min(16);
+
+        let value = Arc::new(AtomicUsize::new(0));
+        let flags = Arc::new(
+            (0..readers)
+                .map(|_| AtomicUsize::new(0))
+                .collect::<Vec<_>>(),
+        );
+        let round = Arc::new(Barrier::new(readers + 1));
+
+        let handles = (0..readers)
+            .map(|i| {
+                let (value, flags, round) = (value.clone(), flags.clone(), round.clone());
+                thread::spawn(move || {
+                    let mut seen = Vec::with_capacity(ROUNDS);
+                    for r in 1..=ROUNDS {
+                        round.wait();
+                        flags[i].store(r, Ordering::Relaxed);
+                        light();
+                        seen.push(value.load(Ordering::Relaxed) == r);
+                        round.wait();
+                    }
+                    seen
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut observed = Vec::with_capacity(ROUNDS);
+        for r in 1..=ROUNDS {
+            round.wait();
+            value.store(r, Ordering::Relaxed);
+            barrier();
+            observed.push(
+                flags
+                    .iter()
+                    .map(|flag| flag.load(Ordering::Relaxed) == r)
+                    .collect::<Vec<_>>(),
+            );
+            round.wait();
+        }
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let seen = handle.join().unwrap();
+            for r in 0..ROUNDS {
+                assert!(
+                    seen[r] || observed[r][i],
+                    "reader {} and the writer missed each other in round {}",
+                    i,
+                    r + 1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mprotect_concurrent_readers() {
+        if mprotect::is_supported() {
+            litmus(|| mprotect::barrier(mprotect::Method::Protect));
+        }
+    }
+
+    #[test]
+    fn madvise_concurrent_readers() {
+        if mprotect::is_supported() {
+            litmus(|| mprotect::barrier(mprotect::Method::Dontneed));
+        }
+    }
+
+    #[test]
+    fn perf_concurrent_readers() {
+        if perf::is_supported() {
+            litmus(|| perf::barrier().unwrap());
+        }
+    }
+}
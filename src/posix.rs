@@ -0,0 +1,57 @@
+//! The parts of the process-wide barriers that work on every POSIX system with the `mprotect()`
+//! trick, shared by the backends of those systems.
+
+use core::mem::MaybeUninit;
+use core::time::Duration;
+
+use super::spin_once::SpinOnce;
+
+/// Returns the `errno` of the current thread.
+pub fn errno() -> libc::c_int {
+    cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "dragonfly"))] {
+            unsafe { *libc::__errno_location() }
+        } else if #[cfg(target_os = "freebsd")] {
+            unsafe { *libc::__error() }
+        } else if #[cfg(any(target_os = "netbsd", target_os = "openbsd"))] {
+            unsafe { *libc::__errno() }
+        } else {
+            unsafe { *libc::___errno() }
+        }
+    }
+}
+
+/// Returns the monotonic time in nanoseconds.
+pub fn now() -> u64 {
+    let mut ts = MaybeUninit::<libc::timespec>::uninit();
+    unsafe {
+        fatal_assert!(libc::clock_gettime(libc::CLOCK_MONOTONIC, ts.as_mut_ptr()) == 0);
+        let ts = ts.assume_init();
+        ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+    }
+}
+
+/// Returns the `CLOCK_REALTIME` time `timeout` from now, as taken by `pthread_mutex_timedlock`.
+pub fn deadline_after(timeout: Duration) -> libc::timespec {
+    let mut now = MaybeUninit::<libc::timespec>::uninit();
+    unsafe {
+        fatal_assert!(libc::clock_gettime(libc::CLOCK_REALTIME, now.as_mut_ptr()) == 0);
+        let now = now.assume_init();
+
+        // Saturate rather than overflow for absurdly long timeouts.
+        let nsec = now.tv_nsec as u64 + u64::from(timeout.subsec_nanos());
+        let secs = (now.tv_sec as u64)
+            .saturating_add(timeout.as_secs())
+            .saturating_add(nsec / 1_000_000_000);
+        libc::timespec {
+            tv_sec: if secs > libc::time_t::MAX as u64 {
+                libc::time_t::MAX
+            } else {
+                secs as libc::time_t
+            },
+            tv_nsec: (nsec % 1_000_000_000) as _,
+        }
+    }
+}
+
+pub mod mprotect;
@@ -0,0 +1,869 @@
+use core::{cell::UnsafeCell, mem::MaybeUninit, ptr, sync::atomic, time::Duration};
+use libc;
+
+use super::super::{BarrierError, Mapping, Syscall};
+use super::{deadline_after, errno, now, SpinOnce};
+
+/// Asks `mmap` to fault the pages in eagerly, which only Linux can.
+#[cfg(target_os = "linux")]
+const MAP_POPULATE: libc::c_int = libc::MAP_POPULATE;
+#[cfg(not(target_os = "linux"))]
+const MAP_POPULATE: libc::c_int = 0;
+
+/// How a `Barrier` makes the OS flush TLBs on all processors.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Changing the access protections of the page from read + write, or read-only, to
+    /// none.
+    Protect,
+    /// Discarding the page with `madvise(MADV_DONTNEED)`.
+    Dontneed,
+}
+
+/// The access protections a `Method::Protect` barrier grants its page before revoking them
+/// again.
+///
+/// Revoking them flushes the TLBs as long as the page table entry of the page is present
+/// and accessed, whatever the protections were: the entry changes from present to not, so
+/// no processor may keep caching it. The page was written to when it was created and is
+/// locked, so it is backed by its own frame rather than by the shared zero page, and its
+/// entry stays present. Accessing the page while it is granted sets the accessed bit,
+/// which Linux 6.0 and later check before flushing. A read does that as well as a write,
+/// while it needs no atomic read-modify-write and never makes the kernel track the page as
+/// dirty, so `Grant::Read` may be cheaper. Which one is faster is measured when the
+/// barrier is created with `Config::benchmark`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Grant {
+    /// `PROT_READ | PROT_WRITE`, with a write to the page.
+    ReadWrite,
+    /// `PROT_READ`, with a read from the page.
+    Read,
+}
+
+struct Barrier {
+    lock: UnsafeCell<libc::pthread_mutex_t>,
+    /// The address of the page.
+    page: usize,
+    /// The size of the page, as reported by the system rather than assumed to be 4 KiB, so
+    /// that the whole page is mapped, protected, locked, and discarded where pages are
+    /// larger, e.g. 16 KiB on Apple Silicon or 64 KiB on some ARM64 Linux kernels.
+    page_size: libc::size_t,
+    method: Method,
+    /// Whether `mlock()` locked the page, which is only attempted for `Method::Protect`.
+    locked: bool,
+    /// The number of barriers started so far. It is only modified with `lock` held.
+    generation: atomic::AtomicUsize,
+    /// The access protections `Method::Protect` grants the page during a flush.
+    grant: Grant,
+}
+
+// On some systems `pthread_mutex_t` is a pointer to the mutex, which is not bound to
+// the thread that initialized it.
+unsafe impl Send for Barrier {}
+unsafe impl Sync for Barrier {}
+
+/// Creates a `PTHREAD_MUTEX_NORMAL` mutex, setting its type with `settype`.
+///
+/// Some libcs reject the type or the attribute calls, in which case the statically
+/// initialized mutex with default attributes is kept. It is just as good, as the mutex is
+/// never locked recursively nor unlocked by another thread.
+unsafe fn new_lock(
+    settype: unsafe extern "C" fn(*mut libc::pthread_mutexattr_t, libc::c_int) -> libc::c_int,
+) -> UnsafeCell<libc::pthread_mutex_t> {
+    let lock = UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER);
+    let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+    if libc::pthread_mutexattr_init(attr.as_mut_ptr()) != 0 {
+        return lock;
+    }
+    let mut attr = attr.assume_init();
+    if settype(&mut attr, libc::PTHREAD_MUTEX_NORMAL) == 0
+        && libc::pthread_mutex_init(lock.get(), &attr) != 0
+    {
+        // A failed initialization leaves the mutex unspecified, so start over.
+        *lock.get() = libc::PTHREAD_MUTEX_INITIALIZER;
+    }
+    libc::pthread_mutexattr_destroy(&mut attr);
+    lock
+}
+
+/// Maps a page backed by a `memfd` that is sealed against resizing, so that sandboxing tools
+/// can tell where the page comes from and allow its file descriptor explicitly. Returns
+/// `None` if the kernel lacks `memfd_create()` or sealing.
+#[cfg(all(target_os = "linux", feature = "memfd-mprotect"))]
+unsafe fn map_memfd(page_size: libc::size_t) -> Option<*mut libc::c_void> {
+    let fd = libc::syscall(
+        libc::SYS_memfd_create,
+        b"membarrier\0".as_ptr() as *const libc::c_char,
+        libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+    ) as libc::c_int;
+    if fd < 0 {
+        return None;
+    }
+
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+    let page = if libc::ftruncate(fd, page_size as libc::off_t) == 0
+        && libc::fcntl(fd, libc::F_ADD_SEALS, seals) == 0
+    {
+        libc::mmap(
+            ptr::null_mut(),
+            page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | MAP_POPULATE,
+            fd,
+            0 as libc::off_t,
+        )
+    } else {
+        libc::MAP_FAILED
+    };
+    if page == libc::MAP_FAILED {
+        libc::close(fd);
+        return None;
+    }
+
+    if !super::super::held_fds::push(&[fd]) {
+        libc::munmap(page, page_size);
+        libc::close(fd);
+        return None;
+    }
+    Some(page)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "memfd-mprotect")))]
+unsafe fn map_memfd(_page_size: libc::size_t) -> Option<*mut libc::c_void> {
+    None
+}
+
+/// Sets the memory policy of `page` to prefer the NUMA node of
+/// `Config::mprotect_numa_node`, if any, and migrates it there if it is already faulted
+/// in elsewhere. The policy belongs to the mapping, so a page that is discarded by
+/// `Method::Dontneed` is faulted in again on the same node.
+///
+/// The placement is only a hint, so a failure, e.g. for an unknown node, is ignored.
+#[cfg(target_os = "linux")]
+unsafe fn bind_to_node(page: *mut libc::c_void, page_size: libc::size_t) {
+    /// Migrates the pages of the range that are already faulted in.
+    const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+    let node = match super::super::config().mprotect_numa_node {
+        Some(node) => node as usize,
+        None => return,
+    };
+    let mut nodes = [0 as libc::c_ulong; 16];
+    let bits = 8 * core::mem::size_of::<libc::c_ulong>();
+    if node >= nodes.len() * bits {
+        return;
+    }
+    nodes[node / bits] |= 1 << (node % bits);
+
+    // The kernel reads one node fewer than `maxnode`.
+    let ret = libc::syscall(
+        libc::SYS_mbind,
+        page,
+        page_size as libc::c_ulong,
+        libc::MPOL_PREFERRED as libc::c_ulong,
+        nodes.as_ptr(),
+        (nodes.len() * bits + 1) as libc::c_ulong,
+        MPOL_MF_MOVE,
+    );
+    if ret != 0 {
+        #[cfg(feature = "log")]
+        log::warn!(
+            "membarrier: mbind() to NUMA node {} failed with errno {}",
+            node,
+            errno()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn bind_to_node(_page: *mut libc::c_void, _page_size: libc::size_t) {}
+
+/// Returns the error of `syscall` if it failed, i.e. returned the nonzero `ret`.
+#[inline]
+fn check(ret: libc::c_int, syscall: Syscall) -> Result<(), BarrierError> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(BarrierError::new(syscall, errno()))
+    }
+}
+
+/// Changes the protections of `page` to each of `prots` in turn, and returns `false` if a
+/// security policy denies a change with `EACCES` or `EPERM`, e.g. PaX `MPROTECT` or an
+/// SELinux policy. Aborts on any other failure.
+unsafe fn permits(page: *mut libc::c_void, page_size: libc::size_t, prots: &[libc::c_int]) -> bool {
+    for &prot in prots {
+        if libc::mprotect(page, page_size, prot) != 0 {
+            let errno = errno();
+            fatal_assert!(errno == libc::EACCES || errno == libc::EPERM);
+            return false;
+        }
+    }
+    true
+}
+
+impl Barrier {
+    /// Creates a barrier with a dedicated page that is flushed with `method`.
+    ///
+    /// Returns `None` if a security policy denies changing the protections of the page,
+    /// which can only be the case for `Method::Protect`.
+    unsafe fn new(method: Method) -> Option<Barrier> {
+        // Find out the page size on the current system.
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE);
+        fatal_assert!(page_size > 0);
+        let page_size = page_size as libc::size_t;
+
+        // Create a dummy page. It is populated eagerly, so that the writes to it in
+        // `Barrier::barrier_locked()` never have to allocate memory, and thus can't fail
+        // under memory pressure. With the `memfd-mprotect` feature, the page of
+        // `Method::Protect` is backed by a sealed `memfd` where the kernel supports it.
+        // `Method::Dontneed` relies on discarding a private page, so it is always anonymous.
+        let memfd = if method == Method::Protect {
+            map_memfd(page_size)
+        } else {
+            None
+        };
+        let page = match memfd {
+            Some(page) => page,
+            None => libc::mmap(
+                ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | MAP_POPULATE,
+                -1 as libc::c_int,
+                0 as libc::off_t,
+            ),
+        };
+        fatal_assert!(page != libc::MAP_FAILED);
+        let page_offset = page as libc::size_t % page_size;
+        fatal_assert!(page_offset == 0);
+        bind_to_node(page, page_size);
+
+        // `MAP_POPULATE` is only a hint, so fault the page in by writing to it.
+        (*(page as *const atomic::AtomicUsize)).store(0, atomic::Ordering::SeqCst);
+
+        // Locking the page ensures that it stays in memory during the two mprotect
+        // calls in `Barrier::barrier()`. If the page was unmapped between those calls,
+        // they would not have the expected effect of generating IPI, and the write
+        // between them would have to fault the page in again. Locked pages can't be
+        // discarded, though, so `Method::Dontneed` leaves its page unlocked. Its page is
+        // faulted in again by the write after every barrier, which may fail like any
+        // other allocation when the system is out of memory. Locking may fail, e.g. when
+        // it would exceed `RLIMIT_MEMLOCK`, which leaves the page unlocked but usable.
+        let mut locked = false;
+        if method == Method::Protect {
+            locked = libc::mlock(page, page_size as libc::size_t) == 0;
+            if !locked {
+                #[cfg(feature = "log")]
+                log::warn!(
+                    "membarrier: mlock() of the barrier page failed with errno {}",
+                    errno()
+                );
+            }
+
+            // The page is only ever accessible during a barrier with `Method::Protect`.
+            if !permits(page, page_size, &[libc::PROT_NONE]) {
+                libc::munmap(page, page_size);
+                return None;
+            }
+        }
+
+        let page = page as usize;
+
+        let mut barrier = Barrier {
+            lock: new_lock(libc::pthread_mutexattr_settype),
+            page,
+            page_size,
+            method,
+            locked,
+            generation: atomic::AtomicUsize::new(0),
+            grant: Grant::ReadWrite,
+        };
+        if method == Method::Protect {
+            let grant = if super::super::config().benchmark {
+                barrier.fastest_grant()
+            } else {
+                barrier.permitted_grant()
+            };
+            barrier.grant = match grant {
+                Some(grant) => grant,
+                None => {
+                    libc::munmap(page as *mut libc::c_void, page_size);
+                    return None;
+                }
+            };
+        }
+        Some(barrier)
+    }
+
+    /// Returns the first `Grant` that the security policies permit, preferring
+    /// `Grant::ReadWrite`, or `None` if none is permitted.
+    unsafe fn permitted_grant(&self) -> Option<Grant> {
+        let page = self.page as *mut libc::c_void;
+        if permits(
+            page,
+            self.page_size,
+            &[libc::PROT_READ | libc::PROT_WRITE, libc::PROT_NONE],
+        ) {
+            Some(Grant::ReadWrite)
+        } else if permits(page, self.page_size, &[libc::PROT_READ, libc::PROT_NONE]) {
+            Some(Grant::Read)
+        } else {
+            None
+        }
+    }
+
+    /// Measures how long a few flushes take with each `Grant` that the security policies
+    /// permit, and returns the faster one, or `None` if none is permitted.
+    ///
+    /// The barrier is not shared yet, so it is flushed without the mutex.
+    unsafe fn fastest_grant(&mut self) -> Option<Grant> {
+        const ROUNDS: usize = 16;
+
+        let page = self.page as *mut libc::c_void;
+        let mut fastest = None;
+        for &grant in &[Grant::ReadWrite, Grant::Read] {
+            let prot = match grant {
+                Grant::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+                Grant::Read => libc::PROT_READ,
+            };
+            if !permits(page, self.page_size, &[prot, libc::PROT_NONE]) {
+                continue;
+            }
+
+            self.grant = grant;
+            // Warm up, so that the page table entry is in its steady state.
+            if self.flush().is_err() {
+                continue;
+            }
+
+            let start = now();
+            if !(0..ROUNDS).all(|_| self.flush().is_ok()) {
+                continue;
+            }
+            let elapsed = now() - start;
+            match fastest {
+                Some((fastest, _)) if fastest <= elapsed => {}
+                _ => fastest = Some((elapsed, grant)),
+            }
+        }
+        fastest.map(|(_, grant)| grant)
+    }
+
+    /// Issues a process-wide barrier by changing access protections of a single mmap-ed
+    /// page, or by discarding it. This method is not as fast as the `sys_membarrier()`
+    /// call, but works very similarly.
+    #[inline]
+    fn barrier(&self) -> Result<(), BarrierError> {
+        let generation = self.generation.load(atomic::Ordering::SeqCst);
+        unsafe {
+            // Lock the mutex.
+            fatal_assert!(libc::pthread_mutex_lock(self.lock.get()) == 0);
+
+            self.barrier_locked(generation)
+        }
+    }
+
+    /// Like `barrier()`, but gives up if the mutex can't be locked by `deadline`, which is
+    /// measured by `CLOCK_REALTIME`. Returns `Ok(true)` if the barrier was issued.
+    fn barrier_until(&self, deadline: &libc::timespec) -> Result<bool, BarrierError> {
+        let generation = self.generation.load(atomic::Ordering::SeqCst);
+        unsafe {
+            match libc::pthread_mutex_timedlock(self.lock.get(), deadline) {
+                0 => {}
+                libc::ETIMEDOUT => return Ok(false),
+                _ => fatal_assert!(false),
+            }
+
+            self.barrier_locked(generation)?;
+        }
+        Ok(true)
+    }
+
+    /// Issues the barrier and unlocks the mutex, which must be locked by the caller.
+    /// `generation` is the value of `self.generation` the caller read before locking.
+    ///
+    /// With the `coalesce-mprotect` feature, the barrier is skipped if another one started
+    /// since the caller read `generation`. That barrier started after the caller's request
+    /// and, as barriers are serialized by the mutex, it has completed by now, so it
+    /// satisfies the request just as well. Under bursty load, all the requests that pile up
+    /// on the mutex during a barrier are thus served by a single next one.
+    unsafe fn barrier_locked(&self, generation: usize) -> Result<(), BarrierError> {
+        if cfg!(feature = "coalesce-mprotect")
+            && self.generation.load(atomic::Ordering::SeqCst) != generation
+        {
+            fatal_assert!(libc::pthread_mutex_unlock(self.lock.get()) == 0);
+            return Ok(());
+        }
+        self.generation.fetch_add(1, atomic::Ordering::SeqCst);
+        let flushed = self.flush();
+
+        // Unlock the mutex.
+        fatal_assert!(libc::pthread_mutex_unlock(self.lock.get()) == 0);
+        flushed
+    }
+
+    /// Flushes the TLBs on all processors, which the caller must hold the mutex for.
+    ///
+    /// Returns the error of the first system call that failed, if any.
+    unsafe fn flush(&self) -> Result<(), BarrierError> {
+        let page = self.page as *mut libc::c_void;
+
+        match self.method {
+            Method::Protect => {
+                // Set the page access protections to read + write, or read-only.
+                let prot = match self.grant {
+                    Grant::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+                    Grant::Read => libc::PROT_READ,
+                };
+                check(
+                    libc::mprotect(page, self.page_size, prot),
+                    Syscall::Mprotect,
+                )?;
+
+                // Ensure that the page is accessed, and dirty if it is writable, before we
+                // change the protection so that we prevent the OS from skipping the
+                // global TLB flush.
+                match self.grant {
+                    Grant::ReadWrite => {
+                        let atomic_usize = &*(page as *const atomic::AtomicUsize);
+                        atomic_usize.fetch_add(1, atomic::Ordering::SeqCst);
+                    }
+                    Grant::Read => {
+                        // A volatile read is always emitted, unlike an unused atomic load.
+                        ptr::read_volatile(page as *const usize);
+                    }
+                }
+
+                // Set the page access protections to none.
+                //
+                // Changing a page protection from read + write to none causes the OS
+                // to issue an interrupt to flush TLBs on all processors. This also
+                // results in flushing the processor buffers.
+                check(
+                    libc::mprotect(page, self.page_size, libc::PROT_NONE),
+                    Syscall::Mprotect,
+                )
+            }
+            Method::Dontneed => {
+                // Ensure that the page is mapped and dirty, as the OS skips the
+                // global TLB flush when discarding a page that isn't mapped.
+                let atomic_usize = &*(page as *const atomic::AtomicUsize);
+                atomic_usize.fetch_add(1, atomic::Ordering::SeqCst);
+
+                // Discard the page.
+                //
+                // Discarding a private page removes its mapping, which causes the OS
+                // to issue an interrupt to flush TLBs on all processors, just like
+                // revoking its access protections.
+                check(
+                    libc::madvise(page, self.page_size, libc::MADV_DONTNEED),
+                    Syscall::Madvise,
+                )
+            }
+        }
+    }
+
+    /// Issues a barrier and checks that it had the effect on the page the trick relies
+    /// on: with `Method::Protect`, the page is inaccessible afterwards, and with
+    /// `Method::Dontneed`, it was discarded, so it reads as zeros.
+    ///
+    /// The accesses to a protected page are made by the kernel on our behalf, like in
+    /// `self_test()`.
+    #[cfg(feature = "paranoid")]
+    fn round_trip(&self) -> bool {
+        unsafe {
+            let mut fds = [0 as libc::c_int; 2];
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) != 0 {
+                return false;
+            }
+
+            fatal_assert!(libc::pthread_mutex_lock(self.lock.get()) == 0);
+            self.generation.fetch_add(1, atomic::Ordering::SeqCst);
+            let flushed = self.flush().is_ok();
+            let page = self.page as *const libc::c_void;
+            let passed = flushed
+                && match self.method {
+                    Method::Protect => {
+                        libc::write(fds[1], page, 1) == -1 && errno() == libc::EFAULT
+                    }
+                    Method::Dontneed => {
+                        (*(page as *const atomic::AtomicUsize)).load(atomic::Ordering::SeqCst) == 0
+                    }
+                };
+            fatal_assert!(libc::pthread_mutex_unlock(self.lock.get()) == 0);
+
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            passed
+        }
+    }
+}
+
+/// An alternative solution to `sys_membarrier` that works on older Linux kernels and
+/// x86/x86-64 systems, or `None` if a security policy denies it.
+static BARRIER: SpinOnce<Option<Barrier>> = SpinOnce::new();
+
+/// A variant of `BARRIER` that discards its page instead of protecting it.
+static DONTNEED_BARRIER: SpinOnce<Option<Barrier>> = SpinOnce::new();
+
+/// Returns the barrier for `method`, creating it on first use, or `None` if a security
+/// policy denies it.
+fn try_barrier_for(method: Method) -> Option<&'static Barrier> {
+    let barrier = match method {
+        Method::Protect => BARRIER.get_or_init(|| unsafe { Barrier::new(Method::Protect) }),
+        Method::Dontneed => {
+            DONTNEED_BARRIER.get_or_init(|| unsafe { Barrier::new(Method::Dontneed) })
+        }
+    };
+    barrier.as_ref()
+}
+
+/// Returns the barrier for `method`, which `is_permitted()` must have checked.
+fn barrier_for(method: Method) -> &'static Barrier {
+    match try_barrier_for(method) {
+        Some(barrier) => barrier,
+        None => unreachable!(),
+    }
+}
+
+/// Replaces the barriers inherited from the parent in the child of a `fork()`.
+///
+/// The child doesn't inherit the lock on the pages, which stay shared with the parent
+/// until either writes to them, and their mutexes may have been held by a thread of the
+/// parent that doesn't exist in the child. The old pages are unmapped, and fresh ones are
+/// mapped and locked for the barriers that had been created.
+///
+/// # Safety
+///
+/// No other thread may use the barriers meanwhile.
+pub unsafe fn reinit_after_fork() {
+    for &(cell, method) in &[
+        (&BARRIER, Method::Protect),
+        (&DONTNEED_BARRIER, Method::Dontneed),
+    ] {
+        let created = match cell.get() {
+            Some(Some(barrier)) => {
+                libc::munmap(barrier.page as *mut libc::c_void, barrier.page_size);
+                true
+            }
+            _ => false,
+        };
+        cell.reset();
+        if created {
+            try_barrier_for(method);
+        }
+    }
+}
+
+/// Returns the dedicated page of the barrier for `method`, if it has been created.
+pub fn mapping(method: Method) -> Option<Mapping> {
+    let barrier = match method {
+        Method::Protect => BARRIER.get(),
+        Method::Dontneed => DONTNEED_BARRIER.get(),
+    }?
+    .as_ref()?;
+    Some(Mapping {
+        address: barrier.page,
+        len: barrier.page_size,
+    })
+}
+
+/// Returns whether `mlock()` locked the page of the `Method::Protect` barrier, if it has
+/// been created.
+pub fn page_locked() -> Option<bool> {
+    Some(BARRIER.get()?.as_ref()?.locked)
+}
+
+/// Returns `true` if the `mprotect`-based trick is supported.
+///
+/// Besides x86 and x86-64, it is supported on riscv64 Linux, which has no broadcast TLB
+/// invalidation like arm64: the kernel flushes the TLBs of the other harts running the
+/// process with a remote `SFENCE.VMA`, either by interrupting each of them or by asking
+/// the SBI firmware to, which does the same. The interrupted hart acknowledges the flush
+/// with a release store or after a fence, which orders its earlier accesses just like the
+/// IPIs do on x86. Unlike on x86, Linux flushes on every change of protections there, so
+/// the access that dirties the page before the flush isn't what forces it, but it is
+/// still made. Harts without hardware updating of the accessed and dirty bits take a
+/// page fault on that access when the kernel cleared them, which only makes it slower.
+pub fn is_supported() -> bool {
+    cfg!(target_arch = "x86")
+        || cfg!(target_arch = "x86_64")
+        || cfg!(all(target_os = "linux", target_arch = "riscv64"))
+}
+
+/// Returns whether the security policies permit the barriers to change the protections of
+/// their pages, creating them if needed.
+///
+/// PaX `MPROTECT` restrictions or SELinux policies may deny `mprotect()` with `EACCES` or
+/// `EPERM`, even for pages that are never executable. The pages are checked along with
+/// the strategy, so that `heavy()` falls back to another one rather than aborting.
+pub fn is_permitted() -> bool {
+    try_barrier_for(Method::Protect).is_some()
+}
+
+/// Checks that the kernel enforces page protections the way the `mprotect`-based trick
+/// assumes: a page is accessible while it is read + write, and inaccessible once its
+/// protections are revoked.
+///
+/// Rather than faulting and recovering from `SIGSEGV`, the accesses are made by the kernel
+/// on our behalf, by writing from the page to a pipe, which fails with `EFAULT` if the
+/// page is inaccessible.
+pub fn self_test() -> bool {
+    unsafe {
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE);
+        if page_size <= 0 {
+            return false;
+        }
+        let page_size = page_size as libc::size_t;
+
+        let mut fds = [0 as libc::c_int; 2];
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) != 0 {
+            return false;
+        }
+        let page = libc::mmap(
+            ptr::null_mut(),
+            page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1 as libc::c_int,
+            0 as libc::off_t,
+        );
+
+        let passed = page != libc::MAP_FAILED && {
+            (*(page as *const atomic::AtomicUsize)).store(1, atomic::Ordering::SeqCst);
+            let accessible = libc::write(fds[1], page, 1) == 1;
+
+            let revoked = libc::mprotect(page, page_size, libc::PROT_NONE) == 0;
+            let inaccessible = libc::write(fds[1], page, 1) == -1 && errno() == libc::EFAULT;
+
+            let restored = libc::mprotect(page, page_size, libc::PROT_READ | libc::PROT_WRITE) == 0;
+            let reaccessible = libc::write(fds[1], page, 1) == 1;
+
+            accessible && revoked && inaccessible && restored && reaccessible
+        };
+
+        if page != libc::MAP_FAILED {
+            libc::munmap(page, page_size);
+        }
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+        passed
+    }
+}
+
+/// Measures how long a few barriers take with `method`, in nanoseconds.
+pub fn measure(method: Method) -> u64 {
+    const ROUNDS: usize = 16;
+
+    // Warm up, so that the page and the mutex are set up before measuring.
+    barrier(method);
+
+    let start = now();
+    for _ in 0..ROUNDS {
+        barrier(method);
+    }
+    now() - start
+}
+
+/// Returns the method that issues barriers faster on the current machine.
+///
+/// Only Linux discards a page on `MADV_DONTNEED`, so the other systems always use
+/// `Method::Protect`.
+pub fn fastest_method() -> Method {
+    if cfg!(target_os = "linux") && measure(Method::Dontneed) < measure(Method::Protect) {
+        Method::Dontneed
+    } else {
+        Method::Protect
+    }
+}
+
+/// Issues a barrier with `method` and checks that it had the expected effect on the page.
+#[cfg(feature = "paranoid")]
+pub fn round_trip(method: Method) -> bool {
+    barrier_for(method).round_trip()
+}
+
+/// Executes a heavy `mprotect`-based barrier, aborting if a system call fails.
+#[inline]
+pub fn barrier(method: Method) {
+    fatal_assert!(try_barrier(method).is_ok());
+}
+
+/// Executes a heavy `mprotect`-based barrier, returning the error of the first system call
+/// that failed, if any.
+#[inline]
+pub fn try_barrier(method: Method) -> Result<(), BarrierError> {
+    barrier_for(method).barrier()
+}
+
+/// Executes a heavy `mprotect`-based barrier, unless another thread holds the barrier for
+/// longer than `timeout`. Returns `true` if the barrier was issued, and aborts if a system
+/// call fails.
+pub fn barrier_timeout(method: Method, timeout: Duration) -> bool {
+    let issued = barrier_for(method).barrier_until(&deadline_after(timeout));
+    fatal_assert!(issued.is_ok());
+    issued == Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "memfd-mprotect"))]
+    fn protect_page_is_sealed_memfd() {
+        if !is_supported() {
+            return;
+        }
+        barrier(Method::Protect);
+
+        let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+        let sealed = super::super::super::held_fds::get()
+            .iter()
+            .any(|&fd| unsafe { libc::fcntl(fd, libc::F_GET_SEALS) } & seals == seals);
+        assert!(sealed);
+    }
+
+    #[test]
+    fn every_grant_revokes_access() {
+        if !is_supported() {
+            return;
+        }
+        unsafe {
+            let mut barrier = Barrier::new(Method::Protect).unwrap();
+            let mut fds = [0 as libc::c_int; 2];
+            assert_eq!(
+                libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK),
+                0
+            );
+            for &grant in &[Grant::ReadWrite, Grant::Read] {
+                barrier.grant = grant;
+                barrier.flush().unwrap();
+                let page = barrier.page as *const libc::c_void;
+                assert_eq!(libc::write(fds[1], page, 1), -1, "{:?}", grant);
+                assert_eq!(errno(), libc::EFAULT);
+            }
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    /// Checks that the barrier covers the whole page, whatever its size, by accessing its
+    /// last byte rather than its first word, which is all the barrier itself touches.
+    #[test]
+    fn barrier_covers_system_page() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as libc::size_t;
+        unsafe {
+            let protect = Barrier::new(Method::Protect).unwrap();
+            assert_eq!(protect.page_size, page_size);
+            assert_eq!(protect.page % page_size, 0);
+            let mut fds = [0 as libc::c_int; 2];
+            assert_eq!(
+                libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK),
+                0
+            );
+            protect.flush().unwrap();
+            let last = (protect.page + page_size - 1) as *const libc::c_void;
+            assert_eq!(libc::write(fds[1], last, 1), -1);
+            assert_eq!(errno(), libc::EFAULT);
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+
+            if cfg!(target_os = "linux") {
+                let dontneed = Barrier::new(Method::Dontneed).unwrap();
+                assert_eq!(dontneed.page_size, page_size);
+                let last = (dontneed.page + page_size - 1) as *mut u8;
+                ptr::write_volatile(last, 1);
+                dontneed.flush().unwrap();
+                assert_eq!(ptr::read_volatile(last), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn lock_survives_rejected_type() {
+        unsafe extern "C" fn reject(
+            _attr: *mut libc::pthread_mutexattr_t,
+            _kind: libc::c_int,
+        ) -> libc::c_int {
+            libc::EINVAL
+        }
+
+        unsafe {
+            let lock = new_lock(reject);
+            assert_eq!(libc::pthread_mutex_lock(lock.get()), 0);
+            assert_eq!(libc::pthread_mutex_trylock(lock.get()), libc::EBUSY);
+            assert_eq!(libc::pthread_mutex_unlock(lock.get()), 0);
+        }
+    }
+
+    #[test]
+    fn barrier_timeout_expires() {
+        let (locked_sender, locked) = mpsc::channel();
+        let (unlock, unlock_receiver) = mpsc::channel::<()>();
+        let holder = thread::spawn(move || unsafe {
+            let barrier = barrier_for(Method::Protect);
+            assert_eq!(libc::pthread_mutex_lock(barrier.lock.get()), 0);
+            locked_sender.send(()).unwrap();
+            unlock_receiver.recv().unwrap();
+            assert_eq!(libc::pthread_mutex_unlock(barrier.lock.get()), 0);
+        });
+
+        locked.recv().unwrap();
+        assert!(!barrier_timeout(Method::Protect, Duration::from_millis(10)));
+        unlock.send(()).unwrap();
+        holder.join().unwrap();
+
+        assert!(barrier_timeout(Method::Protect, Duration::from_secs(10)));
+    }
+
+    /// The trick is available on x86 and x86-64 on every system the module is built for,
+    /// and on riscv64 Linux.
+    #[test]
+    fn supported_where_built() {
+        assert_eq!(
+            is_supported(),
+            cfg!(any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                all(target_os = "linux", target_arch = "riscv64"),
+            ))
+        );
+        if is_supported() {
+            assert!(self_test());
+            barrier(fastest_method());
+        }
+    }
+
+    #[test]
+    fn page_protections_are_enforced() {
+        assert!(self_test());
+    }
+
+    #[test]
+    #[cfg(feature = "coalesce-mprotect")]
+    fn coalesces_covered_requests() {
+        let barrier = unsafe { Barrier::new(Method::Protect).unwrap() };
+        let requested = barrier.generation.load(atomic::Ordering::SeqCst);
+
+        // A barrier started after the request covers it.
+        barrier.barrier().unwrap();
+        unsafe {
+            assert_eq!(libc::pthread_mutex_lock(barrier.lock.get()), 0);
+            barrier.barrier_locked(requested).unwrap();
+        }
+        assert_eq!(
+            barrier.generation.load(atomic::Ordering::SeqCst),
+            requested + 1
+        );
+
+        barrier.barrier().unwrap();
+        assert_eq!(
+            barrier.generation.load(atomic::Ordering::SeqCst),
+            requested + 2
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use core::str;
+
+/// The number of CPUs that are told apart. Threads on CPUs beyond them are counted as if
+/// each occupied a CPU of its own.
+pub const MAX_CPUS: usize = 1024;
+
+const TASKS: &[u8] = b"/proc/self/task/";
+const STAT: &[u8] = b"/stat\0";
+
+/// Returns the CPU the thread whose `stat` file is at the null-terminated `path` last ran on.
+fn thread_cpu(path: &[u8]) -> Option<usize> {
+    let mut buf = [0u8; 1024];
+    let len = unsafe {
+        let fd = libc::open(
+            path.as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        );
+        if fd < 0 {
+            return None;
+        }
+        let len = libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        libc::close(fd);
+        len
+    };
+    if len <= 0 {
+        return None;
+    }
+    let stat = &buf[..len as usize];
+
+    // The thread name in parentheses may contain spaces, so the fields are counted from the
+    // closing parenthesis. `processor` is the 39th field, and the one after the name is the
+    // 3rd.
+    let fields = &stat[stat.iter().rposition(|&b| b == b')')? + 1..];
+    let processor = str::from_utf8(fields)
+        .ok()?
+        .split_whitespace()
+        .nth(39 - 3)?;
+    processor.parse().ok()
+}
+
+/// Returns the CPU the thread of the process with ID `tid` last ran on, or `None` if it
+/// exited.
+#[cfg(all(feature = "std", not(feature = "force-fence")))]
+pub fn task_cpu(tid: libc::pid_t) -> Option<usize> {
+    // Builds `/proc/self/task/<tid>/stat`. Thread IDs have at most 10 digits.
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    let mut rest = tid as u32;
+    loop {
+        digits[len] = b'0' + (rest % 10) as u8;
+        len += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    let mut path = [0u8; 64];
+    path[..TASKS.len()].copy_from_slice(TASKS);
+    for (dst, &src) in path[TASKS.len()..]
+        .iter_mut()
+        .zip(digits[..len].iter().rev())
+    {
+        *dst = src;
+    }
+    path[TASKS.len() + len..TASKS.len() + len + STAT.len()].copy_from_slice(STAT);
+    thread_cpu(&path)
+}
+
+/// Returns the number of distinct CPUs the threads of the process last ran on.
+#[cfg(feature = "diagnostics")]
+pub fn occupied_cpus() -> Option<usize> {
+    let mut cpus = [0u64; MAX_CPUS / 64];
+    let mut others = 0;
+    unsafe {
+        let dir = libc::opendir(b"/proc/self/task\0".as_ptr() as *const libc::c_char);
+        if dir.is_null() {
+            return None;
+        }
+        loop {
+            let entry = libc::readdir(dir);
+            if entry.is_null() {
+                break;
+            }
+            let name = &(*entry).d_name;
+            let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            if len == 0 || name[0] == b'.' as libc::c_char {
+                continue;
+            }
+
+            // Builds `/proc/self/task/<tid>/stat`. Thread IDs have at most 10 digits.
+            let mut path = [0u8; 64];
+            if TASKS.len() + len + STAT.len() > path.len() {
+                continue;
+            }
+            path[..TASKS.len()].copy_from_slice(TASKS);
+            for (dst, &src) in path[TASKS.len()..].iter_mut().zip(&name[..len]) {
+                *dst = src as _;
+            }
+            path[TASKS.len() + len..TASKS.len() + len + STAT.len()].copy_from_slice(STAT);
+
+            // Threads may exit while they're being enumerated.
+            match thread_cpu(&path) {
+                Some(cpu) if cpu < MAX_CPUS => cpus[cpu / 64] |= 1 << (cpu % 64),
+                Some(_) => others += 1,
+                None => {}
+            }
+        }
+        libc::closedir(dir);
+    }
+    Some(
+        cpus.iter()
+            .map(|word| word.count_ones() as usize)
+            .sum::<usize>()
+            + others,
+    )
+}
@@ -0,0 +1,215 @@
+//! The decisions behind selecting and downgrading the Linux strategy, factored out of the code that
+//! probes the system so that they can be tested and fuzzed on their own.
+
+use super::{Backend, Config};
+
+/// A choice between seven strategies for process-wide barrier on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Use the `membarrier` system call.
+    Membarrier,
+    /// Use the legacy shared command of the `membarrier` system call, which is slow but available
+    /// on kernels that predate the expedited commands.
+    SharedMembarrier,
+    /// Use the `mprotect`-based trick.
+    Mprotect,
+    /// Use the `madvise(MADV_DONTNEED)`-based variant of the `mprotect` trick.
+    Madvise,
+    /// Use a realtime signal sent to every thread.
+    Signal,
+    /// Use perf events pinned to every CPU.
+    Perf,
+    /// Use `SeqCst` fences.
+    Fallback,
+}
+
+/// What the selection may find out about the system.
+///
+/// Probing may have side effects, e.g. registering the process for membarrier, so the selection
+/// calls each method at most once.
+pub trait Probe {
+    /// Returns whether private expedited membarrier is supported and registered.
+    fn membarrier_usable(&mut self) -> bool;
+
+    /// Returns whether the legacy shared membarrier command, now called global, is supported.
+    fn shared_membarrier_usable(&mut self) -> bool;
+
+    /// Returns whether the `mprotect`-based trick is supported, passes its self-test, and is
+    /// permitted by the security policies.
+    fn mprotect_usable(&mut self) -> bool;
+
+    /// Returns the faster variant of the `mprotect`-based trick, which must be usable.
+    fn mprotect_fastest(&mut self) -> Strategy;
+
+    /// Returns whether the signal-based barrier is supported, i.e. its signal is free and the
+    /// threads of the process can be listed.
+    fn signal_usable(&mut self) -> bool;
+
+    /// Returns whether the signal-based barrier is faster than `than`, which must be a usable
+    /// variant of the `mprotect`-based trick. The signal-based barrier must be usable, too.
+    fn signal_faster(&mut self, than: Strategy) -> bool;
+
+    /// Returns whether the perf-event-based barrier is supported, i.e. it is enabled and a perf
+    /// event could be opened on every CPU.
+    fn perf_usable(&mut self) -> bool;
+}
+
+/// Remembers the answers of a `Probe`, so that it is called at most once.
+struct Cached<'a, P: 'a> {
+    probe: &'a mut P,
+    membarrier_usable: Option<bool>,
+    mprotect_usable: Option<bool>,
+    signal_usable: Option<bool>,
+    perf_usable: Option<bool>,
+    shared_membarrier_usable: Option<bool>,
+}
+
+impl<'a, P: Probe> Cached<'a, P> {
+    fn new(probe: &'a mut P) -> Cached<'a, P> {
+        Cached {
+            probe,
+            membarrier_usable: None,
+            mprotect_usable: None,
+            signal_usable: None,
+            perf_usable: None,
+            shared_membarrier_usable: None,
+        }
+    }
+
+    fn membarrier_usable(&mut self) -> bool {
+        match self.membarrier_usable {
+            Some(usable) => usable,
+            None => {
+                let usable = self.probe.membarrier_usable();
+                self.membarrier_usable = Some(usable);
+                usable
+            }
+        }
+    }
+
+    fn mprotect_usable(&mut self, config: &Config) -> bool {
+        if !config.allow_mprotect {
+            return false;
+        }
+        match self.mprotect_usable {
+            Some(usable) => usable,
+            None => {
+                let usable = self.probe.mprotect_usable();
+                self.mprotect_usable = Some(usable);
+                usable
+            }
+        }
+    }
+
+    fn signal_usable(&mut self, config: &Config) -> bool {
+        if !config.allow_signals {
+            return false;
+        }
+        match self.signal_usable {
+            Some(usable) => usable,
+            None => {
+                let usable = self.probe.signal_usable();
+                self.signal_usable = Some(usable);
+                usable
+            }
+        }
+    }
+
+    fn perf_usable(&mut self) -> bool {
+        match self.perf_usable {
+            Some(usable) => usable,
+            None => {
+                let usable = self.probe.perf_usable();
+                self.perf_usable = Some(usable);
+                usable
+            }
+        }
+    }
+
+    fn shared_membarrier_usable(&mut self) -> bool {
+        match self.shared_membarrier_usable {
+            Some(usable) => usable,
+            None => {
+                let usable = self.probe.shared_membarrier_usable();
+                self.shared_membarrier_usable = Some(usable);
+                usable
+            }
+        }
+    }
+
+    /// Returns the fastest of the `mprotect`-based trick and the signal-based barrier, or `None`
+    /// if neither is usable. Without `Config::benchmark`, nothing is measured: the trick changes
+    /// the protections of its page, and the signal-based barrier only replaces it if it is not
+    /// usable.
+    fn interrupting(&mut self, config: &Config) -> Option<Strategy> {
+        let mprotect = if !self.mprotect_usable(config) {
+            None
+        } else if config.benchmark {
+            Some(self.probe.mprotect_fastest())
+        } else {
+            Some(Strategy::Mprotect)
+        };
+        if !self.signal_usable(config) {
+            return mprotect;
+        }
+        match mprotect {
+            Some(mprotect) if !config.benchmark || !self.probe.signal_faster(mprotect) => {
+                Some(mprotect)
+            }
+            _ => Some(Strategy::Signal),
+        }
+    }
+
+    /// Returns the strategy that interrupts every CPU or thread running the process, i.e. the
+    /// fastest interrupting one, or the perf-event-based barrier as a last resort. Returns `None`
+    /// if none is usable.
+    fn process_wide(&mut self, config: &Config) -> Option<Strategy> {
+        match self.interrupting(config) {
+            Some(strategy) => Some(strategy),
+            None if self.perf_usable() => Some(Strategy::Perf),
+            None => None,
+        }
+    }
+}
+
+/// Selects the strategy: the preferred one if it is available, and otherwise the first available
+/// one of `sys_membarrier()`, the `mprotect`-based trick or the signal-based barrier, whichever is
+/// faster with `Config::benchmark`, the perf-event-based barrier, the legacy shared
+/// `sys_membarrier()`, and fences.
+pub fn select<P: Probe>(config: &Config, probe: &mut P) -> Strategy {
+    let mut probe = Cached::new(probe);
+
+    match config.prefer {
+        Some(Backend::Membarrier) if probe.membarrier_usable() => return Strategy::Membarrier,
+        Some(Backend::Mprotect) if probe.mprotect_usable(config) => return Strategy::Mprotect,
+        Some(Backend::Madvise) if probe.mprotect_usable(config) => return Strategy::Madvise,
+        Some(Backend::Signal) if probe.signal_usable(config) => return Strategy::Signal,
+        Some(Backend::PerfEvent) if probe.perf_usable() => return Strategy::Perf,
+        Some(Backend::SharedMembarrier) if probe.shared_membarrier_usable() => {
+            return Strategy::SharedMembarrier
+        }
+        Some(Backend::Fence) => return Strategy::Fallback,
+        _ => {}
+    }
+
+    if probe.membarrier_usable() {
+        Strategy::Membarrier
+    } else {
+        match probe.process_wide(config) {
+            Some(strategy) => strategy,
+            None if probe.shared_membarrier_usable() => Strategy::SharedMembarrier,
+            None => Strategy::Fallback,
+        }
+    }
+}
+
+/// Selects the strategy to switch to once `sys_membarrier()`, expedited or shared, or the
+/// signal-based barrier starts failing, or `None` if the process has to be aborted.
+///
+/// `light()` is a compiler fence for the `mprotect`-based trick, the signal-based barrier, and the
+/// perf-event-based barrier as well, so one of their barriers covers the failed one. A fence can't
+/// cover the `light()` calls that relied on the failed barrier, though, so there is no downgrading
+/// to fences.
+pub fn downgrade<P: Probe>(config: &Config, probe: &mut P) -> Option<Strategy> {
+    Cached::new(probe).process_wide(config)
+}
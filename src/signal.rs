@@ -0,0 +1,755 @@
+//! The signal-based barrier, which sends a realtime signal to every thread that may issue `light()`
+//! and waits until each of them has issued a barrier in the signal handler. Linux uses it if
+//! `Config::allow_signals` is set, and the other Unix systems with the `signal-barrier` feature.
+//!
+//! On Linux, it signals every thread listed in `/proc/self/task`, so threads don't register, and
+//! each signal carries the generation of its round as its value. Elsewhere, a thread registers in
+//! a fixed table of slots with its first `light()`, without allocating or locking, and leaves it
+//! when it exits, and the barrier writes the generation of its round to the slot of a thread
+//! before it signals the thread. Either way, the barrier only holds a mutex against other
+//! barriers, so starting and exiting threads never wait for it while it waits for the
+//! acknowledgments.
+//!
+//! A thread that starts, or registers, while the barrier signals the threads only runs after the
+//! barrier's initial fence, or issues a fence afterwards, so it needs no signal. If the threads
+//! take too long, e.g. as one exits with the signal pending, the barrier starts over, with a new
+//! generation that invalidates earlier acknowledgments.
+//!
+//! The signal is the last realtime one, as the libcs reserve the first ones. It is only used if no
+//! handler is installed for it yet, and the barrier is unsupported otherwise.
+
+use core::cell::UnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::spin_once::SpinOnce;
+use super::{BarrierError, Syscall};
+
+#[cfg(all(target_os = "linux", feature = "diagnostics"))]
+pub use self::threads::for_each_thread;
+
+/// Why `barrier()` didn't issue a barrier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Missed {
+    /// It would have taken longer than the timeout.
+    Timeout,
+    /// A thread couldn't be listed or signaled, failing with this `errno`, so the barrier isn't
+    /// supported after all.
+    Unsupported(i32),
+}
+
+struct Lock(UnsafeCell<libc::pthread_mutex_t>);
+
+unsafe impl Sync for Lock {}
+
+/// Serializes the barriers, which share `GENERATION` and `ACKS`. Starting, registering, and
+/// exiting threads never take it.
+static LOCK: Lock = Lock(UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER));
+
+/// The generation of the latest round of signals. It is only modified with `LOCK` held.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of threads that acknowledged the latest round in the low half, tagged with the
+/// round's generation in the high half, so that a late acknowledgment of an earlier round is
+/// never counted.
+static ACKS: AtomicUsize = AtomicUsize::new(0);
+
+const TAG_SHIFT: u32 = (mem::size_of::<usize>() * 4) as u32;
+const COUNT_MASK: usize = (1 << TAG_SHIFT) - 1;
+
+/// Whether the signal handler is installed, which only happens if the signal is free.
+static INSTALLED: SpinOnce<bool> = SpinOnce::new();
+
+/// Returns the signal, or `None` if the system has no realtime signals.
+fn signal() -> Option<libc::c_int> {
+    cfg_if! {
+        if #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "illumos",
+            target_os = "solaris",
+            target_os = "hurd",
+        ))] {
+            Some(libc::SIGRTMAX())
+        } else if #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))] {
+            // The `SIGRTMAX` of `<sys/signal.h>`, which `libc` doesn't declare.
+            Some(126)
+        } else if #[cfg(target_os = "netbsd")] {
+            Some(63)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the monotonic time in nanoseconds.
+fn now() -> u64 {
+    let mut ts = MaybeUninit::<libc::timespec>::uninit();
+    unsafe {
+        fatal_assert!(libc::clock_gettime(libc::CLOCK_MONOTONIC, ts.as_mut_ptr()) == 0);
+        let ts = ts.assume_init();
+        ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+    }
+}
+
+/// Acknowledges a round of the barrier after a full barrier.
+extern "C" fn handle(
+    _signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    fence(Ordering::SeqCst);
+
+    let generation = match threads::received(info) {
+        Some(generation) => generation,
+        None => return,
+    };
+    let tag = generation << TAG_SHIFT;
+    let mut acks = ACKS.load(Ordering::SeqCst);
+    while acks & !COUNT_MASK == tag {
+        match ACKS.compare_exchange_weak(acks, acks + 1, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return,
+            Err(current) => acks = current,
+        }
+    }
+}
+
+/// Returns whether the signal has no handler yet.
+fn is_free() -> bool {
+    let signal = match signal() {
+        Some(signal) => signal,
+        None => return false,
+    };
+    unsafe {
+        let mut previous = MaybeUninit::<libc::sigaction>::uninit();
+        libc::sigaction(signal, ptr::null(), previous.as_mut_ptr()) == 0
+            && previous.assume_init().sa_sigaction == libc::SIG_DFL
+    }
+}
+
+/// Installs the signal handler, unless the signal is already in use or the threads can't be
+/// signaled.
+fn install() -> bool {
+    *INSTALLED.get_or_init(|| unsafe {
+        let signal = match signal() {
+            Some(signal) if is_free() && threads::can_signal() => signal,
+            _ => return false,
+        };
+
+        let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) = handle;
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, ptr::null_mut()) == 0
+    })
+}
+
+/// Returns `true` if the signal-based barrier is supported, installing its handler.
+///
+/// Whether the threads can be signaled is checked again on every call, so that a barrier that
+/// failed, e.g. as a seccomp filter started denying the signal, isn't selected again.
+pub fn is_supported() -> bool {
+    install() && threads::can_signal()
+}
+
+/// Returns `true` if the signal-based barrier is supported, without installing its handler.
+pub fn is_available() -> bool {
+    let free = match INSTALLED.get() {
+        Some(&installed) => installed,
+        None => is_free(),
+    };
+    free && threads::can_signal()
+}
+
+/// Registers the current thread for the barrier, unless it already is or threads don't
+/// register, as on Linux. Returns `false` if the barrier can't reach the thread, in which case
+/// `light()` has to issue a `SeqCst` fence.
+#[inline]
+pub fn register() -> bool {
+    threads::register()
+}
+
+/// Returns the number of threads the barrier signals, or `None` if they can't be listed.
+pub fn thread_count() -> Option<usize> {
+    threads::count()
+}
+
+/// Resets the mutex serializing the barriers and forgets the threads of the parent in the
+/// child of a `fork()`, where only the current thread exists.
+///
+/// # Safety
+///
+/// No other thread may issue barriers meanwhile.
+pub unsafe fn reinit_after_fork() {
+    *LOCK.0.get() = libc::PTHREAD_MUTEX_INITIALIZER;
+    threads::reinit_after_fork();
+}
+
+/// Locks the mutex serializing the barriers, unless another barrier holds it until `deadline`.
+fn lock(deadline: Option<u64>) -> Result<(), Missed> {
+    unsafe {
+        match deadline {
+            None => fatal_assert!(libc::pthread_mutex_lock(LOCK.0.get()) == 0),
+            Some(deadline) => {
+                while libc::pthread_mutex_trylock(LOCK.0.get()) != 0 {
+                    if now() >= deadline {
+                        return Err(Missed::Timeout);
+                    }
+                    libc::sched_yield();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Issues a full barrier on every thread, by signaling it and waiting until its handler
+/// acknowledges, unless it would take longer than `timeout`.
+///
+/// The calling thread issues a `SeqCst` fence instead of signaling itself. The handler must be
+/// installed.
+pub fn barrier(timeout: Option<Duration>) -> Result<(), Missed> {
+    /// How long the first round waits for the acknowledgments, in nanoseconds. Every further
+    /// round waits twice as long as the previous one.
+    const PATIENCE: u64 = 1_000_000;
+
+    let deadline = timeout.map(|timeout| {
+        now()
+            .saturating_add(timeout.as_secs().saturating_mul(1_000_000_000))
+            .saturating_add(u64::from(timeout.subsec_nanos()))
+    });
+    lock(deadline)?;
+    fence(Ordering::SeqCst);
+
+    let mut patience = PATIENCE;
+    let issued = 'rounds: loop {
+        let generation = GENERATION.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        let tag = generation << TAG_SHIFT;
+        ACKS.store(tag, Ordering::SeqCst);
+        let sent = match threads::signal_all(generation) {
+            Ok(sent) => sent,
+            Err(errno) => break Err(Missed::Unsupported(errno)),
+        };
+
+        let start = now();
+        while ACKS.load(Ordering::SeqCst) != tag | sent {
+            match deadline {
+                Some(deadline) if now() >= deadline => break 'rounds Err(Missed::Timeout),
+                _ => {}
+            }
+            if now() - start > patience {
+                patience = patience.saturating_mul(2);
+                continue 'rounds;
+            }
+            unsafe { libc::sched_yield() };
+        }
+        break Ok(());
+    };
+
+    fence(Ordering::SeqCst);
+    unsafe { fatal_assert!(libc::pthread_mutex_unlock(LOCK.0.get()) == 0) };
+    issued
+}
+
+/// Issues a full barrier on every thread like `barrier(None)`, returning the error of the
+/// thread that couldn't be listed or signaled.
+pub fn try_barrier() -> Result<(), BarrierError> {
+    match barrier(None) {
+        Ok(()) => Ok(()),
+        Err(Missed::Unsupported(errno)) => Err(BarrierError::new(Syscall::Signal, errno)),
+        // Only a barrier with a timeout times out.
+        Err(Missed::Timeout) => Err(BarrierError::new(Syscall::Signal, libc::ETIMEDOUT)),
+    }
+}
+
+/// Measures how long a few barriers take, in nanoseconds.
+pub fn measure() -> u64 {
+    const ROUNDS: usize = 16;
+
+    let start = now();
+    for _ in 0..ROUNDS {
+        let _ = barrier(None);
+    }
+    now() - start
+}
+
+/// The threads listed in `/proc/self/task`, each of which is queued the signal with the
+/// generation of the round as its value.
+#[cfg(target_os = "linux")]
+mod threads {
+    use core::mem;
+
+    /// The fields of a `siginfo_t` that the kernel fills in for a queued signal.
+    #[repr(C)]
+    struct Queued {
+        /// `si_signo`, `si_errno`, and `si_code`, which `libc::siginfo_t` names.
+        _header: [libc::c_int; 3],
+        /// The `_rt` member of the union, which is aligned like a pointer.
+        fields: QueuedFields,
+    }
+
+    #[repr(C)]
+    struct QueuedFields {
+        pid: libc::pid_t,
+        uid: libc::uid_t,
+        value: *mut libc::c_void,
+    }
+
+    /// The offsets of `d_reclen`, the entry's length, and of `d_name` in a `linux_dirent64`.
+    const RECLEN_OFFSET: usize = 16;
+    const NAME_OFFSET: usize = 19;
+
+    /// Calls `f` with the ID of every thread of the process. Returns `false` if the threads
+    /// can't be listed.
+    ///
+    /// The threads are read from `/proc/self/task` with `getdents64`, rather than `readdir`,
+    /// so that listing them never allocates.
+    pub fn for_each_thread<F: FnMut(libc::pid_t)>(mut f: F) -> bool {
+        unsafe {
+            let fd = libc::open(
+                b"/proc/self/task\0".as_ptr() as *const libc::c_char,
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            );
+            if fd < 0 {
+                return false;
+            }
+
+            // Aligned for the entries' 64-bit fields.
+            let mut buf = [0u64; 512];
+            let listed = loop {
+                let len = libc::syscall(
+                    libc::SYS_getdents64,
+                    fd,
+                    buf.as_mut_ptr(),
+                    mem::size_of_val(&buf),
+                );
+                if len <= 0 {
+                    break len == 0;
+                }
+
+                let entries = buf.as_ptr() as *const u8;
+                let mut offset = 0;
+                while offset < len as usize {
+                    let entry = entries.add(offset);
+                    let mut name = entry.add(NAME_OFFSET);
+                    let mut tid: libc::pid_t = 0;
+                    while (*name).is_ascii_digit() {
+                        tid = tid * 10 + libc::pid_t::from(*name - b'0');
+                        name = name.add(1);
+                    }
+                    // Skip `.` and `..`.
+                    if tid > 0 {
+                        f(tid);
+                    }
+                    offset += (entry.add(RECLEN_OFFSET) as *const u16).read() as usize;
+                }
+            };
+            libc::close(fd);
+            listed
+        }
+    }
+
+    /// Queues `signal` for thread `tid` of this process with `generation` as its value.
+    /// Returns whether it was queued, `Ok(false)` if the thread has exited, and the `errno`
+    /// if the thread can't be signaled.
+    fn queue(
+        signal: libc::c_int,
+        pid: libc::pid_t,
+        tid: libc::pid_t,
+        generation: usize,
+    ) -> Result<bool, i32> {
+        unsafe {
+            let mut info: libc::siginfo_t = mem::zeroed();
+            info.si_signo = signal;
+            info.si_code = libc::SI_QUEUE;
+            let queued = &mut *(&mut info as *mut libc::siginfo_t as *mut Queued);
+            queued.fields.pid = pid;
+            queued.fields.uid = libc::getuid();
+            queued.fields.value = generation as *mut libc::c_void;
+
+            loop {
+                let ret = libc::syscall(libc::SYS_rt_tgsigqueueinfo, pid, tid, signal, &info);
+                if ret == 0 {
+                    return Ok(true);
+                }
+                match *libc::__errno_location() {
+                    libc::ESRCH => return Ok(false),
+                    // The queue of pending signals is full, so wait for it to drain.
+                    libc::EAGAIN => {
+                        libc::sched_yield();
+                    }
+                    // E.g. `EPERM` or `ENOSYS` from a seccomp filter.
+                    errno => return Err(errno),
+                }
+            }
+        }
+    }
+
+    /// Returns whether the barrier is supported, installing its handler.
+    pub fn register() -> bool {
+        super::install()
+    }
+
+    /// Returns whether the threads can be listed and signaled, by queueing the null signal,
+    /// which is only checked, for the current thread.
+    pub fn can_signal() -> bool {
+        let (pid, own) = unsafe {
+            (
+                libc::getpid(),
+                libc::syscall(libc::SYS_gettid) as libc::pid_t,
+            )
+        };
+        for_each_thread(|_| {}) && queue(0, pid, own, 0) == Ok(true)
+    }
+
+    /// Returns the round that queued the signal that `info` describes.
+    pub fn received(info: *mut libc::siginfo_t) -> Option<usize> {
+        unsafe {
+            let queued = &*(info as *const Queued);
+            if (*info).si_code == libc::SI_QUEUE && queued.fields.pid == libc::getpid() {
+                Some(queued.fields.value as usize)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Queues the signal for every thread but the current one in the round `generation`, and
+    /// returns for how many threads it did, or the `errno` if a thread can't be listed or
+    /// signaled.
+    pub fn signal_all(generation: usize) -> Result<usize, i32> {
+        let signal = super::signal().ok_or(libc::ENOSYS)?;
+        let pid = unsafe { libc::getpid() };
+        let own = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        let mut sent = 0;
+        let mut failed = Ok(());
+        let listed = for_each_thread(|tid| {
+            if tid == own || failed.is_err() {
+                return;
+            }
+            match queue(signal, pid, tid, generation) {
+                Ok(true) => sent += 1,
+                Ok(false) => {}
+                Err(errno) => failed = Err(errno),
+            }
+        });
+        if !listed {
+            return Err(unsafe { *libc::__errno_location() });
+        }
+        failed.map(|()| sent)
+    }
+
+    /// Returns the number of threads of the process, or `None` if they can't be listed.
+    pub fn count() -> Option<usize> {
+        let mut count = 0;
+        if for_each_thread(|_| count += 1) {
+            Some(count)
+        } else {
+            None
+        }
+    }
+
+    /// Does nothing, as the threads are listed anew by every barrier.
+    pub fn reinit_after_fork() {}
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn lists_current_thread() {
+            let own = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+            let mut found = false;
+            assert!(for_each_thread(|tid| found |= tid == own));
+            assert!(found);
+            assert!(count().unwrap() >= 1);
+        }
+    }
+}
+
+/// The registered threads, each in a slot of a fixed table.
+#[cfg(not(target_os = "linux"))]
+mod threads {
+    use core::mem::MaybeUninit;
+    use core::sync::atomic::{fence, AtomicUsize, Ordering};
+    use std::cell::Cell;
+    use std::thread_local;
+
+    use super::SpinOnce;
+
+    /// The most threads that can be registered at once. A thread that finds no free slot
+    /// issues `SeqCst` fences in `light()` instead.
+    const MAX_THREADS: usize = 1024;
+
+    /// The slot is free.
+    const FREE: usize = 0;
+    /// A thread is registering or unregistering in the slot.
+    const CLAIMED: usize = 1;
+    /// A registered thread is in the slot.
+    const LIVE: usize = 2;
+    /// A barrier is signaling the thread in the slot, which can't unregister meanwhile.
+    const SIGNALING: usize = 3;
+
+    /// The `SLOT` of a thread that hasn't registered yet.
+    const UNREGISTERED: usize = MAX_THREADS;
+    /// The `SLOT` of a thread that couldn't register, and no longer tries to.
+    const FAILED: usize = MAX_THREADS + 1;
+
+    /// The registration of a thread.
+    struct Slot {
+        state: AtomicUsize,
+        /// The `pthread_t` of the thread.
+        thread: AtomicUsize,
+        /// The generation of the latest round that signaled the thread, or 0 once the thread
+        /// acknowledged it.
+        pending: AtomicUsize,
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const VACANT: Slot = Slot {
+        state: AtomicUsize::new(FREE),
+        thread: AtomicUsize::new(0),
+        pending: AtomicUsize::new(0),
+    };
+
+    static SLOTS: [Slot; MAX_THREADS] = [VACANT; MAX_THREADS];
+
+    /// The key whose destructor unregisters an exiting thread, or `None` if it can't be
+    /// created.
+    static KEY: SpinOnce<Option<libc::pthread_key_t>> = SpinOnce::new();
+
+    thread_local! {
+        /// The index of the slot of the current thread, or `UNREGISTERED` or `FAILED`.
+        ///
+        /// It is const-initialized without a destructor, so that the signal handler can access
+        /// it.
+        static SLOT: Cell<usize> = const { Cell::new(UNREGISTERED) };
+    }
+
+    /// Registers the current thread, unless it already is. Returns `false` if it can't be
+    /// registered, as the signal is taken or no slot is free.
+    #[inline]
+    pub fn register() -> bool {
+        match SLOT.with(Cell::get) {
+            UNREGISTERED => register_slow(),
+            slot => slot < MAX_THREADS,
+        }
+    }
+
+    #[cold]
+    fn register_slow() -> bool {
+        let key = match *KEY.get_or_init(create_key) {
+            Some(key) if super::install() => key,
+            _ => {
+                SLOT.with(|cell| cell.set(FAILED));
+                return false;
+            }
+        };
+
+        for (index, slot) in SLOTS.iter().enumerate() {
+            if slot
+                .state
+                .compare_exchange(FREE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            // The destructor of a key only runs for a non-null value.
+            let value = (index + 1) as *const libc::c_void;
+            if unsafe { libc::pthread_setspecific(key, value) } != 0 {
+                slot.state.store(FREE, Ordering::Release);
+                break;
+            }
+
+            slot.thread
+                .store(unsafe { libc::pthread_self() } as usize, Ordering::Relaxed);
+            slot.pending.store(0, Ordering::Relaxed);
+            SLOT.with(|cell| cell.set(index));
+            slot.state.store(LIVE, Ordering::SeqCst);
+            // A barrier that misses the slot issued its initial fence before this one, so the
+            // accesses of the thread from here on are ordered with it without a signal.
+            fence(Ordering::SeqCst);
+            return true;
+        }
+
+        SLOT.with(|cell| cell.set(FAILED));
+        false
+    }
+
+    /// Creates the key whose destructor unregisters an exiting thread.
+    fn create_key() -> Option<libc::pthread_key_t> {
+        let mut key = MaybeUninit::<libc::pthread_key_t>::uninit();
+        unsafe {
+            if libc::pthread_key_create(key.as_mut_ptr(), Some(unregister)) == 0 {
+                Some(key.assume_init())
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Unregisters an exiting thread, whose slot's index plus one is `value`.
+    unsafe extern "C" fn unregister(value: *mut libc::c_void) {
+        let slot = &SLOTS[value as usize - 1];
+        // A barrier only holds the slot while it sends the signal.
+        while slot
+            .state
+            .compare_exchange_weak(LIVE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            libc::sched_yield();
+        }
+        SLOT.with(|cell| cell.set(UNREGISTERED));
+        slot.state.store(FREE, Ordering::Release);
+    }
+
+    /// Returns whether the threads can be signaled, which registered threads always can.
+    pub fn can_signal() -> bool {
+        true
+    }
+
+    /// Returns the round that signaled the current thread, unless the thread acknowledged it
+    /// already.
+    pub fn received(_info: *mut libc::siginfo_t) -> Option<usize> {
+        let slot = SLOTS.get(SLOT.with(Cell::get))?;
+        match slot.pending.swap(0, Ordering::SeqCst) {
+            0 => None,
+            generation => Some(generation),
+        }
+    }
+
+    /// Signals every registered thread but the current one in the round `generation`, and
+    /// returns how many threads it signaled, or the error if a thread can't be signaled.
+    pub fn signal_all(generation: usize) -> Result<usize, i32> {
+        let signal = super::signal().ok_or(libc::ENOSYS)?;
+        let own = SLOT.with(Cell::get);
+        let mut sent = 0;
+        for (index, slot) in SLOTS.iter().enumerate() {
+            if index == own
+                || slot
+                    .state
+                    .compare_exchange(LIVE, SIGNALING, Ordering::Acquire, Ordering::Relaxed)
+                    .is_err()
+            {
+                continue;
+            }
+            slot.pending.store(generation, Ordering::SeqCst);
+            let thread = slot.thread.load(Ordering::Relaxed) as libc::pthread_t;
+            let error = unsafe { libc::pthread_kill(thread, signal) };
+            slot.state.store(LIVE, Ordering::Release);
+            if error != 0 {
+                return Err(error);
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Returns the number of registered threads.
+    pub fn count() -> Option<usize> {
+        let count = SLOTS
+            .iter()
+            .filter(|slot| {
+                let state = slot.state.load(Ordering::Relaxed);
+                state == LIVE || state == SIGNALING
+            })
+            .count();
+        Some(count)
+    }
+
+    /// Unregisters the threads of the parent in the child of a `fork()`, where only the current
+    /// thread exists.
+    pub fn reinit_after_fork() {
+        let own = SLOT.with(Cell::get);
+        for (index, slot) in SLOTS.iter().enumerate() {
+            slot.pending.store(0, Ordering::Relaxed);
+            let state = if index == own { LIVE } else { FREE };
+            slot.state.store(state, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn barrier_reaches_threads() {
+        if !is_supported() {
+            return;
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (registered_sender, registered) = mpsc::channel();
+        let threads = (0..4)
+            .map(|_| {
+                let stop = stop.clone();
+                let registered_sender = registered_sender.clone();
+                thread::spawn(move || {
+                    assert!(register());
+                    registered_sender.send(()).unwrap();
+                    while !stop.load(Ordering::Relaxed) {
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..4 {
+            registered.recv().unwrap();
+        }
+        assert!(register());
+        assert!(thread_count().unwrap() >= 5);
+        for _ in 0..100 {
+            assert_eq!(barrier(None), Ok(()));
+        }
+        assert_eq!(barrier(Some(Duration::from_secs(60))), Ok(()));
+
+        stop.store(true, Ordering::Relaxed);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // The exited threads no longer need signals, so barriers don't wait for them.
+        assert_eq!(barrier(None), Ok(()));
+    }
+
+    #[test]
+    fn barrier_times_out_on_blocked_signal() {
+        if !is_supported() {
+            return;
+        }
+
+        let (blocked_sender, blocked) = mpsc::channel();
+        let (unblock, unblock_receiver) = mpsc::channel::<()>();
+        let blocker = thread::spawn(move || unsafe {
+            let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(set.as_mut_ptr());
+            libc::sigaddset(set.as_mut_ptr(), signal().unwrap());
+            assert_eq!(
+                libc::pthread_sigmask(libc::SIG_BLOCK, set.as_ptr(), ptr::null_mut()),
+                0
+            );
+            assert!(register());
+            blocked_sender.send(()).unwrap();
+            unblock_receiver.recv().unwrap();
+            // The thread exits with the signal pending, which the barrier survives.
+        });
+
+        blocked.recv().unwrap();
+        assert_eq!(
+            barrier(Some(Duration::from_millis(50))),
+            Err(Missed::Timeout)
+        );
+
+        unblock.send(()).unwrap();
+        blocker.join().unwrap();
+        assert_eq!(barrier(None), Ok(()));
+    }
+}
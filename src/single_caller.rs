@@ -0,0 +1,30 @@
+//! Downgrades `heavy()` to a fence once `assume_single_caller()` was called.
+
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+
+/// Whether `assume_single_caller()` was called.
+///
+/// It is only stored and loaded by the single caller, so the loads don't need to acquire.
+static ASSUMED: AtomicBool = AtomicBool::new(false);
+
+/// Downgrades the heavy barriers of the current thread from now on.
+pub fn assume() {
+    ASSUMED.store(true, Ordering::Relaxed);
+}
+
+/// Issues `heavy()` as a fence if `assume_single_caller()` was called, and returns whether it
+/// did.
+#[inline]
+pub fn heavy() -> bool {
+    if !ASSUMED.load(Ordering::Relaxed) {
+        return false;
+    }
+    #[cfg(feature = "metrics")]
+    let started = super::metrics::heavy_started();
+    let generation = super::generation::begin();
+    fence(Ordering::SeqCst);
+    super::generation::end(generation);
+    #[cfg(feature = "metrics")]
+    super::metrics::heavy_finished(started);
+    true
+}
@@ -0,0 +1,220 @@
+use core::sync::atomic;
+use windows_sys;
+
+use core::time::Duration;
+
+use super::spin_once::SpinOnce;
+use super::{Backend, BarrierError, Capabilities, HeavyCost, Timeout};
+
+#[cfg(feature = "diagnostics")]
+use super::BarrierReport;
+
+mod ntdll {
+    use core::mem;
+    use windows_sys::Win32::System::LibraryLoader::{GetModuleHandleA, GetProcAddress};
+
+    use super::SpinOnce;
+
+    /// The signature of `NtFlushProcessWriteBuffers()`, which returns an `NTSTATUS`.
+    pub type Flush = unsafe extern "system" fn() -> i32;
+
+    /// `NtFlushProcessWriteBuffers()`, if it is used.
+    static FLUSH: SpinOnce<Option<Flush>> = SpinOnce::new();
+
+    /// Returns `NtFlushProcessWriteBuffers()`, resolving it from `ntdll.dll` on first use, or
+    /// `None` without the `ntdll-flush` feature or if it isn't exported.
+    #[inline]
+    pub fn flush() -> Option<Flush> {
+        *FLUSH.get_or_init(|| {
+            if !cfg!(feature = "ntdll-flush") {
+                return None;
+            }
+            unsafe {
+                // `ntdll.dll` is mapped into every process, so it is never loaded here.
+                let module = GetModuleHandleA(b"ntdll.dll\0".as_ptr());
+                if module == 0 {
+                    return None;
+                }
+                GetProcAddress(module, b"NtFlushProcessWriteBuffers\0".as_ptr())
+                    .map(|f| mem::transmute::<unsafe extern "system" fn() -> isize, Flush>(f))
+            }
+        })
+    }
+
+    /// Returns whether the process runs under Wine, whose `ntdll.dll` exports
+    /// `wine_get_version()`.
+    pub fn wine() -> bool {
+        unsafe {
+            let module = GetModuleHandleA(b"ntdll.dll\0".as_ptr());
+            module != 0 && GetProcAddress(module, b"wine_get_version\0".as_ptr()).is_some()
+        }
+    }
+}
+
+/// Whether `FlushProcessWriteBuffers()` can be trusted, i.e. the process doesn't run under
+/// Wine.
+static TRUSTED: SpinOnce<bool> = SpinOnce::new();
+
+/// Returns whether `FlushProcessWriteBuffers()` can be trusted, checking it on first use.
+///
+/// Wine implements it on top of the host system, with whatever barrier that offers, if any,
+/// and its behavior has changed across versions. So under Wine, both barriers fall back to
+/// `SeqCst` fences.
+#[inline]
+fn trusted() -> bool {
+    *TRUSTED.get_or_init(|| !ntdll::wine())
+}
+
+/// Issues light memory barrier for fast path.
+///
+/// It issues compiler fence, which disallows compiler optimizations across itself. Under Wine,
+/// it issues a `SeqCst` fence instead, as `heavy()` does.
+#[inline]
+pub fn light() {
+    #[cfg(feature = "tsan")]
+    super::tsan::light();
+    if trusted() {
+        atomic::compiler_fence(atomic::Ordering::SeqCst);
+    } else {
+        atomic::fence(atomic::Ordering::SeqCst);
+    }
+    #[cfg(feature = "metrics")]
+    super::metrics::light();
+}
+
+/// Issues heavy memory barrier for slow path.
+///
+/// It invokes the `FlushProcessWriteBuffers()` system call. With the `ntdll-flush` feature, it
+/// calls `NtFlushProcessWriteBuffers()` in `ntdll.dll` instead, bypassing `kernel32.dll`, if
+/// it is exported.
+///
+/// # Warning
+///
+/// Under Wine, which is detected by the `wine_get_version()` export of its `ntdll.dll`, it only
+/// issues a `SeqCst` fence, and so does `light()`. Wine emulates the call with whatever the
+/// host system offers, which has been anything from a real process-wide barrier to nothing
+/// across its versions, so the crate doesn't rely on it. The barriers stay correct, but
+/// `light()` is as slow as a full fence there. `capabilities()` reports whether this happens.
+#[inline]
+pub fn heavy() {
+    fatal_assert!(try_heavy().is_ok());
+}
+
+/// Implements `try_heavy()`, which never fails as `FlushProcessWriteBuffers()` can't.
+pub fn try_heavy() -> Result<(), BarrierError> {
+    if !super::single_caller::heavy() {
+        issue();
+    }
+    Ok(())
+}
+
+/// Issues the barrier of `heavy()`.
+#[inline]
+fn issue() {
+    #[cfg(feature = "metrics")]
+    let started = super::metrics::heavy_started();
+    let generation = super::generation::begin();
+    if trusted() {
+        unsafe {
+            match ntdll::flush() {
+                Some(flush) => {
+                    flush();
+                }
+                None => windows_sys::Win32::System::Threading::FlushProcessWriteBuffers(),
+            }
+        }
+    } else {
+        atomic::fence(atomic::Ordering::SeqCst);
+    }
+    super::generation::end(generation);
+    #[cfg(feature = "metrics")]
+    super::metrics::heavy_finished(started);
+}
+
+/// Implements `try_heavy_timeout()`, which never waits.
+#[inline]
+pub fn try_heavy_timeout(_timeout: Duration) -> Result<(), Timeout> {
+    heavy();
+    Ok(())
+}
+
+/// Implements `heavy_reporting()`, reporting the active processors.
+#[cfg(feature = "diagnostics")]
+pub fn heavy_reporting() -> BarrierReport {
+    heavy();
+    let cpus = if trusted() { active_processors() } else { None };
+    BarrierReport::new(backend(), None, cpus)
+}
+
+/// Implements `init()` by resolving `NtFlushProcessWriteBuffers()`.
+#[inline]
+pub fn init() {
+    trusted();
+    ntdll::flush();
+}
+
+/// Implements `reinit_after_fork()`, which is a no-op as Windows has no `fork()`.
+#[inline]
+pub unsafe fn reinit_after_fork() {}
+
+/// Implements `heavy_signal_safe()`, which always issues the barrier.
+#[inline]
+pub fn heavy_signal_safe() -> bool {
+    issue();
+    true
+}
+
+/// Implements `has_signal_safe_heavy()`, which always holds.
+#[inline]
+pub fn has_signal_safe_heavy() -> bool {
+    true
+}
+
+/// Implements `is_supported()`, which only fails under Wine.
+pub fn is_supported() -> bool {
+    backend() != Backend::Fence
+}
+
+/// Implements `backend()`, telling whether `NtFlushProcessWriteBuffers()` was resolved.
+#[inline]
+pub fn backend() -> Backend {
+    if !trusted() {
+        Backend::Fence
+    } else if ntdll::flush().is_some() {
+        Backend::NtFlushProcessWriteBuffers
+    } else {
+        Backend::FlushProcessWriteBuffers
+    }
+}
+
+/// Implements `expected_heavy_cost()` from the active processors.
+pub fn expected_heavy_cost() -> HeavyCost {
+    if !trusted() {
+        return HeavyCost::Cheap;
+    }
+    HeavyCost::of_reach(active_processors())
+}
+
+/// Returns the number of active processors in every processor group.
+fn active_processors() -> Option<usize> {
+    use windows_sys::Win32::System::Threading::GetActiveProcessorCount;
+
+    /// `ALL_PROCESSOR_GROUPS` in `<winnt.h>`, which `windows-sys` only exposes elsewhere.
+    const ALL_PROCESSOR_GROUPS: u16 = 0xffff;
+
+    let processors = unsafe { GetActiveProcessorCount(ALL_PROCESSOR_GROUPS) };
+    if processors > 0 {
+        Some(processors as usize)
+    } else {
+        None
+    }
+}
+
+/// Implements `capabilities()`, adding whether the process runs under Wine.
+#[inline]
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        wine: ntdll::wine(),
+        ..Capabilities::new(backend())
+    }
+}
@@ -0,0 +1,25 @@
+#![no_std]
+
+extern crate membarrier;
+
+use membarrier::{AlreadyInitialized, Backend, Config};
+
+#[test]
+fn configure_once() {
+    let config = Config {
+        eager_init: true,
+        prefer: Some(Backend::Fence),
+        ..Config::default()
+    };
+    assert_eq!(membarrier::configure(config), Ok(()));
+    if cfg!(all(target_os = "linux", not(feature = "force-fence"))) {
+        assert_eq!(membarrier::backend(), Backend::Fence);
+    }
+    membarrier::light();
+    membarrier::heavy();
+
+    assert_eq!(
+        membarrier::configure(Config::default()),
+        Err(AlreadyInitialized)
+    );
+}
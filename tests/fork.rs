@@ -0,0 +1,82 @@
+//! Checks that the barriers keep working in the child of a `fork()` once it called
+//! `reinit_after_fork()`, with every strategy that holds state the child doesn't inherit.
+//!
+//! The strategy is selected once per process, so the test runs itself in a child process per
+//! preferred mechanism, which then forks.
+
+#![cfg(all(
+    any(target_os = "linux", target_os = "freebsd"),
+    not(feature = "force-fence")
+))]
+
+extern crate libc;
+extern crate membarrier;
+
+use membarrier::{Backend, Config};
+use std::env;
+use std::process::Command;
+
+/// The environment variable that tells the child process which mechanism to prefer.
+const PREFER: &str = "MEMBARRIER_TEST_FORK_PREFER";
+
+const BACKENDS: &[(&str, Backend)] = &[
+    ("membarrier", Backend::Membarrier),
+    ("mprotect", Backend::Mprotect),
+    ("madvise", Backend::Madvise),
+];
+
+#[test]
+fn forked() {
+    for &(name, _) in BACKENDS {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["forking_process", "--exact", "--test-threads=1"])
+            .env(PREFER, name)
+            .status()
+            .unwrap();
+        assert!(status.success(), "the forked child failed with {}", name);
+    }
+}
+
+extern "C" fn reinit_child() {
+    unsafe { membarrier::reinit_after_fork() };
+}
+
+/// Forks after the first barrier, and checks that the child still issues barriers with the
+/// strategy of the parent.
+#[test]
+fn forking_process() {
+    let prefer = match env::var(PREFER) {
+        Ok(name) => BACKENDS.iter().find(|&&(n, _)| n == name).unwrap().1,
+        // Only run in the child processes of `forked()`.
+        Err(_) => return,
+    };
+    let config = Config {
+        prefer: Some(prefer),
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+    membarrier::light();
+    membarrier::heavy();
+    let backend = membarrier::backend();
+
+    unsafe {
+        assert_eq!(libc::pthread_atfork(None, None, Some(reinit_child)), 0);
+        let pid = libc::fork();
+        if pid == 0 {
+            membarrier::light();
+            let issued = membarrier::try_heavy().is_ok() && membarrier::try_heavy().is_ok();
+            // The strategy would have been switched if `sys_membarrier()` had failed.
+            let passed = issued && membarrier::backend() == backend;
+            libc::_exit(if passed { 0 } else { 1 });
+        }
+        assert!(pid > 0);
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(
+            libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0,
+            "{:?} failed in the child",
+            backend
+        );
+    }
+    membarrier::heavy();
+}
@@ -0,0 +1,231 @@
+//! Checks that the first `heavy()` after `init()` in a fresh process synchronizes with a thread
+//! issuing `light()`, with every strategy.
+//!
+//! The strategy is selected once per process, so the test runs itself in a child process per
+//! preferred mechanism. It also checks that threads racing for the first barrier agree on the
+//! strategy, and that `heavy()` has completed when it returns.
+
+extern crate membarrier;
+
+use membarrier::{Backend, Config};
+use std::env;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+/// The environment variable that tells the child process which mechanism to prefer.
+const PREFER: &str = "MEMBARRIER_TEST_PREFER";
+
+/// The environment variable that tells the child process to race for the first barrier.
+const RACE: &str = "MEMBARRIER_TEST_RACE";
+
+/// The environment variable that tells the child process to check that `heavy()` completes.
+const COMPLETION: &str = "MEMBARRIER_TEST_COMPLETION";
+
+const BACKENDS: &[(&str, Option<Backend>)] = &[
+    ("default", None),
+    ("membarrier", Some(Backend::Membarrier)),
+    ("shared", Some(Backend::SharedMembarrier)),
+    ("mprotect", Some(Backend::Mprotect)),
+    ("madvise", Some(Backend::Madvise)),
+    ("signal", Some(Backend::Signal)),
+    ("perf", Some(Backend::PerfEvent)),
+    ("fence", Some(Backend::Fence)),
+];
+
+#[test]
+fn heavy_after_init() {
+    for &(name, _) in BACKENDS {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["fresh_process", "--exact", "--test-threads=1"])
+            .env(PREFER, name)
+            .status()
+            .unwrap();
+        assert!(status.success(), "the first barrier failed with {}", name);
+    }
+}
+
+#[test]
+fn first_use_race() {
+    for &(name, _) in BACKENDS {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["racing_process", "--exact", "--test-threads=1"])
+            .env(PREFER, name)
+            .env(RACE, "1")
+            .status()
+            .unwrap();
+        assert!(
+            status.success(),
+            "the racing threads disagreed with {}",
+            name
+        );
+    }
+}
+
+#[test]
+fn heavy_completes() {
+    for &(name, _) in BACKENDS {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["completing_process", "--exact", "--test-threads=1"])
+            .env(PREFER, name)
+            .env(COMPLETION, "1")
+            .status()
+            .unwrap();
+        assert!(
+            status.success(),
+            "a write was invisible after `heavy()` with {}",
+            name
+        );
+    }
+}
+
+/// Runs a store-buffering litmus test between this thread, whose first barrier is `heavy()`, and a
+/// thread issuing `light()`: in every round, either this thread observes the other's flag or the
+/// other observes this thread's value, but never neither.
+#[test]
+fn fresh_process() {
+    const ROUNDS: usize = 100;
+
+    let prefer = match env::var(PREFER) {
+        Ok(name) => BACKENDS.iter().find(|&&(n, _)| n == name).unwrap().1,
+        // Only run in the child processes of `heavy_after_init()`.
+        Err(_) => return,
+    };
+    let config = Config {
+        prefer,
+        allow_signals: true,
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+    membarrier::init();
+
+    let value = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let round = Arc::new(Barrier::new(2));
+
+    let reader = {
+        let (value, flag, round) = (value.clone(), flag.clone(), round.clone());
+        thread::spawn(move || {
+            let mut seen = Vec::with_capacity(ROUNDS);
+            for r in 1..=ROUNDS {
+                round.wait();
+                flag.store(r, Ordering::Relaxed);
+                membarrier::light();
+                seen.push(value.load(Ordering::Relaxed) == r);
+                round.wait();
+            }
+            seen
+        })
+    };
+
+    let mut observed = Vec::with_capacity(ROUNDS);
+    for r in 1..=ROUNDS {
+        round.wait();
+        value.store(r, Ordering::Relaxed);
+        membarrier::heavy();
+        observed.push(flag.load(Ordering::Relaxed) == r);
+        round.wait();
+    }
+
+    let seen = reader.join().unwrap();
+    for r in 0..ROUNDS {
+        assert!(
+            seen[r] || observed[r],
+            "the threads missed each other in round {}",
+            r + 1
+        );
+    }
+}
+
+/// Lets threads race for the first barrier of the process, and checks that they all end up with
+/// the same strategy.
+#[test]
+fn racing_process() {
+    const THREADS: usize = 16;
+
+    // Only run in the child processes of `first_use_race()`.
+    if env::var_os(RACE).is_none() {
+        return;
+    }
+    let name = env::var(PREFER).unwrap();
+    let config = Config {
+        prefer: BACKENDS.iter().find(|&&(n, _)| n == name).unwrap().1,
+        allow_signals: true,
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+
+    let start = Arc::new(Barrier::new(THREADS));
+    let racers = (0..THREADS)
+        .map(|i| {
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                if i % 2 == 0 {
+                    membarrier::light();
+                } else {
+                    membarrier::heavy();
+                }
+                membarrier::backend()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let backends = racers
+        .into_iter()
+        .map(|racer| racer.join().unwrap())
+        .collect::<Vec<_>>();
+    for &backend in &backends {
+        assert_eq!(backend, membarrier::backend());
+    }
+}
+
+/// Lets a spinning thread publish a value with `light()`, and checks that it is visible right after
+/// `heavy()`: the thread announces the value only after `light()`, so once this thread saw the
+/// announcement, the value must be visible as soon as the barrier completed.
+#[test]
+fn completing_process() {
+    const ROUNDS: usize = 1000;
+
+    // Only run in the child processes of `heavy_completes()`.
+    if env::var_os(COMPLETION).is_none() {
+        return;
+    }
+    let name = env::var(PREFER).unwrap();
+    let config = Config {
+        prefer: BACKENDS.iter().find(|&&(n, _)| n == name).unwrap().1,
+        allow_signals: true,
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+    membarrier::init();
+
+    let value = Arc::new(AtomicUsize::new(0));
+    let ready = Arc::new(AtomicUsize::new(0));
+    let go = Arc::new(AtomicUsize::new(0));
+
+    let writer = {
+        let (value, ready, go) = (value.clone(), ready.clone(), go.clone());
+        thread::spawn(move || {
+            for r in 1..=ROUNDS {
+                while go.load(Ordering::Relaxed) != r {
+                    thread::yield_now();
+                }
+                value.store(r, Ordering::Relaxed);
+                membarrier::light();
+                ready.store(r, Ordering::Relaxed);
+            }
+        })
+    };
+
+    for r in 1..=ROUNDS {
+        go.store(r, Ordering::Relaxed);
+        while ready.load(Ordering::Relaxed) != r {
+            thread::yield_now();
+        }
+        membarrier::heavy();
+        assert_eq!(value.load(Ordering::Relaxed), r, "in round {}", r);
+    }
+    writer.join().unwrap();
+}
@@ -0,0 +1,67 @@
+//! Checks that `is_supported()` neither selects the strategy, registers the process for
+//! `sys_membarrier()`, installs the signal handler, nor freezes the configuration, and that it
+//! predicts whether `init()` selects a process-wide barrier.
+//!
+//! The strategy is selected once per process, so this is the only test of its binary.
+
+#![no_std]
+
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate membarrier;
+
+use membarrier::{Backend, Config};
+
+/// Returns the `sys_membarrier()` commands the process is registered for, or `None` if the kernel
+/// can't tell.
+#[cfg(target_os = "linux")]
+fn registrations() -> Option<libc::c_long> {
+    // `MEMBARRIER_CMD_GET_REGISTRATIONS`, which `libc` doesn't know yet.
+    const MEMBARRIER_CMD_GET_REGISTRATIONS: libc::c_int = 1 << 9;
+
+    let ret =
+        unsafe { libc::syscall(libc::SYS_membarrier, MEMBARRIER_CMD_GET_REGISTRATIONS, 0, 0) };
+    if ret >= 0 {
+        Some(ret)
+    } else {
+        None
+    }
+}
+
+/// Returns whether the signal of the signal-based barrier has no handler.
+#[cfg(target_os = "linux")]
+fn signal_free() -> bool {
+    unsafe {
+        let mut previous: libc::sigaction = core::mem::zeroed();
+        libc::sigaction(libc::SIGRTMAX(), core::ptr::null(), &mut previous) == 0
+            && previous.sa_sigaction == libc::SIG_DFL
+    }
+}
+
+#[test]
+fn side_effect_free() {
+    let supported = membarrier::is_supported();
+    assert_eq!(membarrier::is_supported(), supported);
+
+    let config = Config {
+        allow_signals: true,
+        ..Config::default()
+    };
+    assert_eq!(membarrier::configure(config), Ok(()));
+    let predicted = membarrier::is_supported();
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(registrations().unwrap_or(0), 0);
+        assert!(signal_free());
+    }
+
+    membarrier::init();
+    let selected = membarrier::backend() != Backend::Fence;
+    assert_eq!(membarrier::is_supported(), selected);
+    // The checks of the `paranoid` feature may still reject a strategy that is available.
+    if cfg!(feature = "paranoid") {
+        assert!(predicted || !selected);
+    } else {
+        assert_eq!(predicted, selected);
+    }
+}
@@ -0,0 +1,93 @@
+//! Checks that `light()` never allocates or issues a system call once the strategy is selected.
+
+extern crate libc;
+extern crate membarrier;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// An allocator that counts the allocations of the threads that are tracking them.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+fn count_allocation() {
+    // The thread-local storage may already be gone while the thread exits.
+    let _ = ALLOCATIONS.try_with(|allocations| {
+        if let Some(count) = allocations.get() {
+            allocations.set(Some(count + 1));
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ROUNDS: usize = 10_000;
+
+#[test]
+fn light_never_allocates() {
+    membarrier::init();
+
+    ALLOCATIONS.with(|allocations| allocations.set(Some(0)));
+    for _ in 0..ROUNDS {
+        membarrier::light();
+    }
+    let allocations = ALLOCATIONS.with(|allocations| allocations.replace(None));
+    assert_eq!(allocations, Some(0));
+}
+
+/// Runs `light()` in a child process in seccomp strict mode, which kills the process on any system
+/// call other than `read()`, `write()`, `exit()`, and `sigreturn()`.
+#[cfg(target_os = "linux")]
+#[test]
+fn light_never_syscalls() {
+    membarrier::init();
+
+    unsafe {
+        let pid = libc::fork();
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Only async-signal-safe functions may be called in the child of a multi-threaded
+            // process, and `light()` doesn't do anything else.
+            if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_STRICT) != 0 {
+                libc::_exit(2);
+            }
+            for _ in 0..ROUNDS {
+                membarrier::light();
+            }
+            // `exit_group()`, which `_exit()` calls, is not allowed in strict mode.
+            libc::syscall(libc::SYS_exit, 0);
+        }
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 2 {
+            // Seccomp is not available, e.g. in a container that forbids it.
+            return;
+        }
+        assert!(
+            libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0,
+            "light() issued a system call (wait status {:#x})",
+            status
+        );
+    }
+}
@@ -1,8 +1,12 @@
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
 extern crate membarrier;
 
-use core::sync::atomic::{fence, Ordering};
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use core::time::Duration;
+use membarrier::Barrier;
 
 #[test]
 fn fences() {
@@ -10,3 +14,362 @@ fn fences() {
     fence(Ordering::SeqCst); // normal barrier
     membarrier::heavy();     // heavy-weight barrier
 }
+
+#[test]
+fn flush_self() {
+    membarrier::flush_self();
+    membarrier::heavy();
+}
+
+#[test]
+fn light_hardened() {
+    membarrier::light_hardened();
+    membarrier::heavy();
+}
+
+#[test]
+fn one_directional_heavy() {
+    membarrier::light();
+    membarrier::heavy_release();
+    membarrier::heavy_acquire();
+}
+
+#[test]
+fn heavy_cost() {
+    let cost = membarrier::expected_heavy_cost();
+    if membarrier::backend() == membarrier::Backend::Fence {
+        assert_eq!(cost, membarrier::HeavyCost::Cheap);
+    } else {
+        assert_ne!(cost, membarrier::HeavyCost::Cheap);
+    }
+}
+
+#[test]
+fn capabilities() {
+    let capabilities = membarrier::capabilities();
+    assert_eq!(capabilities.backend(), membarrier::backend());
+    if capabilities.backend() == membarrier::Backend::Membarrier {
+        assert!(capabilities.membarrier_commands().is_some());
+        assert_eq!(capabilities.register_error(), None);
+    }
+    if !cfg!(target_os = "linux") {
+        assert_eq!(capabilities.register_error(), None);
+    }
+    if !cfg!(target_os = "linux") {
+        assert_eq!(capabilities.hypervisor(), None);
+        assert_eq!(capabilities.isolated_cpus(), 0);
+    }
+    if !cfg!(windows) {
+        assert!(!capabilities.wine());
+    }
+    if capabilities.heavy_disturbs_isolated_cpus() {
+        assert!(capabilities.isolated_cpus() > 0);
+    }
+}
+
+#[test]
+fn try_init() {
+    match membarrier::try_init() {
+        Ok(backend) => assert_eq!(backend, membarrier::backend()),
+        Err(error) => {
+            assert_eq!(membarrier::backend(), membarrier::Backend::Fence);
+            let register_error = membarrier::capabilities().register_error();
+            match error {
+                membarrier::InitError::Membarrier(error) => {
+                    assert_eq!(register_error, Some(error))
+                }
+                _ => assert_eq!(register_error, None),
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn describe_capabilities() {
+    use alloc::format;
+
+    let capabilities = membarrier::capabilities();
+    let report = capabilities.describe();
+    assert_eq!(report, format!("{}", capabilities));
+    let backend = format!("backend: {:?}", capabilities.backend());
+    assert_eq!(report.lines().next(), Some(backend.as_str()));
+    let isolated = format!("isolated CPUs: {}", capabilities.isolated_cpus());
+    assert_eq!(report.lines().last(), Some(isolated.as_str()));
+}
+
+#[test]
+fn barrier_generation() {
+    let snapshot = membarrier::barrier_generation();
+    membarrier::heavy();
+    assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 1);
+    membarrier::heavy();
+    assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 2);
+    if membarrier::try_heavy_timeout(Duration::from_secs(10)).is_ok() {
+        assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 3);
+    }
+    let snapshot = membarrier::barrier_generation();
+    membarrier::heavy_gentle();
+    assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 1);
+}
+
+#[test]
+fn try_heavy() {
+    let snapshot = membarrier::barrier_generation();
+    assert_eq!(membarrier::try_heavy(), Ok(()));
+    assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 1);
+}
+
+#[test]
+fn sync_core() {
+    let snapshot = membarrier::barrier_generation();
+    let result = membarrier::sync_core();
+    let registered =
+        membarrier::register_all(&[membarrier::Command::PrivateExpeditedSyncCore]).is_ok();
+    if cfg!(any(target_os = "linux", target_os = "freebsd")) && registered {
+        assert_eq!(result, Ok(()));
+    }
+    if result.is_ok() {
+        assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 1);
+    }
+}
+
+#[test]
+fn register_all() {
+    assert_eq!(membarrier::register_all(&[]), Ok(()));
+    if membarrier::backend() == membarrier::Backend::Membarrier {
+        // The process is already registered, so this is a no-op on Linux 6.3 and later.
+        let commands = [membarrier::Command::PrivateExpedited];
+        assert_eq!(membarrier::register_all(&commands), Ok(()));
+    }
+}
+
+#[test]
+fn fds() {
+    membarrier::heavy();
+    let resources = membarrier::fds();
+    if membarrier::backend() == membarrier::Backend::PerfEvent {
+        assert!(!resources.fds().is_empty());
+    } else if !cfg!(feature = "memfd-mprotect") {
+        // Only the `memfd` of the `mprotect()`-based barrier may be held besides the perf events.
+        assert!(resources.fds().is_empty());
+    }
+    for mapping in resources.mappings() {
+        assert!(!mapping.is_empty());
+    }
+    if membarrier::backend() == membarrier::Backend::Mprotect {
+        assert!(!resources.mappings().is_empty());
+    }
+}
+
+#[test]
+fn mprotect_page_locked() {
+    membarrier::heavy();
+    assert_eq!(
+        membarrier::mprotect_page_locked().is_some(),
+        membarrier::backend() == membarrier::Backend::Mprotect
+    );
+}
+
+#[test]
+fn signal_safe_heavy() {
+    membarrier::init();
+    assert_eq!(
+        membarrier::heavy_signal_safe(),
+        membarrier::has_signal_safe_heavy()
+    );
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn capi() {
+    membarrier::init();
+    assert_eq!(
+        membarrier::membarrier_heavy_signal_safe(),
+        membarrier::membarrier_has_signal_safe_heavy()
+    );
+}
+
+#[cfg(feature = "rseq-barrier")]
+#[test]
+fn heavy_rseq() {
+    membarrier::init();
+    let snapshot = membarrier::barrier_generation();
+    membarrier::heavy_rseq();
+    assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 1);
+}
+
+#[test]
+fn init() {
+    membarrier::init();
+    membarrier::heavy();
+}
+
+#[test]
+fn build_info() {
+    let info = membarrier::build_info();
+    assert_eq!(info.version(), env!("CARGO_PKG_VERSION"));
+    if cfg!(all(target_os = "linux", not(feature = "force-fence"))) {
+        assert_eq!(info.module(), "linux");
+    }
+    if cfg!(all(target_os = "freebsd", not(feature = "force-fence"))) {
+        assert_eq!(info.module(), "freebsd");
+    }
+    assert_eq!(info.has_feature("std"), cfg!(feature = "std"));
+    assert_eq!(info.has_feature("paranoid"), cfg!(feature = "paranoid"));
+    assert!(!info.has_feature("unknown"));
+}
+
+/// Nothing was hotplugged, so refreshing the topology keeps the strategy.
+#[test]
+fn refresh_topology() {
+    membarrier::init();
+    let backend = membarrier::backend();
+    membarrier::refresh_topology().unwrap();
+    assert_eq!(membarrier::backend(), backend);
+    membarrier::heavy();
+}
+
+#[cfg(all(windows, not(feature = "force-fence")))]
+#[test]
+fn windows_backend() {
+    membarrier::heavy();
+    let backend = membarrier::backend();
+    if membarrier::capabilities().wine() {
+        assert_eq!(backend, membarrier::Backend::Fence);
+    } else if cfg!(feature = "ntdll-flush") {
+        // Every supported version of `ntdll.dll` exports it.
+        assert_eq!(backend, membarrier::Backend::NtFlushProcessWriteBuffers);
+    } else {
+        assert_eq!(backend, membarrier::Backend::FlushProcessWriteBuffers);
+    }
+}
+
+/// riscv64 Linux falls back to the `mprotect()`-based barrier on kernels without
+/// `sys_membarrier()`, so it always has a process-wide barrier.
+#[cfg(all(
+    target_os = "linux",
+    target_arch = "riscv64",
+    not(feature = "force-fence")
+))]
+#[test]
+fn riscv64_backend() {
+    membarrier::light();
+    membarrier::heavy();
+    let backend = membarrier::backend();
+    assert!(backend != membarrier::Backend::Fence, "{:?}", backend);
+    membarrier::heavy();
+}
+
+#[cfg(all(target_os = "freebsd", not(feature = "force-fence")))]
+#[test]
+fn freebsd_backend() {
+    membarrier::light();
+    membarrier::heavy();
+    let backend = membarrier::backend();
+    if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+        // The `mprotect()`-based barrier is there to fall back to on older kernels.
+        assert!(
+            backend == membarrier::Backend::Membarrier || backend == membarrier::Backend::Mprotect,
+            "{:?}",
+            backend
+        );
+    }
+    if backend == membarrier::Backend::Membarrier {
+        let commands = [membarrier::Command::PrivateExpeditedRseq];
+        assert_eq!(
+            membarrier::register_all(&commands),
+            Err(membarrier::RegisterError::Unsupported)
+        );
+    }
+    membarrier::heavy();
+}
+
+#[test]
+fn dyn_barrier() {
+    struct CountingBarrier(AtomicUsize);
+
+    impl Barrier for CountingBarrier {
+        fn light(&self) {
+            membarrier::light();
+        }
+
+        fn heavy(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            membarrier::heavy();
+        }
+    }
+
+    let counting = CountingBarrier(AtomicUsize::new(0));
+    let barriers: [&dyn Barrier; 2] = [membarrier::process_barrier(), &counting];
+    for barrier in barriers.iter() {
+        barrier.light();
+        barrier.heavy();
+    }
+    assert_eq!(counting.0.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn light_barrier() {
+    let barriers = [
+        (membarrier::LightBarrier::reader(), Ordering::Acquire),
+        (membarrier::LightBarrier::writer(), Ordering::Release),
+        (membarrier::LightBarrier::full(), Ordering::SeqCst),
+    ];
+    for &(barrier, ordering) in &barriers {
+        assert_eq!(barrier.ordering(), ordering);
+        barrier.issue();
+        membarrier::heavy();
+    }
+}
+
+#[test]
+fn epoch_slot() {
+    let slot = membarrier::EpochSlot::new();
+    assert_eq!(slot.observe(membarrier::heavy_guard), None);
+    {
+        let _section = slot.announce(3).enter(membarrier::light_guard);
+        assert_eq!(slot.observe(membarrier::heavy_guard), Some(3));
+    }
+    assert_eq!(slot.observe(membarrier::heavy_guard), None);
+
+    // An announcement that never turns into a critical section is withdrawn, too.
+    let announced = slot.announce(4);
+    drop(announced);
+    assert_eq!(slot.observe(membarrier::heavy_guard), None);
+}
+
+#[test]
+fn heavy_timeout() {
+    assert_eq!(
+        membarrier::try_heavy_timeout(Duration::from_secs(10)),
+        Ok(())
+    );
+}
+
+#[cfg(feature = "diagnostics")]
+#[test]
+fn heavy_with_stats() {
+    let stats = membarrier::heavy_with_stats();
+    assert_eq!(stats.backend(), membarrier::backend());
+    if cfg!(target_os = "linux") {
+        assert!(stats.cpus().unwrap() >= 1);
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+#[test]
+fn heavy_reporting() {
+    use membarrier::Backend;
+
+    let report = membarrier::heavy_reporting();
+    assert_eq!(report.backend(), membarrier::backend());
+    match report.backend() {
+        Backend::MachThreadState => assert!(report.threads().unwrap() >= 1),
+        Backend::Signal => assert!(report.threads().is_some()),
+        Backend::Membarrier | Backend::Mprotect | Backend::Madvise | Backend::PerfEvent => {
+            assert!(report.cpus().unwrap() >= 1)
+        }
+        _ => {}
+    }
+}
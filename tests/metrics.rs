@@ -0,0 +1,60 @@
+//! Checks that the barriers are reported to the sink set with `set_metrics()`.
+//!
+//! The sink is set once per process, so this has a test binary of its own.
+
+#![cfg(feature = "metrics")]
+
+extern crate membarrier;
+
+use membarrier::{AlreadyInitialized, Backend, BarrierMetrics};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+struct Recorder {
+    lights: AtomicUsize,
+    heavies: Mutex<Vec<(Backend, Duration)>>,
+}
+
+impl BarrierMetrics for Recorder {
+    fn on_heavy(&self, backend: Backend, duration: Duration) {
+        self.heavies.lock().unwrap().push((backend, duration));
+    }
+
+    fn on_light(&self) {
+        self.lights.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static RECORDER: Recorder = Recorder {
+    lights: AtomicUsize::new(0),
+    heavies: Mutex::new(Vec::new()),
+};
+
+static OTHER: Recorder = Recorder {
+    lights: AtomicUsize::new(0),
+    heavies: Mutex::new(Vec::new()),
+};
+
+#[test]
+fn barriers_are_reported() {
+    membarrier::light();
+    membarrier::heavy();
+    assert_eq!(RECORDER.lights.load(Ordering::Relaxed), 0);
+
+    assert_eq!(membarrier::set_metrics(&RECORDER), Ok(()));
+    assert_eq!(membarrier::set_metrics(&OTHER), Err(AlreadyInitialized));
+    for _ in 0..3 {
+        membarrier::light();
+    }
+    membarrier::heavy();
+    membarrier::heavy();
+
+    assert_eq!(RECORDER.lights.load(Ordering::Relaxed), 3);
+    let heavies = RECORDER.heavies.lock().unwrap();
+    assert_eq!(heavies.len(), 2);
+    for &(backend, _) in heavies.iter() {
+        assert_eq!(backend, membarrier::backend());
+    }
+    assert_eq!(OTHER.lights.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,39 @@
+//! Checks that the `mprotect()`-based barrier works with a page it failed to lock, and reports it.
+//!
+//! The configuration is frozen by the first barrier of the process, so this has a test binary of
+//! its own.
+
+#![cfg(all(target_os = "linux", not(feature = "force-fence")))]
+
+extern crate libc;
+extern crate membarrier;
+
+use membarrier::{Backend, Config};
+
+#[test]
+fn unlocked_page() {
+    // Nothing can be locked anymore, unless the process has `CAP_IPC_LOCK`.
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) }, 0);
+    assert_eq!(membarrier::mprotect_page_locked(), None);
+
+    let config = Config {
+        prefer: Some(Backend::Mprotect),
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+    membarrier::heavy();
+    if membarrier::backend() != Backend::Mprotect {
+        assert_eq!(membarrier::mprotect_page_locked(), None);
+        return;
+    }
+
+    let locked = membarrier::mprotect_page_locked().unwrap();
+    if unsafe { libc::geteuid() } != 0 {
+        assert!(!locked);
+    }
+    assert!(membarrier::try_heavy().is_ok());
+}
@@ -0,0 +1,139 @@
+//! Checks that a security policy denying `mprotect()`, like PaX `MPROTECT` or SELinux, makes the
+//! barriers fall back rather than abort the process.
+//!
+//! The denial is simulated with a seccomp filter that makes `mprotect()` fail with `EACCES` for
+//! some protections. The strategy is selected once per process, so the test runs itself in a child
+//! process per denied protection, and installs the filter there before the first barrier.
+
+#![cfg(all(
+    target_os = "linux",
+    target_arch = "x86_64",
+    not(feature = "force-fence")
+))]
+
+extern crate libc;
+extern crate membarrier;
+
+use membarrier::{Backend, Config};
+use std::env;
+use std::process::Command;
+
+/// The environment variable that tells the child process which protection to deny.
+const DENY: &str = "MEMBARRIER_TEST_DENY";
+
+/// The exit code of a child process that couldn't install the filter.
+const NO_SECCOMP: i32 = 77;
+
+/// The protections to deny, and the mechanisms the barriers may still use with each.
+const DENIALS: &[(&str, libc::c_int, &[Backend])] = &[
+    // Every `mprotect()`-based barrier revokes the protections of its page.
+    (
+        "none",
+        libc::PROT_NONE,
+        &[
+            Backend::Membarrier,
+            Backend::SharedMembarrier,
+            Backend::Signal,
+            Backend::PerfEvent,
+            Backend::Fence,
+        ],
+    ),
+    // The barrier can still grant its page read + write instead of read-only.
+    (
+        "read",
+        libc::PROT_READ,
+        &[Backend::Mprotect, Backend::Madvise],
+    ),
+];
+
+/// Makes `mprotect()` fail with `EACCES` whenever it is asked for exactly `prot`, and for no
+/// other protections, so that the stacks of new threads can still be set up.
+unsafe fn deny_mprotect(prot: libc::c_int) -> bool {
+    // The offset of the low half of the third argument in `struct seccomp_data`.
+    const PROT_OFFSET: u32 = 16 + 2 * 8;
+
+    let filter = [
+        libc::BPF_STMT((libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16, 0),
+        libc::BPF_JUMP(
+            (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+            libc::SYS_mprotect as u32,
+            0,
+            3,
+        ),
+        libc::BPF_STMT(
+            (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+            PROT_OFFSET,
+        ),
+        libc::BPF_JUMP(
+            (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+            prot as u32,
+            0,
+            1,
+        ),
+        libc::BPF_STMT(
+            (libc::BPF_RET | libc::BPF_K) as u16,
+            libc::SECCOMP_RET_ERRNO | libc::EACCES as u32,
+        ),
+        libc::BPF_STMT(
+            (libc::BPF_RET | libc::BPF_K) as u16,
+            libc::SECCOMP_RET_ALLOW,
+        ),
+    ];
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+        && libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            0,
+            &program as *const libc::sock_fprog,
+        ) == 0
+}
+
+#[test]
+fn denied_mprotect() {
+    for &(name, _, _) in DENIALS {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["denied_process", "--exact", "--test-threads=1"])
+            .env(DENY, name)
+            .status()
+            .unwrap();
+        if status.code() == Some(NO_SECCOMP) {
+            // Seccomp is not available, e.g. in a container that forbids it.
+            return;
+        }
+        assert!(status.success(), "the barriers failed with {} denied", name);
+    }
+}
+
+/// Prefers the `mprotect()`-based barrier under the filter, and checks that the barriers work
+/// with one of the mechanisms the denial leaves.
+#[test]
+fn denied_process() {
+    let &(_, prot, allowed) = match env::var(DENY) {
+        Ok(name) => DENIALS.iter().find(|&&(n, _, _)| n == name).unwrap(),
+        // Only run in the child processes of `denied_mprotect()`.
+        Err(_) => return,
+    };
+    if !unsafe { deny_mprotect(prot) } {
+        std::process::exit(NO_SECCOMP);
+    }
+
+    let config = Config {
+        prefer: Some(Backend::Mprotect),
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+    membarrier::light();
+    membarrier::heavy();
+    let backend = membarrier::backend();
+    assert!(
+        allowed.contains(&backend),
+        "{:?} with {:#x} denied",
+        backend,
+        prot
+    );
+    membarrier::heavy();
+}
@@ -0,0 +1,27 @@
+//! Checks that `--cfg membarrier_unsound_noop_heavy` keeps the signatures of the barriers, so that
+//! test suites can set it without changing their code.
+
+#![cfg(membarrier_unsound_noop_heavy)]
+
+extern crate membarrier;
+
+use membarrier::Timeout;
+use std::time::Duration;
+
+#[test]
+fn signatures() {
+    let light: fn() = membarrier::light;
+    let heavy: fn() = membarrier::heavy;
+    let try_heavy_timeout: fn(Duration) -> Result<(), Timeout> = membarrier::try_heavy_timeout;
+
+    light();
+    heavy();
+    assert_eq!(try_heavy_timeout(Duration::from_secs(1)), Ok(()));
+}
+
+#[test]
+fn heavy_advances_generation() {
+    let snapshot = membarrier::barrier_generation();
+    membarrier::heavy();
+    assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 1);
+}
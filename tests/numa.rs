@@ -0,0 +1,48 @@
+//! Checks that the page of the `mprotect()`-based barrier prefers the configured NUMA node.
+//!
+//! The configuration is frozen by the first barrier of the process, so this has a test binary of
+//! its own.
+
+#![cfg(all(target_os = "linux", not(feature = "force-fence")))]
+
+extern crate libc;
+extern crate membarrier;
+
+use membarrier::{Backend, Config};
+
+#[test]
+fn mprotect_page_prefers_node() {
+    let config = Config {
+        prefer: Some(Backend::Mprotect),
+        mprotect_numa_node: Some(0),
+        ..Config::default()
+    };
+    membarrier::configure(config).unwrap();
+    membarrier::heavy();
+    if membarrier::backend() != Backend::Mprotect {
+        return;
+    }
+
+    let page = membarrier::fds().mappings()[0].address();
+    let mut mode: libc::c_int = -1;
+    let mut nodes: [libc::c_ulong; 16] = [0; 16];
+    let maxnode = nodes.len() * 8 * std::mem::size_of::<libc::c_ulong>();
+    // `MPOL_F_ADDR`: the policy of the mapping at `page`, which needn't be accessible.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut mode,
+            nodes.as_mut_ptr(),
+            maxnode as libc::c_ulong,
+            page,
+            (1 << 1) as libc::c_ulong,
+        )
+    };
+    // Kernels without NUMA support have no memory policies.
+    if ret != 0 {
+        assert_eq!(unsafe { *libc::__errno_location() }, libc::ENOSYS);
+        return;
+    }
+    assert_eq!(mode, libc::MPOL_PREFERRED);
+    assert_eq!(nodes[0] & 1, 1);
+}
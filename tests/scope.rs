@@ -0,0 +1,115 @@
+//! Checks that `BarrierScope::heavy()` orders the accesses of a registered thread with the
+//! store-buffering litmus test of the `paranoid` feature, while the thread is pinned to a CPU and
+//! moved to another one every few rounds, so that the barrier sometimes targets the CPU it left.
+
+#![cfg(all(target_os = "linux", feature = "std"))]
+
+extern crate libc;
+extern crate membarrier;
+
+use membarrier::BarrierScope;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// The number of rounds of the litmus test.
+const ROUNDS: usize = 4096;
+/// The number of rounds after which the registered thread moves to the other CPU.
+const MIGRATE_EVERY: usize = 64;
+
+/// Returns the CPUs the process may run on.
+fn allowed_cpus() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect()
+    }
+}
+
+/// Pins the current thread to `cpu`.
+fn pin(cpu: usize) {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        assert_eq!(
+            libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set),
+            0
+        );
+    }
+}
+
+/// What the test shares with the registered thread.
+#[derive(Default)]
+struct Shared {
+    /// The value the current thread writes before its heavy barrier.
+    value: AtomicUsize,
+    /// The flag the registered thread writes before its light barrier.
+    flag: AtomicUsize,
+    /// The round the registered thread may start.
+    started: AtomicUsize,
+    /// The round the registered thread has finished.
+    finished: AtomicUsize,
+    /// Whether the registered thread saw `value` of the finished round.
+    seen: AtomicBool,
+}
+
+/// Waits until `round` is in `counter`, yielding so that this works on a single CPU, too.
+fn wait_for(counter: &AtomicUsize, round: usize) {
+    while counter.load(Ordering::Acquire) != round {
+        thread::yield_now();
+    }
+}
+
+#[test]
+fn pinned_and_migrated() {
+    let cpus = allowed_cpus();
+    let scope = Arc::new(BarrierScope::new());
+    let shared = Arc::new(Shared::default());
+
+    let registered = {
+        let scope = scope.clone();
+        let shared = shared.clone();
+        let cpus = cpus.clone();
+        thread::spawn(move || {
+            scope.register();
+            for round in 1..=ROUNDS {
+                if cpus.len() >= 2 && round % MIGRATE_EVERY == 1 {
+                    pin(cpus[round / MIGRATE_EVERY % 2]);
+                }
+                wait_for(&shared.started, round);
+                shared.flag.store(round, Ordering::Relaxed);
+                membarrier::light();
+                let seen = shared.value.load(Ordering::Relaxed) == round;
+                shared.seen.store(seen, Ordering::Relaxed);
+                shared.finished.store(round, Ordering::Release);
+            }
+            scope.unregister();
+        })
+    };
+
+    // Keeps this thread off the CPUs of the registered one where there are enough of them.
+    if cpus.len() >= 3 {
+        pin(cpus[2]);
+    }
+    while scope.is_empty() {
+        thread::yield_now();
+    }
+    for round in 1..=ROUNDS {
+        shared.started.store(round, Ordering::Release);
+        shared.value.store(round, Ordering::Relaxed);
+        scope.heavy();
+        let observed = shared.flag.load(Ordering::Relaxed) == round;
+        wait_for(&shared.finished, round);
+        assert!(
+            observed || shared.seen.load(Ordering::Relaxed),
+            "the threads missed each other in round {}",
+            round
+        );
+    }
+    registered.join().unwrap();
+}
@@ -0,0 +1,17 @@
+//! Checks `assume_single_caller()`. It downgrades `heavy()` for the whole process, so this has a
+//! test binary of its own, with a single test.
+
+extern crate membarrier;
+
+/// Checks that the downgraded `heavy()` still advances the generation, so that the logic built on
+/// it keeps working.
+#[test]
+fn downgraded_heavy() {
+    unsafe { membarrier::assume_single_caller() };
+    for _ in 0..100 {
+        let snapshot = membarrier::barrier_generation();
+        membarrier::light();
+        membarrier::heavy();
+        assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 1);
+    }
+}
@@ -0,0 +1,190 @@
+//! Checks that the barrier functions can be called from any thread at any time, without setup or
+//! coordination, and behave the same on every thread.
+//!
+//! The state of the crate is process-wide, so this is worth running under ThreadSanitizer as well:
+//!
+//! ```text
+//! RUSTFLAGS=-Zsanitizer=thread cargo +nightly test -Zbuild-std --features tsan --test threads \
+//!     --target x86_64-unknown-linux-gnu
+//! ```
+
+extern crate membarrier;
+
+use membarrier::{Backend, Capabilities, HeavyCost};
+use std::thread;
+
+/// Every public type a thread may hand to another.
+#[test]
+fn send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Backend>();
+    assert_send_sync::<Capabilities>();
+    assert_send_sync::<HeavyCost>();
+    assert_send_sync::<membarrier::Config>();
+    assert_send_sync::<membarrier::HeldResources>();
+    assert_send_sync::<membarrier::LightGuard>();
+    assert_send_sync::<membarrier::HeavyGuard>();
+    #[cfg(feature = "std")]
+    assert_send_sync::<membarrier::BarrierScope>();
+}
+
+/// Lets threads use the barriers right away, each initializing the crate on its own, and checks
+/// that they all see the same strategy.
+#[test]
+fn uncoordinated_threads() {
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 100;
+
+    let threads = (0..THREADS)
+        .map(|i| {
+            thread::spawn(move || {
+                // Half the threads start with a barrier rather than `init()`.
+                if i % 2 == 0 {
+                    membarrier::init();
+                }
+                for _ in 0..ROUNDS {
+                    membarrier::light();
+                    membarrier::heavy();
+                }
+                (membarrier::backend(), membarrier::capabilities())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let seen = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect::<Vec<_>>();
+    // The expected cost may depend on the number of threads, so it is not compared.
+    let expected = (membarrier::backend(), membarrier::capabilities());
+    for behavior in &seen {
+        assert_eq!(*behavior, expected);
+    }
+}
+
+/// Lets the threads of a pool register with a scope and issue `light()` while another thread
+/// issues scoped barriers.
+#[cfg(feature = "std")]
+#[test]
+fn barrier_scope() {
+    use membarrier::BarrierScope;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const THREADS: usize = 4;
+
+    let scope = Arc::new(BarrierScope::new());
+    let done = Arc::new(AtomicBool::new(false));
+    let workers = (0..THREADS)
+        .map(|_| {
+            let scope = scope.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                scope.register();
+                // Registering twice is a no-op.
+                scope.register();
+                while !done.load(Ordering::Relaxed) {
+                    membarrier::light();
+                    thread::yield_now();
+                }
+                scope.unregister();
+            })
+        })
+        .collect::<Vec<_>>();
+
+    while scope.len() < THREADS {
+        thread::yield_now();
+    }
+    for _ in 0..100 {
+        scope.heavy();
+    }
+
+    done.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert!(scope.is_empty());
+    scope.heavy();
+}
+
+/// Lets a thread wait for the heavy barriers that other threads issue after it took a snapshot.
+#[test]
+fn wait_for_generation() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let snapshot = membarrier::barrier_generation();
+    let done = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let done = done.clone();
+        thread::spawn(move || {
+            membarrier::wait_for_generation(snapshot.wrapping_add(2));
+            assert!(membarrier::barrier_generation().wrapping_sub(snapshot) >= 2);
+            done.store(true, Ordering::Relaxed);
+        })
+    };
+
+    // Gives the waiter time to park between the barriers.
+    while !done.load(Ordering::Relaxed) {
+        thread::sleep(std::time::Duration::from_millis(1));
+        membarrier::heavy();
+    }
+    waiter.join().unwrap();
+}
+
+/// Checks that a critical region defers the `heavy_deferred()` of another thread until it ends,
+/// but never `heavy()`.
+#[cfg(feature = "std")]
+#[test]
+fn critical_region() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let region = membarrier::critical_region();
+    // The barriers of the thread in the region are issued right away.
+    membarrier::heavy_deferred();
+
+    // Those of the other threads are only deferred if they opted in.
+    thread::spawn(membarrier::heavy).join().unwrap();
+    let timeout = thread::spawn(|| membarrier::try_heavy_timeout(Duration::from_secs(1)));
+    assert!(timeout.join().unwrap().is_ok());
+
+    let issued = Arc::new(AtomicBool::new(false));
+    let other = {
+        let issued = issued.clone();
+        thread::spawn(move || {
+            let snapshot = membarrier::barrier_generation();
+            membarrier::heavy_deferred();
+            // The barrier that served the deferred one started after it was requested.
+            assert_ne!(membarrier::barrier_generation(), snapshot);
+            issued.store(true, Ordering::SeqCst);
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert!(!issued.load(Ordering::SeqCst));
+
+    drop(region);
+    other.join().unwrap();
+    assert!(issued.load(Ordering::SeqCst));
+}
+
+/// Checks that `light_bounded()` escalates to `heavy()` once the calls of a thread reach the bound.
+#[cfg(feature = "std")]
+#[test]
+fn light_bounded() {
+    let thread = thread::spawn(|| {
+        for _ in 0..3 {
+            membarrier::light_bounded(4);
+        }
+        let snapshot = membarrier::barrier_generation();
+        membarrier::light_bounded(4);
+        assert_ne!(membarrier::barrier_generation(), snapshot);
+    });
+    thread.join().unwrap();
+
+    let snapshot = membarrier::barrier_generation();
+    membarrier::light_bounded(0);
+    assert_ne!(membarrier::barrier_generation(), snapshot);
+}
@@ -0,0 +1,42 @@
+//! Checks that ThreadSanitizer sees the synchronization of the barriers, with the `tsan` feature.
+//!
+//! The data is handed over with relaxed atomics and the barriers alone, which ThreadSanitizer
+//! reports as a data race unless the barriers are annotated. It only links under ThreadSanitizer:
+//!
+//! ```text
+//! RUSTFLAGS=-Zsanitizer=thread cargo +nightly test -Zbuild-std --features tsan --test tsan \
+//!     --target x86_64-unknown-linux-gnu
+//! ```
+
+#![cfg(feature = "tsan")]
+
+extern crate membarrier;
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/// Data that is written and read without atomics.
+struct Data(UnsafeCell<u64>);
+
+unsafe impl Sync for Data {}
+
+/// Hands data over from a producer issuing `heavy()` to a consumer issuing `light()`.
+#[test]
+fn producer_consumer() {
+    static DATA: Data = Data(UnsafeCell::new(0));
+    static READY: AtomicBool = AtomicBool::new(false);
+
+    let consumer = thread::spawn(|| {
+        while !READY.load(Ordering::Relaxed) {
+            thread::yield_now();
+        }
+        membarrier::light();
+        assert_eq!(unsafe { *DATA.0.get() }, 42);
+    });
+
+    unsafe { *DATA.0.get() = 42 };
+    membarrier::heavy();
+    READY.store(true, Ordering::Relaxed);
+    consumer.join().unwrap();
+}